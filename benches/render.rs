@@ -0,0 +1,82 @@
+use audio::{analyzer::AnalyzerParams, frequency_sensor::Features as AudioFeatures, Analyzer};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use image::RgbImage;
+
+use vuzic::visualizer::{cpurender::Visualizer, scenes, Params, SceneKind};
+
+const SIZE: (u32, u32) = (192, 64);
+const BINS: usize = 16;
+const LENGTH: usize = 144;
+
+/// Features of a few seconds of a pulsing tone sweeping up through the spectrum, so every
+/// bin and the whole history carry signal
+fn features() -> AudioFeatures {
+    use std::f64::consts::PI;
+
+    const RATE: f64 = 44100.;
+    const BLOCK: usize = 256;
+    let mut analyzer = Analyzer::new(1024, BLOCK, BINS, LENGTH);
+    let params = AnalyzerParams::default();
+    let mut features = None;
+    for block in 0..4 * LENGTH {
+        let mut data: Vec<f64> = (0..BLOCK)
+            .map(|i| {
+                let t = (block * BLOCK + i) as f64 / RATE;
+                // 50Hz doubling twice a second, pulsing 4 times a second
+                let phase = 50. * (4f64.powf(t) - 1.) / 4f64.ln();
+                let pulse = 0.5 + 0.5 * (2. * PI * 4. * t).sin();
+                pulse * (2. * PI * phase).sin()
+            })
+            .collect();
+        if let Some(f) = analyzer.process(&mut data, &params) {
+            features = Some(f);
+        }
+    }
+    features.expect("the analyzer produced no features")
+}
+
+fn params(blur: f32) -> Params {
+    let mut params: serde_json::Value = serde_json::to_value(Params::default()).unwrap();
+    params["blur"] = blur.into();
+    serde_json::from_value(params).unwrap()
+}
+
+fn warp_grid(c: &mut Criterion) {
+    let features = features();
+    let (w, h) = SIZE;
+    let mut group = c.benchmark_group("warp_grid");
+    for &blur in &[0., 1., 3.] {
+        let params = params(blur);
+        let mut vis = Visualizer::new(w, h, 0);
+        let mut out = RgbImage::new(w, h);
+        group.bench_with_input(BenchmarkId::new("render_into", blur), &params, |b, p| {
+            b.iter(|| vis.render_into(p, &features, &mut out))
+        });
+    }
+    group.finish();
+}
+
+fn scenes(c: &mut Criterion) {
+    let features = features();
+    let params = params(1.);
+    let (w, h) = SIZE;
+    let mut group = c.benchmark_group("scenes");
+    for &kind in &[
+        SceneKind::WarpGrid,
+        SceneKind::SpectrumBars,
+        SceneKind::RadialSpectrum,
+        SceneKind::Spectrogram,
+        SceneKind::Particles,
+        SceneKind::ShaderReference,
+    ] {
+        let mut scene = scenes::new_scene(kind, w, h, 0);
+        let mut out = RgbImage::new(w, h);
+        group.bench_function(format!("{:?}", kind), |b| {
+            b.iter(|| scene.render(&params, &features, &mut out))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, warp_grid, scenes);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+fn main() {
+    #[cfg(feature = "gpu")]
+    shaders::compile();
+}
+
+/// Compile the warpgrid shaders to SPIR-V in `OUT_DIR`, so that GLSL errors fail the
+/// build instead of panicking on first use
+#[cfg(feature = "gpu")]
+mod shaders {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use shaderc::{CompileOptions, Compiler, ShaderKind};
+
+    const DIR: &str = "src/visualizer/warpgrid/shaders";
+    const SHADERS: &[(&str, ShaderKind)] = &[
+        ("update.vert", ShaderKind::Vertex),
+        ("update.frag", ShaderKind::Fragment),
+        ("readback.frag", ShaderKind::Fragment),
+        ("warp.vert", ShaderKind::Vertex),
+        ("warp.frag", ShaderKind::Fragment),
+    ];
+
+    pub fn compile() {
+        let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+        let mut compiler = Compiler::new().expect("failed to create shader compiler");
+        let options = CompileOptions::new().expect("failed to create shader compile options");
+
+        for &(name, kind) in SHADERS {
+            let path = PathBuf::from(DIR).join(name);
+            println!("cargo:rerun-if-changed={}", path.display());
+
+            let source = fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+            let artifact = compiler
+                .compile_into_spirv(&source, kind, name, "main", Some(&options))
+                .unwrap_or_else(|e| panic!("failed to compile {}:\n{}", path.display(), e));
+            if artifact.get_num_warnings() > 0 {
+                println!(
+                    "cargo:warning={}: {}",
+                    name,
+                    artifact.get_warning_messages()
+                );
+            }
+
+            let out = out_dir.join(format!("{}.spv", name));
+            fs::write(&out, artifact.as_binary_u8())
+                .unwrap_or_else(|e| panic!("failed to write {}: {}", out.display(), e));
+        }
+    }
+}
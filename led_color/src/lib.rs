@@ -0,0 +1,230 @@
+use serde::{Deserialize, Serialize};
+
+/// Output color correction for an LED sink, applied to 8-bit frames right before they
+/// are written to the hardware
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ColorCorrection {
+    /// gamma per channel, `[r, g, b]`
+    pub gamma: [f32; 3],
+    /// gain per channel so that full white shows up as white on the LEDs
+    pub white_point: [f32; 3],
+    /// color temperature in kelvin, tints the white point relative to 6500K
+    pub temperature: Option<f32>,
+    /// upper bound on the output level of every channel, in [0, 1]
+    pub max_brightness: f32,
+    /// carry the quantization error over to the next frame so dim colors keep their
+    /// precision at high frame rates
+    pub dither: bool,
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        Self {
+            gamma: [1.; 3],
+            white_point: [1.; 3],
+            temperature: None,
+            max_brightness: 1.,
+            dither: false,
+        }
+    }
+}
+
+impl ColorCorrection {
+    /// Gain per channel after applying the color temperature and brightness limit
+    pub fn gains(&self) -> [f32; 3] {
+        let tint = match self.temperature {
+            Some(k) => {
+                let (t, w) = (kelvin_to_rgb(k), kelvin_to_rgb(6500.));
+                [t[0] / w[0], t[1] / w[1], t[2] / w[2]]
+            }
+            None => [1.; 3],
+        };
+        let max = self.max_brightness.clamp(0., 1.);
+        let mut gains = self.white_point;
+        for (g, t) in gains.iter_mut().zip(tint.iter()) {
+            *g = (*g * t).clamp(0., 1.) * max;
+        }
+        gains
+    }
+}
+
+/// Applies a `ColorCorrection` to frames, keeping the dithering state between frames
+pub struct Corrector {
+    config: ColorCorrection,
+    /// 8.8 fixed point output level for every input level
+    lut: [[u16; 256]; 3],
+    /// fractional part left over from the previous frame
+    error: Vec<u8>,
+}
+
+impl Corrector {
+    pub fn new(config: ColorCorrection) -> Self {
+        let gains = config.gains();
+        let mut lut = [[0; 256]; 3];
+        for (c, table) in lut.iter_mut().enumerate() {
+            let gamma = config.gamma[c].max(0.01);
+            for (i, v) in table.iter_mut().enumerate() {
+                let x = (i as f32 / 255.).powf(gamma) * gains[c];
+                *v = (x * 255. * 256.).round().min(u16::MAX as f32) as u16;
+            }
+        }
+        Self {
+            config,
+            lut,
+            error: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &ColorCorrection {
+        &self.config
+    }
+
+    /// Correct the rgb channels of `data` in place, `stride` is the number of bytes per
+    /// pixel and any channels after the first three are left alone
+    pub fn apply(&mut self, data: &mut [u8], stride: usize) {
+        if self.config.dither {
+            let len = data.len() / stride * 3;
+            if self.error.len() != len {
+                self.error = vec![0; len];
+            }
+            for (px, err) in data
+                .chunks_exact_mut(stride)
+                .zip(self.error.chunks_exact_mut(3))
+            {
+                for ((p, e), table) in px.iter_mut().zip(err.iter_mut()).zip(self.lut.iter()) {
+                    let v = table[*p as usize] as u32 + *e as u32;
+                    *p = (v >> 8).min(255) as u8;
+                    *e = v as u8;
+                }
+            }
+        } else {
+            for px in data.chunks_exact_mut(stride) {
+                for (p, table) in px.iter_mut().zip(self.lut.iter()) {
+                    *p = ((table[*p as usize] + 128) >> 8).min(255) as u8;
+                }
+            }
+        }
+    }
+
+    /// Correct the rgb channels of 16-bit `data` in place, interpolating between the
+    /// table entries so the extra precision survives. Dithering is not applied.
+    pub fn apply16(&mut self, data: &mut [u16], stride: usize) {
+        for px in data.chunks_exact_mut(stride) {
+            for (p, table) in px.iter_mut().zip(self.lut.iter()) {
+                let (i, frac) = ((*p >> 8) as usize, (*p & 0xff) as i32);
+                let (a, b) = (table[i] as i32, table[(i + 1).min(255)] as i32);
+                let v = a + (((b - a) * frac) >> 8);
+                // the table tops out at 255 << 8, stretch it to the full 16 bits
+                *p = (v + (v >> 8)).clamp(0, u16::MAX as i32) as u16;
+            }
+        }
+    }
+}
+
+/// Approximate rgb of a black body at `kelvin`, from Tanner Helland's fit
+fn kelvin_to_rgb(kelvin: f32) -> [f32; 3] {
+    let t = kelvin.clamp(1000., 40000.) / 100.;
+    let r = if t <= 66. {
+        255.
+    } else {
+        329.698_73 * (t - 60.).powf(-0.133_204_76)
+    };
+    let g = if t <= 66. {
+        99.470_8 * t.ln() - 161.119_57
+    } else {
+        288.122_16 * (t - 60.).powf(-0.075_514_846)
+    };
+    let b = if t >= 66. {
+        255.
+    } else if t <= 19. {
+        0.
+    } else {
+        138.517_73 * (t - 10.).ln() - 305.044_8
+    };
+    [
+        r.clamp(0., 255.) / 255.,
+        g.clamp(0., 255.) / 255.,
+        b.clamp(0., 255.) / 255.,
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ColorCorrection, Corrector};
+
+    fn correct(config: ColorCorrection, levels: &[u8]) -> Vec<u8> {
+        let mut data: Vec<u8> = levels.iter().flat_map(|&l| vec![l; 3]).collect();
+        Corrector::new(config).apply(&mut data, 3);
+        data.chunks_exact(3).map(|px| px[0]).collect()
+    }
+
+    #[test]
+    pub fn identity_by_default() {
+        let levels: Vec<u8> = (0..=255).collect();
+        assert_eq!(correct(Default::default(), &levels), levels);
+    }
+
+    #[test]
+    pub fn gamma_endpoints() {
+        let config = ColorCorrection {
+            gamma: [2.2, 1.8, 2.8],
+            ..Default::default()
+        };
+        let mut data = vec![0, 0, 0, 255, 255, 255];
+        Corrector::new(config).apply(&mut data, 3);
+        assert_eq!(data, vec![0, 0, 0, 255, 255, 255]);
+    }
+
+    #[test]
+    pub fn max_brightness() {
+        let config = ColorCorrection {
+            max_brightness: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(correct(config, &[0, 100, 255]), vec![0, 50, 128]);
+    }
+
+    #[test]
+    pub fn apply16_endpoints() {
+        let configs = [
+            ColorCorrection::default(),
+            ColorCorrection {
+                gamma: [2.2, 1.8, 2.8],
+                dither: true,
+                ..Default::default()
+            },
+        ];
+        for config in configs.iter() {
+            let mut data = vec![0, 0, 0, u16::MAX, u16::MAX, u16::MAX];
+            Corrector::new(config.clone()).apply16(&mut data, 3);
+            assert_eq!(data, vec![0, 0, 0, u16::MAX, u16::MAX, u16::MAX]);
+        }
+
+        let mut data = vec![u16::MAX; 3];
+        Corrector::new(ColorCorrection {
+            max_brightness: 0.5,
+            ..Default::default()
+        })
+        .apply16(&mut data, 3);
+        assert_eq!(data, vec![u16::MAX / 2; 3]);
+    }
+
+    #[test]
+    pub fn dither_carries_error() {
+        // level 1 at half brightness is half an output step
+        let mut corrector = Corrector::new(ColorCorrection {
+            max_brightness: 0.5,
+            dither: true,
+            ..Default::default()
+        });
+        let frames: Vec<_> = (0..2)
+            .map(|_| {
+                let mut data = vec![1, 1, 1, 7];
+                corrector.apply(&mut data, 4);
+                data
+            })
+            .collect();
+        assert_eq!(frames, vec![vec![0, 0, 0, 7], vec![1, 1, 1, 7]]);
+    }
+}
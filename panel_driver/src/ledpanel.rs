@@ -1,25 +1,26 @@
-use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Sender, SyncSender};
+use std::sync::Arc;
 use std::thread;
 
 use anyhow::Result;
 use image::RgbImage;
+use led_color::Corrector;
 use rpi_led_matrix::{LedColor, LedMatrix, LedMatrixOptions, LedRuntimeOptions};
-use serde::{Deserialize, Serialize};
+
+use crate::{ColorCorrection, Options};
 
 pub struct Panel {
     send_frame_: SyncSender<RgbImage>,
+    send_correction: Sender<ColorCorrection>,
+    stats: Arc<Stats>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Options {
-    pub cols: Option<u32>,
-    pub rows: Option<u32>,
-    pub chain_length: Option<u32>,
-    pub parallel: Option<u32>,
-    pub hardware_mapping: Option<String>,
-    pub pwm_dither_bits: Option<u32>,
-    pub pwm_lsb_nanoseconds: Option<u32>,
-    pub gpio_slowdown: Option<u32>,
+/// Frame counters updated by the panel thread
+#[derive(Default)]
+struct Stats {
+    frames: AtomicU64,
+    fps: AtomicU32,
 }
 
 impl Options {
@@ -54,36 +55,17 @@ impl Options {
 
         (opts, rt_opts)
     }
-
-    pub fn frame_size(&self) -> (u32, u32) {
-        (
-            self.cols.unwrap_or_default() * self.chain_length.unwrap_or(1),
-            self.rows.unwrap_or_default() * self.parallel.unwrap_or(1),
-        )
-    }
-}
-
-// FIXME: this isn't "default". Default should be None
-impl Default for Options {
-    fn default() -> Self {
-        Self {
-            cols: Some(64),
-            rows: Some(32),
-            chain_length: Some(3),
-            parallel: Some(2),
-            hardware_mapping: Some("vuzic".to_string()),
-            pwm_dither_bits: Some(0),
-            pwm_lsb_nanoseconds: Some(120),
-            gpio_slowdown: Some(3),
-        }
-    }
 }
 
 impl Panel {
     pub fn new(verbose: i32, options: Options) -> Self {
         let (send_frame_, recv_frame) = sync_channel::<RgbImage>(1);
+        let (send_correction, recv_correction) = channel();
+        let stats = Arc::new(Stats::default());
+        let thread_stats = stats.clone();
 
         thread::spawn(move || {
+            let mut corrector = Corrector::new(options.color.clone());
             let (mut options, rt_options) = options.into_matrix_options();
             // options.set_cols(64);
             // options.set_rows(32);
@@ -103,16 +85,24 @@ impl Panel {
 
             loop {
                 match recv_frame.recv() {
-                    Ok(frame) => {
+                    Ok(mut frame) => {
+                        if let Some(color) = recv_correction.try_iter().last() {
+                            corrector = Corrector::new(color);
+                        }
+                        corrector.apply(&mut frame, 3);
                         for (x, y, c) in frame.enumerate_pixels() {
                             let (red, green, blue) = (c[0], c[1], c[2]);
                             canvas.set(x as i32, y as i32, &LedColor { red, green, blue });
                         }
                         canvas = matrix.swap(canvas);
                         frame_count += 1;
-                        if verbose > 0 && frame_count % 256 == 0 {
-                            let e = then.elapsed().unwrap().as_secs_f32();
-                            log::debug!("FPS: {:.2}", 256. / e);
+                        thread_stats.frames.fetch_add(1, Ordering::Relaxed);
+                        if frame_count % 256 == 0 {
+                            let fps = 256. / then.elapsed().unwrap().as_secs_f32();
+                            thread_stats.fps.store(fps.to_bits(), Ordering::Relaxed);
+                            if verbose > 0 {
+                                log::debug!("FPS: {:.2}", fps);
+                            }
                             then = std::time::SystemTime::now();
                         }
                     }
@@ -124,11 +114,31 @@ impl Panel {
             }
         });
 
-        Self { send_frame_ }
+        Self {
+            send_frame_,
+            send_correction,
+            stats,
+        }
     }
 
     pub fn send_frame(&self, frame: RgbImage) -> Result<()> {
         self.send_frame_.send(frame)?;
         Ok(())
     }
+
+    /// Replace the color correction applied to the following frames
+    pub fn set_color_correction(&self, color: ColorCorrection) -> Result<()> {
+        self.send_correction.send(color)?;
+        Ok(())
+    }
+
+    /// Number of frames displayed so far
+    pub fn frames(&self) -> u64 {
+        self.stats.frames.load(Ordering::Relaxed)
+    }
+
+    /// Frame rate averaged over the last 256 frames
+    pub fn fps(&self) -> f32 {
+        f32::from_bits(self.stats.fps.load(Ordering::Relaxed))
+    }
 }
@@ -1,2 +1,7 @@
+mod options;
+pub use options::*;
+
+#[cfg(feature = "hardware")]
 mod ledpanel;
-pub use ledpanel::*;
\ No newline at end of file
+#[cfg(feature = "hardware")]
+pub use ledpanel::*;
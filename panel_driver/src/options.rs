@@ -0,0 +1,47 @@
+use patch::Patch;
+use serde::{Deserialize, Serialize};
+
+pub use led_color::ColorCorrection;
+
+/// Led matrix settings, `null` in a patch clears a field
+#[derive(Debug, Clone, Serialize, Deserialize, Patch)]
+#[patch(derive(Debug, Clone, Serialize, Deserialize))]
+pub struct Options {
+    pub cols: Option<u32>,
+    pub rows: Option<u32>,
+    pub chain_length: Option<u32>,
+    pub parallel: Option<u32>,
+    pub hardware_mapping: Option<String>,
+    pub pwm_dither_bits: Option<u32>,
+    pub pwm_lsb_nanoseconds: Option<u32>,
+    pub gpio_slowdown: Option<u32>,
+    #[serde(default)]
+    pub color: ColorCorrection,
+}
+
+impl Options {
+    /// Size of the whole chain, unset fields fall back to the matrix library's defaults
+    pub fn frame_size(&self) -> (u32, u32) {
+        (
+            self.cols.unwrap_or(32) * self.chain_length.unwrap_or(1),
+            self.rows.unwrap_or(32) * self.parallel.unwrap_or(1),
+        )
+    }
+}
+
+// FIXME: this isn't "default". Default should be None
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            cols: Some(64),
+            rows: Some(32),
+            chain_length: Some(3),
+            parallel: Some(2),
+            hardware_mapping: Some("vuzic".to_string()),
+            pwm_dither_bits: Some(0),
+            pwm_lsb_nanoseconds: Some(120),
+            gpio_slowdown: Some(3),
+            color: Default::default(),
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use clap::Clap;
 use image::RgbaImage;
-use parallel_strip_driver::{APA102Parallel, Hardware};
+use parallel_strip_driver::{APA102Parallel, ColorCorrection, Hardware, PowerLimit, Rgb16Image};
 use simple_logger::SimpleLogger;
 
 /// LED Strip Parallel Demo
@@ -14,6 +14,26 @@ struct Opts {
 
     #[clap(long, default_value = "1")]
     alpha: u8,
+
+    /// Gamma applied to every channel
+    #[clap(long, default_value = "1")]
+    gamma: f32,
+
+    /// Output level limit in [0, 1]
+    #[clap(long, default_value = "1")]
+    max_brightness: f32,
+
+    /// Current budget per strip in mA
+    #[clap(long)]
+    strip_budget_ma: Option<f32>,
+
+    /// Current budget for all strips in mA
+    #[clap(long)]
+    total_budget_ma: Option<f32>,
+
+    /// Show a slow dim fade through the 16-bit path instead of the rainbow
+    #[clap(long)]
+    fade: bool,
 }
 
 fn rainbow(l: u32, w: f32, alpha: u8) -> Vec<u8> {
@@ -34,6 +54,14 @@ fn rainbow(l: u32, w: f32, alpha: u8) -> Vec<u8> {
         .collect()
 }
 
+/// Breathing white between off and a quarter brightness, squared so most of the time
+/// is spent at the dim end
+fn fade(l: u32, t: f32) -> Vec<u16> {
+    let x = 0.5 - 0.5 * f32::cos(t);
+    let v = (0.25 * x * x * 65535.0) as u16;
+    vec![v; 3 * l as usize]
+}
+
 fn main() {
     SimpleLogger::new().init().unwrap();
     let opts = Opts::parse();
@@ -43,10 +71,27 @@ fn main() {
     let hw = Hardware::new(spi_clock, 17, 22, 27, 5, 6, 13, 19, opts.counter_preset)
         .expect("failed to create hardware");
     let leds = APA102Parallel::new(144, 16, hw);
+    leds.set_color_correction(ColorCorrection {
+        gamma: [opts.gamma; 3],
+        max_brightness: opts.max_brightness,
+        ..Default::default()
+    });
+    leds.set_power_limit(PowerLimit {
+        strip_budget_ma: opts.strip_budget_ma,
+        total_budget_ma: opts.total_budget_ma,
+        ..Default::default()
+    });
 
     let mut i = 0;
     loop {
         i += 1;
+        if opts.fade {
+            let buf = (0..16)
+                .flat_map(|_| fade(144, i as f32 / 512.0))
+                .collect::<Vec<u16>>();
+            leds.display16(Rgb16Image::from_raw(144, 16, buf).unwrap());
+            continue;
+        }
         let buf = (0..16)
             .flat_map(|_| rainbow(144, i as f32 / 32.0, alpha))
             .collect::<Vec<u8>>();
@@ -2,11 +2,17 @@ use std::thread;
 use std::{
     io::Read,
     os::linux::raw,
-    sync::mpsc::{sync_channel, SyncSender},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    sync::mpsc::{channel, sync_channel, Sender, SyncSender},
+    sync::Arc,
 };
 
 use anyhow::Result;
-use image::{Pixel, RgbaImage};
+use image::{ImageBuffer, Pixel, Rgb, RgbaImage};
+pub use led_color::ColorCorrection;
+use led_color::Corrector;
+mod power;
+pub use power::{PowerEstimate, PowerLimit};
 use rppal::{
     gpio::{Gpio, OutputPin},
     spi::{Bus, Mode, Polarity, SlaveSelect, Spi},
@@ -17,7 +23,34 @@ pub struct APA102Parallel {
     length: u32,
     rows: u32,
     buffer: Vec<u16>,
-    send_frame: SyncSender<RgbaImage>,
+    send_frame: SyncSender<Frame>,
+    send_control: Sender<Control>,
+    stats: Arc<Stats>,
+}
+
+/// Frame with 16 bits per channel, see `APA102Parallel::display16`
+pub type Rgb16Image = ImageBuffer<Rgb<u16>, Vec<u16>>;
+
+enum Frame {
+    /// rgb with the 5-bit global brightness in alpha
+    Rgba8(RgbaImage),
+    Rgb16(Rgb16Image),
+}
+
+/// Settings changed while the output thread is running
+enum Control {
+    Color(ColorCorrection),
+    Power(PowerLimit),
+}
+
+/// Frame counters updated by the output thread
+#[derive(Default)]
+struct Stats {
+    frames: AtomicU64,
+    fps: AtomicU32,
+    /// estimated draw of the last frame in mA
+    current: AtomicU32,
+    limited: AtomicBool,
 }
 
 pub struct Hardware {
@@ -136,6 +169,27 @@ impl Hardware {
     }
 }
 
+/// Split 16-bit channels into 8-bit PWM values and the 5-bit global brightness
+fn split_brightness(image: &Rgb16Image) -> RgbaImage {
+    const FULL: u32 = 255 * 31;
+
+    let (w, h) = image.dimensions();
+    let mut out = RgbaImage::new(w, h);
+    for (src, dst) in image.pixels().zip(out.pixels_mut()) {
+        // scale to units of the smallest step, pwm 1 at global brightness 1
+        let level = |v: u16| (v as u32 * FULL + 0x7fff) / 0xffff;
+        let [r, g, b] = [level(src[0]), level(src[1]), level(src[2])];
+        let max = r.max(g).max(b);
+        if max == 0 {
+            continue;
+        }
+        let gb = ((max + 254) / 255).clamp(1, 31);
+        let pwm = |l: u32| ((l + gb / 2) / gb).min(255) as u8;
+        dst.0 = [pwm(r), pwm(g), pwm(b), gb as u8];
+    }
+    out
+}
+
 fn to_bytes(input: &[u16]) -> Vec<u8> {
     let mut bytes = Vec::with_capacity(4 * input.len());
     for value in input {
@@ -157,27 +211,70 @@ impl APA102Parallel {
         let buffer = Self::to_output_buffer(buffer);
 
         let (send_frame, recv_frame) = sync_channel(1);
+        let (send_control, recv_control) = channel();
+        let stats = Arc::new(Stats::default());
+        let thread_stats = stats.clone();
 
         thread::spawn(move || {
             let mut frame_count = 0;
             let mut then = std::time::SystemTime::now();
             let mut hw = hardware;
+            let mut corrector = Corrector::new(Default::default());
+            let mut power = PowerLimit::default();
 
             hw.spi
                 .set_ss_polarity(Polarity::ActiveHigh)
                 .expect("failed to set spi polarity");
 
-            while let Ok(image) = recv_frame.recv() {
+            while let Ok(frame) = recv_frame.recv() {
+                for control in recv_control.try_iter() {
+                    match control {
+                        Control::Color(color) => corrector = Corrector::new(color),
+                        Control::Power(limit) => power = limit,
+                    }
+                }
+                let mut image = match frame {
+                    Frame::Rgba8(mut image) => {
+                        corrector.apply(&mut image, 4);
+                        image
+                    }
+                    Frame::Rgb16(mut image) => {
+                        corrector.apply16(&mut image, 3);
+                        split_brightness(&image)
+                    }
+                };
+
+                let estimate = power.apply(&mut image);
+                let was_limited = thread_stats
+                    .limited
+                    .swap(estimate.limited, Ordering::Relaxed);
+                if estimate.limited != was_limited {
+                    if estimate.limited {
+                        log::warn!(
+                            "power limit active, estimated draw {:?} mA",
+                            estimate.strips_ma
+                        );
+                    } else {
+                        log::info!("power limit no longer active");
+                    }
+                }
+                thread_stats
+                    .current
+                    .store(estimate.total_ma.to_bits(), Ordering::Relaxed);
+
                 let buffer = Self::to_output_buffer(image);
                 let bs = to_bytes(buffer.as_slice());
                 hw.write(&bs).expect("failed to write frame");
 
                 frame_count += 1;
+                thread_stats.frames.fetch_add(1, Ordering::Relaxed);
                 if frame_count % 256 == 0 {
                     let now = std::time::SystemTime::now();
                     if let Ok(e) = now.duration_since(then) {
                         then = now;
-                        log::debug!("fps: {:.02}", 256.0 / e.as_secs_f32());
+                        let fps = 256.0 / e.as_secs_f32();
+                        thread_stats.fps.store(fps.to_bits(), Ordering::Relaxed);
+                        log::debug!("fps: {:.02}", fps);
                     }
                 }
             }
@@ -190,11 +287,58 @@ impl APA102Parallel {
             rows,
             buffer,
             send_frame,
+            send_control,
+            stats,
+        }
+    }
+
+    /// Replace the color correction applied to the following frames, the alpha channel
+    /// carrying the global brightness is left alone
+    pub fn set_color_correction(&self, color: ColorCorrection) {
+        if let Err(e) = self.send_control.send(Control::Color(color)) {
+            log::error!("failed to send color correction: {}", e);
+        }
+    }
+
+    /// Replace the current budget applied to the following frames
+    pub fn set_power_limit(&self, limit: PowerLimit) {
+        if let Err(e) = self.send_control.send(Control::Power(limit)) {
+            log::error!("failed to send power limit: {}", e);
         }
     }
 
+    /// Estimated draw of the last frame in mA, after limiting
+    pub fn current_ma(&self) -> f32 {
+        f32::from_bits(self.stats.current.load(Ordering::Relaxed))
+    }
+
+    /// Whether the last frame was dimmed to stay within the power budget
+    pub fn power_limited(&self) -> bool {
+        self.stats.limited.load(Ordering::Relaxed)
+    }
+
+    /// Number of frames written to the strips so far
+    pub fn frames(&self) -> u64 {
+        self.stats.frames.load(Ordering::Relaxed)
+    }
+
+    /// Frame rate averaged over the last 256 frames
+    pub fn fps(&self) -> f32 {
+        f32::from_bits(self.stats.fps.load(Ordering::Relaxed))
+    }
+
+    /// Display a frame of 8-bit rgb with the 5-bit global brightness in alpha
     pub fn display(&self, image: RgbaImage) {
-        if let Err(e) = self.send_frame.send(image) {
+        if let Err(e) = self.send_frame.send(Frame::Rgba8(image)) {
+            log::error!("failed to send frame: {}", e);
+        }
+    }
+
+    /// Display a frame with 16 bits per channel. The global brightness of every LED is
+    /// chosen so its brightest channel uses as much of the 8-bit PWM range as possible,
+    /// which keeps dim colors from collapsing onto a few levels.
+    pub fn display16(&self, image: Rgb16Image) {
+        if let Err(e) = self.send_frame.send(Frame::Rgb16(image)) {
             log::error!("failed to send frame: {}", e);
         }
     }
@@ -245,8 +389,34 @@ impl APA102Parallel {
 
 #[cfg(test)]
 mod test {
-    use super::APA102Parallel;
-    use image::RgbaImage;
+    use super::{split_brightness, APA102Parallel, Rgb16Image};
+    use image::{Rgb, RgbaImage};
+
+    #[test]
+    pub fn split_brightness_levels() {
+        const FULL: u32 = 255 * 31;
+        let mut brightness = [false; 32];
+        for v in 0..=u16::MAX {
+            // the other channels at a third and none of the brightest one
+            let image = Rgb16Image::from_pixel(1, 1, Rgb([v, v / 3, 0]));
+            let px = split_brightness(&image).into_raw();
+            let gb = px[3] as u32;
+            brightness[gb as usize] = true;
+            for (c, &ch) in [v, v / 3, 0].iter().enumerate() {
+                let level = (ch as u32 * FULL + 0x7fff) / 0xffff;
+                let shown = px[c] as u32 * gb;
+                // within one pwm step at the chosen global brightness
+                assert!(
+                    (shown as i64 - level as i64).abs() <= gb as i64 / 2,
+                    "{}: {:?} for level {}",
+                    v,
+                    px,
+                    level
+                );
+            }
+        }
+        assert!(brightness[1..].iter().all(|&b| b));
+    }
     #[test]
     pub fn to_output_buffer() {
         for i in 0..16 {
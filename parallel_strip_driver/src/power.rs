@@ -0,0 +1,201 @@
+use image::RgbaImage;
+
+/// Current budget for the strips, frames which would draw more are dimmed to fit
+#[derive(Debug, Clone, PartialEq)]
+pub struct PowerLimit {
+    /// draw of a single color channel at full PWM and full global brightness
+    pub ma_per_channel: f32,
+    /// quiescent draw of an LED which is off
+    pub idle_ma_per_led: f32,
+    /// budget for each strip, i.e. each row of the frame
+    pub strip_budget_ma: Option<f32>,
+    /// budget for all strips together
+    pub total_budget_ma: Option<f32>,
+}
+
+impl Default for PowerLimit {
+    fn default() -> Self {
+        Self {
+            ma_per_channel: 20.,
+            idle_ma_per_led: 1.,
+            strip_budget_ma: None,
+            total_budget_ma: None,
+        }
+    }
+}
+
+/// Expected current draw of a frame
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PowerEstimate {
+    /// draw of each strip before limiting
+    pub strips_ma: Vec<f32>,
+    /// total draw after limiting
+    pub total_ma: f32,
+    /// whether the frame was dimmed to stay within budget
+    pub limited: bool,
+}
+
+impl PowerLimit {
+    /// Draw of each row of `image`, taking the 5-bit global brightness in alpha into account
+    pub fn estimate(&self, image: &RgbaImage) -> Vec<f32> {
+        let (cols, _) = image.dimensions();
+        let scale = self.ma_per_channel / (255. * 31.);
+        image
+            .as_raw()
+            .chunks_exact(4 * cols as usize)
+            .map(|row| {
+                let pwm: u32 = row
+                    .chunks_exact(4)
+                    .map(|p| (p[0] as u32 + p[1] as u32 + p[2] as u32) * (p[3] as u32).min(31))
+                    .sum();
+                pwm as f32 * scale + self.idle_ma_per_led * cols as f32
+            })
+            .collect()
+    }
+
+    /// Dim `image` in place so it stays within the budgets
+    pub fn apply(&self, image: &mut RgbaImage) -> PowerEstimate {
+        let strips_ma = self.estimate(image);
+        let (cols, _) = image.dimensions();
+        let idle = self.idle_ma_per_led * cols as f32;
+
+        // the idle draw can't be dimmed, so only scale what is above it
+        let fit = |draw: f32, budget: f32| {
+            if draw > budget {
+                ((budget - idle).max(0.) / (draw - idle).max(f32::EPSILON)).min(1.)
+            } else {
+                1.
+            }
+        };
+        let mut scales: Vec<f32> = strips_ma
+            .iter()
+            .map(|&ma| self.strip_budget_ma.map_or(1., |b| fit(ma, b)))
+            .collect();
+
+        let mut total_ma: f32 = strips_ma
+            .iter()
+            .zip(scales.iter())
+            .map(|(ma, s)| idle + (ma - idle) * s)
+            .sum();
+        if let Some(budget) = self.total_budget_ma {
+            let total_idle = idle * strips_ma.len() as f32;
+            if total_ma > budget {
+                let above_idle = (total_ma - total_idle).max(f32::EPSILON);
+                let s = ((budget - total_idle).max(0.) / above_idle).min(1.);
+                scales.iter_mut().for_each(|x| *x *= s);
+                total_ma = total_idle + (total_ma - total_idle) * s;
+            }
+        }
+
+        let limited = scales.iter().any(|&s| s < 1.);
+        if limited {
+            for (row, &s) in image.chunks_exact_mut(4 * cols as usize).zip(scales.iter()) {
+                if s < 1. {
+                    let s = (s * 256.) as u32;
+                    for p in row.chunks_exact_mut(4) {
+                        for c in p[..3].iter_mut() {
+                            *c = ((*c as u32 * s) >> 8) as u8;
+                        }
+                    }
+                }
+            }
+        }
+
+        PowerEstimate {
+            strips_ma,
+            total_ma,
+            limited,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PowerLimit;
+    use image::{Rgba, RgbaImage};
+
+    /// 2 leds per strip, full white at full global brightness on the `lit` strips
+    fn frame(lit: &[bool]) -> RgbaImage {
+        RgbaImage::from_fn(2, lit.len() as u32, |_, y| {
+            if lit[y as usize] {
+                Rgba([255, 255, 255, 31])
+            } else {
+                Rgba([0, 0, 0, 31])
+            }
+        })
+    }
+
+    fn assert_ma(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected.iter()) {
+            assert!((a - e).abs() < 1e-3, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    fn levels(image: &RgbaImage) -> Vec<u8> {
+        image.pixels().map(|p| p[0]).collect()
+    }
+
+    #[test]
+    pub fn estimate() {
+        let limit = PowerLimit::default();
+        // 3 channels at 20mA for 2 leds, plus 1mA idle for each
+        assert_ma(&limit.estimate(&frame(&[true, false])), &[122., 2.]);
+    }
+
+    #[test]
+    pub fn strip_over_budget() {
+        let limit = PowerLimit {
+            strip_budget_ma: Some(62.),
+            ..Default::default()
+        };
+        let mut image = frame(&[true, false]);
+        let estimate = limit.apply(&mut image);
+        assert!(estimate.limited);
+        assert_ma(&estimate.strips_ma, &[122., 2.]);
+        assert_ma(&[estimate.total_ma], &[64.]);
+        assert_eq!(levels(&image), vec![127, 127, 0, 0]);
+    }
+
+    #[test]
+    pub fn total_over_budget() {
+        let limit = PowerLimit {
+            strip_budget_ma: Some(200.),
+            total_budget_ma: Some(124.),
+            ..Default::default()
+        };
+        let mut image = frame(&[true, true]);
+        let estimate = limit.apply(&mut image);
+        assert!(estimate.limited);
+        assert_ma(&[estimate.total_ma], &[124.]);
+        assert_eq!(levels(&image), vec![127; 4]);
+    }
+
+    #[test]
+    pub fn zero_budget() {
+        let limit = PowerLimit {
+            strip_budget_ma: Some(0.),
+            total_budget_ma: Some(0.),
+            ..Default::default()
+        };
+        let mut image = frame(&[true, true]);
+        let estimate = limit.apply(&mut image);
+        assert!(estimate.limited);
+        // only the idle draw is left
+        assert_ma(&[estimate.total_ma], &[4.]);
+        assert_eq!(levels(&image), vec![0; 4]);
+    }
+
+    #[test]
+    pub fn within_budget() {
+        let limit = PowerLimit {
+            strip_budget_ma: Some(122.),
+            total_budget_ma: Some(244.),
+            ..Default::default()
+        };
+        let mut image = frame(&[true, true]);
+        let estimate = limit.apply(&mut image);
+        assert!(!estimate.limited);
+        assert_eq!(levels(&image), vec![255; 4]);
+    }
+}
@@ -0,0 +1,145 @@
+//! Partial updates of config structs, so a client can send only the fields it changes
+
+pub use patch_derive::Patch;
+
+// lets the derive's `::patch` paths resolve in the tests
+#[cfg(test)]
+extern crate self as patch;
+
+/// A struct which can be updated from a patch with every field optional.
+///
+/// `#[derive(Patch)]` generates the patch struct, named `<Struct>Patch` unless set with
+/// `#[patch(name = "...")]`, and derives the traits listed in `#[patch(derive(...))]` for
+/// it. Fields are replaced when they are set in the patch, fields marked
+/// `#[patch(nested)]` are patched themselves and fields marked `#[patch(skip)]` are left
+/// out of the patch.
+///
+/// When the patch derives serde's traits, `rename`, `rename_all`, `alias`,
+/// `deny_unknown_fields` and the `skip` arguments of `#[serde(...)]` are carried over to
+/// it and any other argument but `default` is a compile error. Unset fields aren't
+/// serialized. A field which is an `Option` itself is cleared by `null` and left alone
+/// when it is missing.
+pub trait Patch {
+    type Patch;
+
+    /// Update every field which is set in `patch`
+    fn apply(&mut self, patch: Self::Patch);
+}
+
+#[cfg(test)]
+mod test {
+    use super::Patch;
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Patch)]
+    #[patch(derive(Serialize, Deserialize, Debug))]
+    struct Render {
+        blur: f32,
+        #[serde(rename = "colorPeriod")]
+        color_period: f32,
+        limit: Option<u32>,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Patch)]
+    #[patch(name = "PartialConfig", derive(Serialize, Deserialize, Debug))]
+    struct Config {
+        #[patch(skip)]
+        startup: u32,
+        #[patch(nested)]
+        render: Render,
+        name: String,
+    }
+
+    fn config() -> Config {
+        Config {
+            startup: 1,
+            render: Render {
+                blur: 1.,
+                color_period: 3.,
+                limit: Some(10),
+            },
+            name: "vuzic".to_string(),
+        }
+    }
+
+    fn patch(value: serde_json::Value) -> PartialConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    pub fn replace() {
+        let mut c = config();
+        c.apply(PartialConfig {
+            render: None,
+            name: Some("other".to_string()),
+        });
+        assert_eq!(c.name, "other");
+        assert_eq!(c.render, config().render);
+    }
+
+    #[test]
+    pub fn nested() {
+        let mut c = config();
+        c.apply(PartialConfig {
+            render: Some(RenderPatch {
+                blur: Some(2.),
+                color_period: None,
+                limit: None,
+            }),
+            name: None,
+        });
+        assert_eq!(c.render.blur, 2.);
+        assert_eq!(c.render.color_period, 3.);
+        assert_eq!(c.render.limit, Some(10));
+        assert_eq!(c.name, "vuzic");
+    }
+
+    #[test]
+    pub fn skip() {
+        let mut c = config();
+        c.apply(patch(json!({"startup": 5, "name": "other"})));
+        assert_eq!(c.startup, 1);
+        assert_eq!(c.name, "other");
+    }
+
+    #[test]
+    pub fn rename() {
+        let mut c = config();
+        c.apply(patch(json!({"render": {"colorPeriod": 5}})));
+        assert_eq!(c.render.color_period, 5.);
+
+        let unchanged = patch(json!({"render": {"color_period": 6}}));
+        assert!(unchanged.render.unwrap().color_period.is_none());
+    }
+
+    #[test]
+    pub fn partial_json() {
+        let p = patch(json!({"render": {"blur": 2}}));
+        assert_eq!(
+            serde_json::to_value(&p).unwrap(),
+            json!({"render": {"blur": 2.0}})
+        );
+
+        let mut c = config();
+        c.apply(p);
+        let mut expected = config();
+        expected.render.blur = 2.;
+        assert_eq!(c, expected);
+    }
+
+    #[test]
+    pub fn clear_option() {
+        let mut c = config();
+        c.apply(patch(json!({"render": {"blur": 2}})));
+        assert_eq!(c.render.limit, Some(10));
+
+        let p = patch(json!({"render": {"limit": null}}));
+        assert_eq!(
+            serde_json::to_value(&p).unwrap(),
+            json!({"render": {"limit": null}})
+        );
+        c.apply(p);
+        assert_eq!(c.render.limit, None);
+    }
+}
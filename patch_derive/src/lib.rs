@@ -0,0 +1,293 @@
+//! `#[derive(Patch)]`, see the `patch` crate
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, Attribute, Data, DeriveInput, Fields, Ident, Lit, Meta, NestedMeta, Path,
+    Type,
+};
+
+#[proc_macro_derive(Patch, attributes(patch))]
+pub fn derive_patch(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// `#[patch(...)]` on the struct
+#[derive(Default)]
+struct StructOptions {
+    name: Option<Ident>,
+    derives: Vec<Path>,
+}
+
+impl StructOptions {
+    fn derives(&self, name: &str) -> bool {
+        self.derives
+            .iter()
+            .any(|p| p.segments.last().is_some_and(|s| s.ident == name))
+    }
+}
+
+/// `#[serde(...)]` arguments which mean the same on the patch, `default` is accepted but
+/// not carried over since every field of a patch is optional anyway
+const SERDE_CONTAINER: &[&str] = &["rename", "rename_all", "deny_unknown_fields", "default"];
+const SERDE_FIELD: &[&str] = &[
+    "rename",
+    "alias",
+    "skip",
+    "skip_serializing",
+    "skip_deserializing",
+    "default",
+];
+
+/// The `#[serde(...)]` attributes to put on the patch, an error for any argument which
+/// would change its meaning or not compile with the field wrapped in an `Option`
+fn serde_attrs(attrs: &[Attribute], allowed: &[&str]) -> syn::Result<Vec<NestedMeta>> {
+    let mut metas = Vec::new();
+    for attr in attrs.iter().filter(|a| a.path.is_ident("serde")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list.nested,
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[serde(...)]")),
+        };
+        for nested in list {
+            let path = match &nested {
+                NestedMeta::Meta(meta) => meta.path(),
+                NestedMeta::Lit(lit) => {
+                    return Err(syn::Error::new_spanned(lit, "expected a serde argument"))
+                }
+            };
+            let name = path.get_ident().map(|i| i.to_string()).unwrap_or_default();
+            if !allowed.contains(&name.as_str()) {
+                return Err(syn::Error::new_spanned(
+                    path,
+                    format!(
+                        "`#[serde({})]` can't be carried over to the patch, \
+                         Patch supports {}",
+                        name,
+                        allowed.join(", ")
+                    ),
+                ));
+            }
+            if name != "default" {
+                metas.push(nested);
+            }
+        }
+    }
+    Ok(metas)
+}
+
+/// Whether `ty` is spelled `Option<...>`
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(p) => p.path.segments.last().is_some_and(|s| s.ident == "Option"),
+        _ => false,
+    }
+}
+
+/// How a field ends up in the patch
+enum FieldKind {
+    Replace,
+    Nested,
+    Skip,
+}
+
+/// Everything inside the `#[patch(...)]` attributes
+fn patch_metas(attrs: &[Attribute]) -> syn::Result<Vec<NestedMeta>> {
+    let mut metas = Vec::new();
+    for attr in attrs.iter().filter(|a| a.path.is_ident("patch")) {
+        match attr.parse_meta()? {
+            Meta::List(list) => metas.extend(list.nested),
+            meta => return Err(syn::Error::new_spanned(meta, "expected #[patch(...)]")),
+        }
+    }
+    Ok(metas)
+}
+
+fn struct_options(attrs: &[Attribute]) -> syn::Result<StructOptions> {
+    let mut options = StructOptions::default();
+    for meta in patch_metas(attrs)? {
+        match meta {
+            NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("name") => match nv.lit {
+                Lit::Str(s) => options.name = Some(s.parse()?),
+                lit => return Err(syn::Error::new_spanned(lit, "expected a string")),
+            },
+            NestedMeta::Meta(Meta::List(list)) if list.path.is_ident("derive") => {
+                for nested in list.nested {
+                    match nested {
+                        NestedMeta::Meta(Meta::Path(path)) => options.derives.push(path),
+                        other => return Err(syn::Error::new_spanned(other, "expected a trait")),
+                    }
+                }
+            }
+            other => {
+                return Err(syn::Error::new_spanned(
+                    other,
+                    "expected `name = \"...\"` or `derive(...)`",
+                ))
+            }
+        }
+    }
+    Ok(options)
+}
+
+fn field_kind(attrs: &[Attribute]) -> syn::Result<FieldKind> {
+    let mut kind = FieldKind::Replace;
+    for meta in patch_metas(attrs)? {
+        match meta {
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("nested") => {
+                kind = FieldKind::Nested
+            }
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => kind = FieldKind::Skip,
+            other => {
+                return Err(syn::Error::new_spanned(
+                    other,
+                    "expected `nested` or `skip`",
+                ))
+            }
+        }
+    }
+    Ok(kind)
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    if !input.generics.params.is_empty() {
+        return Err(syn::Error::new_spanned(
+            &input.generics,
+            "Patch can't be derived for generic structs",
+        ));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "Patch needs a struct with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "Patch can only be derived for structs",
+            ))
+        }
+    };
+
+    let options = struct_options(&input.attrs)?;
+    let ident = &input.ident;
+    let vis = &input.vis;
+    let patch = options
+        .name
+        .clone()
+        .unwrap_or_else(|| format_ident!("{}Patch", ident));
+    let derives = &options.derives;
+    let doc = format!("Fields of [`{}`] to update, see `patch::Patch`", ident);
+
+    // serde attributes are only understood when the patch derives a serde trait
+    let (ser, de) = (options.derives("Serialize"), options.derives("Deserialize"));
+    let container_serde = serde_attrs(&input.attrs, SERDE_CONTAINER)?;
+    let container_serde = if (ser || de) && !container_serde.is_empty() {
+        Some(quote! { #[serde(#(#container_serde),*)] })
+    } else {
+        None
+    };
+    let set_option = format!("{}::__patch_set_option", patch);
+
+    let mut decls = Vec::new();
+    let mut applies = Vec::new();
+    for field in fields {
+        let name = field.ident.as_ref().unwrap();
+        let (field_vis, ty) = (&field.vis, &field.ty);
+        let docs: Vec<_> = field
+            .attrs
+            .iter()
+            .filter(|a| a.path.is_ident("doc"))
+            .collect();
+        let kind = field_kind(&field.attrs)?;
+        let mut serde = serde_attrs(&field.attrs, SERDE_FIELD)?;
+        let skips_de = serde.iter().any(|m| match m {
+            NestedMeta::Meta(Meta::Path(p)) => {
+                p.is_ident("skip") || p.is_ident("skip_deserializing")
+            }
+            _ => false,
+        });
+        if ser {
+            serde.push(syn::parse_quote!(skip_serializing_if = "Option::is_none"));
+        }
+        // a field which is an `Option` itself is cleared by `null`, which has to be told
+        // apart from the field missing
+        if de && !skips_de && is_option(ty) && matches!(kind, FieldKind::Replace) {
+            serde.push(syn::parse_quote!(default));
+            serde.push(syn::parse_quote!(deserialize_with = #set_option));
+        }
+        let serde = if (ser || de) && !serde.is_empty() {
+            Some(quote! { #[serde(#(#serde),*)] })
+        } else {
+            None
+        };
+        match kind {
+            FieldKind::Skip => continue,
+            FieldKind::Replace => {
+                decls.push(quote! { #(#docs)* #serde #field_vis #name: Option<#ty> });
+                applies.push(quote! {
+                    if let Some(value) = patch.#name {
+                        self.#name = value;
+                    }
+                });
+            }
+            FieldKind::Nested => {
+                decls.push(quote! {
+                    #(#docs)* #serde #field_vis #name: Option<<#ty as ::patch::Patch>::Patch>
+                });
+                applies.push(quote! {
+                    if let Some(value) = patch.#name {
+                        ::patch::Patch::apply(&mut self.#name, value);
+                    }
+                });
+            }
+        }
+    }
+
+    let helpers = if de {
+        Some(quote! {
+            impl #patch {
+                /// Present fields are set, including to `None` by `null`
+                #[doc(hidden)]
+                #[allow(dead_code)]
+                fn __patch_set_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+                where
+                    D: ::serde::Deserializer<'de>,
+                    T: ::serde::Deserialize<'de>,
+                {
+                    <T as ::serde::Deserialize<'de>>::deserialize(deserializer).map(Some)
+                }
+            }
+        })
+    } else {
+        None
+    };
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(#(#derives),*)]
+        #container_serde
+        #vis struct #patch {
+            #(#decls,)*
+        }
+
+        #helpers
+
+        impl ::patch::Patch for #ident {
+            type Patch = #patch;
+
+            #[allow(unused_variables)]
+            fn apply(&mut self, patch: #patch) {
+                #(#applies)*
+            }
+        }
+    })
+}
@@ -5,15 +5,17 @@ use std::time::{Duration, Instant};
 use actix::*;
 use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
+use futures::channel::oneshot;
 use log::{error, info};
 
 use crate::audiosys::{
-    analysis::AudioAnalysis, analysis::ParamsMessage as AudioParamsMessage, AnalyzerState,
-    AudioFeatures,
+    analysis::AudioAnalysis, analysis::ParamsMessage as AudioParamsMessage, devices::list_devices,
+    AnalyzerState, AudioFeatures,
 };
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
 
 async fn websocket(
     req: HttpRequest,
@@ -31,6 +33,84 @@ async fn websocket(
     )
 }
 
+async fn audio_devices() -> Result<HttpResponse, Error> {
+    let devices = list_devices().map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(devices))
+}
+
+#[derive(Deserialize)]
+struct DeviceRequest {
+    /// device name as reported by `audio_devices`, or null for the default device
+    device: Option<String>,
+}
+
+async fn set_audio_device(
+    req: web::Json<DeviceRequest>,
+    srv: web::Data<Addr<ApiServer>>,
+) -> Result<HttpResponse, Error> {
+    let DeviceRequest { device } = req.into_inner();
+    if let Some(name) = &device {
+        let devices = list_devices().map_err(actix_web::error::ErrorInternalServerError)?;
+        if !devices.iter().any(|d| &d.name == name) {
+            return Ok(HttpResponse::NotFound().body(format!("no such audio device: {}", name)));
+        }
+    }
+    let res = srv
+        .send(SetAudioDevice(device))
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    match res {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(e) => Ok(HttpResponse::InternalServerError().body(e.to_string())),
+    }
+}
+
+async fn get_config(srv: web::Data<Addr<ApiServer>>) -> Result<HttpResponse, Error> {
+    let config = srv
+        .send(GetConfig)
+        .await
+        .and_then(|res| res)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(config))
+}
+
+async fn update_config(
+    req: web::Json<OptionalConfig>,
+    srv: web::Data<Addr<ApiServer>>,
+) -> HttpResponse {
+    let res = srv
+        .send(ConfigMessage(req.into_inner()))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|res| res);
+    match res {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(e) => match e.downcast_ref::<Errors>() {
+            Some(errors) => HttpResponse::BadRequest().json(&errors.0),
+            None => HttpResponse::InternalServerError().body(e.to_string()),
+        },
+    }
+}
+
+async fn latency() -> HttpResponse {
+    HttpResponse::Ok().json(LATENCY.report())
+}
+
+async fn reset_latency() -> HttpResponse {
+    LATENCY.reset();
+    HttpResponse::NoContent().finish()
+}
+
+async fn metrics() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(METRICS.render())
+}
+
+async fn status() -> HttpResponse {
+    HttpResponse::Ok().json(STATUS.report())
+}
+
 pub struct WsSession {
     id: usize,
     hb: Instant,
@@ -132,7 +212,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
     }
 }
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 #[derive(Serialize)]
 enum WsResponse {
@@ -208,6 +288,10 @@ struct Subscribe {
     sub: Subscription,
 }
 
+#[derive(Message)]
+#[rtype(result = "anyhow::Result<()>")]
+struct SetAudioDevice(Option<String>);
+
 enum Subscription {
     AudioFeatures(Option<Recipient<AudioMessage>>),
 }
@@ -215,30 +299,41 @@ enum Subscription {
 pub struct ApiServer {
     sessions: HashMap<usize, Recipient<Message>>,
     rng: ThreadRng,
-    _app: Addr<MainApp>,
+    app: Addr<MainApp>,
     audio: Addr<AudioAnalysis>,
     audio_subs: HashMap<usize, Recipient<AudioMessage>>,
 }
 
 use super::App as MainApp;
+use crate::app::{ConfigMessage, GetConfig};
+use crate::config::{Config, OptionalConfig};
+use crate::latency::LATENCY;
+use crate::metrics::{Metrics, METRICS};
+use crate::status::{Subsystem, STATUS};
+use crate::validate::Errors;
 
 impl ApiServer {
-    pub fn new(_app: Addr<MainApp>, audio: Addr<AudioAnalysis>) -> Self {
+    pub fn new(app: Addr<MainApp>, audio: Addr<AudioAnalysis>) -> Self {
         Self {
             sessions: HashMap::new(),
             rng: rand::thread_rng(),
-            _app, // TODO: for now just hold on to this so it's not dropped
+            app,
             audio,
             audio_subs: HashMap::new(),
         }
     }
 
+    fn update_session_metrics(&self) {
+        Metrics::set(&METRICS.ws_sessions, self.sessions.len());
+        Metrics::set(&METRICS.ws_audio_subscribers, self.audio_subs.len());
+    }
+
     fn disable_audio_subscriptions(&self, ctx: &mut Context<Self>) {
         self.audio
             .send(AudioParamsMessage {
-                ap: None,
                 send_features: Some(false),
                 send_state: Some(false),
+                ..Default::default()
             })
             .into_actor(self)
             .then(|res, _, _| {
@@ -253,6 +348,17 @@ impl ApiServer {
 
 impl Actor for ApiServer {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // the http server outlives the pipeline threads, so exit and let the service manager
+        // restart us when one of them dies
+        ctx.run_interval(WATCHDOG_INTERVAL, |_act, _ctx| {
+            if let Some((sub, reason)) = STATUS.failed_critical() {
+                error!("critical subsystem {:?} failed: {}, exiting", sub, reason);
+                std::process::exit(1);
+            }
+        });
+    }
 }
 
 impl Handler<Connect> for ApiServer {
@@ -261,6 +367,7 @@ impl Handler<Connect> for ApiServer {
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
         let id = self.rng.gen::<usize>();
         self.sessions.insert(id, msg.addr);
+        self.update_session_metrics();
         id
     }
 }
@@ -271,6 +378,7 @@ impl Handler<Disconnect> for ApiServer {
     fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) {
         let _ = self.sessions.remove(&msg.id);
         let _ = self.audio_subs.remove(&msg.id);
+        self.update_session_metrics();
         if self.audio_subs.len() == 0 {
             self.disable_audio_subscriptions(ctx);
         }
@@ -302,11 +410,12 @@ impl Handler<Subscribe> for ApiServer {
         match msg.sub {
             Subscription::AudioFeatures(Some(addr)) => {
                 let _ = self.audio_subs.insert(msg.id, addr);
+                self.update_session_metrics();
                 self.audio
                     .send(AudioParamsMessage {
-                        ap: None,
                         send_features: Some(true),
                         send_state: Some(true),
+                        ..Default::default()
                     })
                     .into_actor(self)
                     .then(|res, _, _| {
@@ -319,6 +428,7 @@ impl Handler<Subscribe> for ApiServer {
             }
             Subscription::AudioFeatures(None) => {
                 let _ = self.audio_subs.remove(&msg.id);
+                self.update_session_metrics();
                 if self.audio_subs.len() == 0 {
                     self.disable_audio_subscriptions(ctx);
                 }
@@ -327,11 +437,63 @@ impl Handler<Subscribe> for ApiServer {
     }
 }
 
+impl Handler<SetAudioDevice> for ApiServer {
+    type Result = ResponseFuture<anyhow::Result<()>>;
+
+    /// Resolves once the audio thread has switched, or failed to switch, the device
+    fn handle(&mut self, msg: SetAudioDevice, _ctx: &mut Self::Context) -> Self::Result {
+        let (reply, result) = oneshot::channel();
+        self.audio.do_send(AudioParamsMessage {
+            device: Some(msg.0),
+            reply: Some(reply),
+            ..Default::default()
+        });
+        Box::pin(async move {
+            result
+                .await
+                .map_err(|_| anyhow::anyhow!("the audio thread has exited"))?
+        })
+    }
+}
+
+impl Handler<ConfigMessage> for ApiServer {
+    type Result = ResponseFuture<anyhow::Result<()>>;
+
+    fn handle(&mut self, msg: ConfigMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let req = self.app.send(msg);
+        Box::pin(async move { req.await? })
+    }
+}
+
+impl Handler<GetConfig> for ApiServer {
+    type Result = ResponseFuture<Result<Config, MailboxError>>;
+
+    fn handle(&mut self, msg: GetConfig, _ctx: &mut Self::Context) -> Self::Result {
+        let req = self.app.send(msg);
+        Box::pin(async move { req.await? })
+    }
+}
+
 pub async fn run(addr: &str, port: &str, server: Addr<ApiServer>) -> std::io::Result<()> {
+    STATUS.start(Subsystem::Api, false);
     HttpServer::new(move || {
         App::new()
             .data(server.clone())
+            .service(web::resource("/metrics").route(web::get().to(metrics)))
+            .service(web::resource("/api/v1/status").route(web::get().to(status)))
             .service(web::resource("/api/v1/ws/").to(websocket))
+            .service(
+                web::resource("/api/v1/config")
+                    .route(web::get().to(get_config))
+                    .route(web::put().to(update_config)),
+            )
+            .service(
+                web::resource("/api/v1/latency")
+                    .route(web::get().to(latency))
+                    .route(web::delete().to(reset_latency)),
+            )
+            .service(web::resource("/api/v1/audio/devices").route(web::get().to(audio_devices)))
+            .service(web::resource("/api/v1/audio/device").route(web::put().to(set_audio_device)))
     })
     .bind(format!("{}:{}", addr, port))?
     .run()
@@ -4,29 +4,44 @@ use amethyst::renderer::{
     plugins::RenderToWindow, rendy::hal::command::ClearColor, types::DefaultBackend,
     RenderingBundle,
 };
+#[cfg(feature = "gpu")]
+use amethyst::window::DisplayConfig;
 use amethyst::{
     core::{dispatcher::ThreadLocalSystem, frame_limiter::FrameRateLimitStrategy},
     ecs::*,
     prelude::*,
 };
+use anyhow::{anyhow, Result};
+use futures::channel::oneshot;
 use log::{debug, error};
+use panel_driver::Options as LedPanelOptions;
+use patch::Patch;
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TryRecvError};
 
 use crate::audiosys::{
-    analysis::{AudioSystem, Opts as AudioOpts},
+    analysis::{AudioAnalysis, AudioSystem, ParamsMessage},
     AnalyzerParams, AnalyzerState,
 };
-use crate::config::{Config, OptionalConfig};
-use crate::visualizer::Params as RenderParams;
+use crate::config::{Config, OptionalConfig, PanelSource};
+use crate::latency::{FrameTiming, SyncParams};
+use crate::status::{Subsystem, STATUS};
+use crate::validate::validate;
 #[cfg(feature = "ledpanel")]
+use crate::visualizer::ledpanel::RenderToPanel;
+#[cfg(feature = "strips")]
+use crate::visualizer::strips::RenderToStrips;
+#[cfg(feature = "gpu")]
+use crate::visualizer::{frame::RecordFrames, warpgrid::WarpGridRender};
 use crate::visualizer::{
-    ledpanel::{Options as LedPanelOptions, RenderToPanel},
+    frame::SharedFrame,
+    layers::CompositorParams,
+    modulation::{ModulatedParams, ModulationSystem, Modulations},
+    palette,
+    strips::StripOptions,
+    Params as RenderParams,
 };
-#[cfg(feature = "gpu")]
-use crate::visualizer::warpgrid::WarpGridRender;
 
 struct Init {
-    audio_opts: AudioOpts,
     config: Config,
 }
 
@@ -34,9 +49,16 @@ impl SimpleState for Init {
     fn on_start(&mut self, data: StateData<'_, GameData>) {
         data.resources.insert(Some(self.config.audio));
         data.resources.insert(self.config.render);
+        data.resources.insert(ModulatedParams(self.config.render));
+        data.resources.insert(self.config.modulation.clone());
+        data.resources.insert(self.config.compositor.clone());
+        data.resources.insert(self.config.sync);
+        data.resources.insert(FrameTiming::default());
         data.resources.insert(AnalyzerState::default());
+        data.resources.insert(self.config.panel.clone());
+        data.resources.insert(self.config.strips.clone());
 
-        let default_features = self.audio_opts.default_features();
+        let default_features = self.config.dimensions.default_features();
         data.resources.insert(default_features);
         log::debug!("@@@ inserted features");
     }
@@ -54,48 +76,43 @@ impl SimpleState for Visualizer {
 
 /// actor which contains the game engine allowing it to comminicate with other actors
 pub struct App {
+    config: Config,
     config_update: SyncSender<OptionalConfig>,
+    /// resizes the analyzer when the dimensions change
+    analysis: Addr<AudioAnalysis>,
 }
 
 impl App {
+    /// Start the game loop, `config` has to be valid
     pub(crate) fn new(
         config: Config,
-        audio_opts: AudioOpts,
         audio: AudioSystem,
-        _verbose: i32,
+        analysis: Addr<AudioAnalysis>,
+        verbose: i32,
     ) -> Self {
         let (config_update, config_mailbox) = sync_channel(1);
+        let current_config = config.clone();
+        if let Err(e) = palette::activate(&config.palette) {
+            error!("failed to load palette, using the default: {}", e);
+        }
 
         let app_system = AppSystem { config_mailbox };
 
         std::thread::spawn(move || {
+            let _status = STATUS.guard(Subsystem::GameLoop, true);
+
             let mut dispatcher = DispatcherBuilder::default();
             dispatcher.add_thread_local(audio);
             dispatcher.add_thread_local(app_system);
+            dispatcher.add_thread_local(ModulationSystem::default());
 
-            #[cfg(feature = "gpu")]
-            {
-                dispatcher.add_bundle(
-                    RenderingBundle::<DefaultBackend>::new()
-                        .with_plugin(
-                            RenderToWindow::from_config_path(display_config_path)
-                                .unwrap()
-                                .with_clear(ClearColor {
-                                    float32: [0.0, 0.0, 0.0, 1.0],
-                                }),
-                        )
-                        .with_plugin(WarpGridRender::default()),
-                );
-            }
-
-            #[cfg(feature = "ledpanel")]
-            {
-                let render = RenderToPanel::new(_verbose, config.panel.clone());
-                dispatcher.add_thread_local(render);
+            if let Err(e) = add_renderers(&mut dispatcher, &config, verbose) {
+                error!("failed to add renderers: {}", e);
+                return;
             }
 
             let app_root = std::path::Path::new(".");
-            let game = Application::build(app_root, Init { audio_opts, config })
+            let game = Application::build(app_root, Init { config })
                 .expect("failed to create app builder")
                 .with_frame_limit(
                     FrameRateLimitStrategy::SleepAndYield(std::time::Duration::from_millis(1)),
@@ -105,11 +122,89 @@ impl App {
                 .expect("failed to build app");
             game.run();
 
-            println!("oh, we done..?");
+            error!("game loop exited");
         });
 
-        Self { config_update }
+        Self {
+            config: current_config,
+            config_update,
+            analysis,
+        }
+    }
+}
+
+/// Add the renderers selected in `config`, which validation has found in this build
+#[cfg_attr(
+    not(all(feature = "gpu", feature = "ledpanel", feature = "strips")),
+    allow(unused_variables)
+)]
+fn add_renderers(dispatcher: &mut DispatcherBuilder, config: &Config, verbose: i32) -> Result<()> {
+    let renderers = &config.renderers;
+    let gpu_frames = if renderers.reads_back() {
+        Some(SharedFrame::default())
+    } else {
+        None
+    };
+    let from_gpu = |source: Option<PanelSource>| match (source, &gpu_frames) {
+        (Some(PanelSource::Gpu), Some(frames)) => Some(frames.clone()),
+        _ => None,
+    };
+
+    #[cfg(feature = "gpu")]
+    if renderers.uses_gpu() {
+        let mut warpgrid = WarpGridRender::default();
+        if let Some(frames) = &gpu_frames {
+            // the strips scale frames of another size, so they only get their own size
+            // when the panel doesn't need its
+            let size = if renderers.strips == Some(PanelSource::Gpu)
+                && renderers.panel != Some(PanelSource::Gpu)
+            {
+                config.strips.frame_size()
+            } else {
+                config.panel.frame_size()
+            };
+            warpgrid = warpgrid.with_readback(size, frames.clone());
+        }
+        if !renderers.window {
+            warpgrid = warpgrid.offscreen_only();
+        }
+        let mut bundle = RenderingBundle::<DefaultBackend>::new();
+        if renderers.window {
+            let window = match &renderers.display_config {
+                Some(path) => RenderToWindow::from_config_path(path)?,
+                None => RenderToWindow::from_config(DisplayConfig::default()),
+            };
+            bundle = bundle.with_plugin(window.with_clear(ClearColor {
+                float32: [0.0, 0.0, 0.0, 1.0],
+            }));
+        }
+        dispatcher.add_bundle(bundle.with_plugin(warpgrid));
+    }
+
+    #[cfg(feature = "ledpanel")]
+    if renderers.panel.is_some() {
+        let mut render = RenderToPanel::new(verbose, config.panel.clone());
+        if let Some(frames) = from_gpu(renderers.panel) {
+            render = render.with_gpu_frames(frames);
+        }
+        dispatcher.add_thread_local(render);
+    }
+
+    #[cfg(feature = "strips")]
+    if renderers.strips.is_some() {
+        let mut render = RenderToStrips::new(verbose, config.strips.clone())?;
+        if let Some(frames) = from_gpu(renderers.strips) {
+            render = render.with_gpu_frames(frames);
+        }
+        dispatcher.add_thread_local(render);
+    }
+
+    #[cfg(feature = "gpu")]
+    if let (Some(path), Some(frames)) = (&renderers.record_frames, &gpu_frames) {
+        dispatcher.add_thread_local(RecordFrames::create(path, frames.clone())?);
     }
+
+    Ok(())
 }
 
 impl Actor for App {
@@ -117,16 +212,74 @@ impl Actor for App {
 }
 
 #[derive(Message)]
-#[rtype(result = "()")]
-pub struct ConfigMessage(OptionalConfig);
+#[rtype(result = "Result<()>")]
+pub(crate) struct ConfigMessage(pub OptionalConfig);
 
 impl Handler<ConfigMessage> for App {
-    type Result = ();
+    type Result = ResponseActFuture<Self, Result<()>>;
+
+    /// Apply the update unless it leaves the config invalid. New dimensions are only
+    /// applied once the audio thread has resized the analyzer, the rest of the update
+    /// is applied right away.
+    fn handle(&mut self, mut config: ConfigMessage, _ctx: &mut Self::Context) -> Self::Result {
+        let mut updated = self.config.clone();
+        updated.apply(config.0.clone());
+        if let Err(e) = validate(&updated) {
+            return Box::pin(fut::err(e));
+        }
 
-    fn handle(&mut self, config: ConfigMessage, _ctx: &mut Self::Context) {
+        if let Some(pp) = &config.0.palette {
+            if let Err(e) = palette::activate(pp) {
+                error!("failed to load palette: {}", e);
+                config.0.palette = None;
+            }
+        }
+        let dimensions = config
+            .0
+            .dimensions
+            .take()
+            .filter(|&d| d != self.config.dimensions);
+        self.config.apply(config.0.clone());
         if let Err(e) = self.config_update.send(config.0) {
             log::error!("failed to send config_update: {}", e);
         }
+
+        let dimensions = match dimensions {
+            Some(d) => d,
+            None => return Box::pin(fut::ok(())),
+        };
+        let (reply, result) = oneshot::channel();
+        self.analysis.do_send(ParamsMessage {
+            dimensions: Some(dimensions),
+            reply: Some(reply),
+            ..Default::default()
+        });
+        Box::pin(
+            async move {
+                result
+                    .await
+                    .map_err(|_| anyhow!("the audio thread has exited"))?
+            }
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                res.map(|()| {
+                    debug!("updated dimensions: {:?}", dimensions);
+                    act.config.dimensions = dimensions;
+                })
+            }),
+        )
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Config, MailboxError>")]
+pub(crate) struct GetConfig;
+
+impl Handler<GetConfig> for App {
+    type Result = Result<Config, MailboxError>;
+
+    fn handle(&mut self, _msg: GetConfig, _ctx: &mut Self::Context) -> Self::Result {
+        Ok(self.config.clone())
     }
 }
 
@@ -138,12 +291,15 @@ impl ThreadLocalSystem<'_> for AppSystem {
     fn build(self) -> Box<dyn Runnable> {
         let builder = SystemBuilder::new("app system")
             .write_resource::<Option<AnalyzerParams>>()
-            .write_resource::<RenderParams>();
-
-        #[cfg(feature = "ledpanel")]
-        let builder = builder.write_resource::<LedPanelOptions>();
+            .write_resource::<RenderParams>()
+            .write_resource::<SyncParams>()
+            .write_resource::<CompositorParams>()
+            .write_resource::<Modulations>()
+            .write_resource::<LedPanelOptions>()
+            .write_resource::<StripOptions>();
 
         Box::new(builder.build(move |_commands, _world, resources, _query| {
+            STATUS.heartbeat(Subsystem::GameLoop);
             match self.config_mailbox.try_recv() {
                 Err(TryRecvError::Empty) => (),
                 Ok(config) => {
@@ -152,15 +308,28 @@ impl ThreadLocalSystem<'_> for AppSystem {
                         resources.0.replace(ap);
                     }
                     if let Some(rp) = config.render {
-                        debug!("updated render params: {:?}", rp);
-                        *resources.1 = rp;
+                        resources.1.apply(rp);
+                        debug!("updated render params: {:?}", *resources.1);
+                    }
+                    if let Some(sp) = config.sync {
+                        debug!("updated sync params: {:?}", sp);
+                        *resources.2 = sp;
+                    }
+                    if let Some(cp) = config.compositor {
+                        debug!("updated compositor params: {:?}", cp);
+                        *resources.3 = cp;
+                    }
+                    if let Some(m) = config.modulation {
+                        debug!("updated modulations: {:?}", m);
+                        *resources.4 = m;
                     }
-                    // FIXME: this will be a mess as soon as there are multiple options
-                    // maybe options can always be in the struct but have no effect unless enabled
-                    #[cfg(feature = "ledpanel")]
                     if let Some(lp) = config.panel {
-                        debug!("updated panel config: {:?}", lp);
-                        *resources.2 = lp;
+                        resources.5.apply(lp);
+                        debug!("updated panel config: {:?}", *resources.5);
+                    }
+                    if let Some(so) = config.strips {
+                        debug!("updated strip config: {:?}", so);
+                        *resources.6 = so;
                     }
                 }
                 Err(e) => error!("error recv on config_mailbox: {}", e),
@@ -1,13 +1,29 @@
-use std::sync::mpsc::{channel, sync_channel, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{
+    channel, sync_channel, Receiver, RecvTimeoutError, SyncSender, TryRecvError, TrySendError,
+};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use actix::Recipient;
 use amethyst::{core::dispatcher::ThreadLocalSystem, prelude::*};
+use anyhow::{anyhow, Result};
 use audio::Analyzer;
 use clap::Clap;
+use futures::channel::oneshot;
+use serde::{Deserialize, Serialize};
 
-use super::{AnalyzerParams, AudioFeatures};
+use super::{
+    devices::list_devices,
+    record::{Frame, Player, Recorder},
+    AnalyzerParams, AudioFeatures,
+};
 use crate::api::AudioMessage;
+use crate::latency::{FrameTiming, Stage, SyncParams, LATENCY};
+use crate::metrics::{Metrics, METRICS};
+use crate::status::{Subsystem, STATUS};
+use crate::validate::{Validate, Validator};
 
 #[derive(Clap, Clone)]
 pub struct Opts {
@@ -17,23 +33,103 @@ pub struct Opts {
     #[clap(long, short = 'r', default_value = "44100")]
     sample_rate: usize,
 
-    #[clap(long, short = 'b', default_value = "256")]
-    sample_block_size: usize,
+    /// Overrides `dimensions.sample_block_size` from the config
+    #[clap(long, short = 'b')]
+    sample_block_size: Option<usize>,
 
-    #[clap(long, short = 'f', default_value = "1024")]
-    fft_size: usize,
+    /// Overrides `dimensions.fft_size` from the config
+    #[clap(long, short = 'f')]
+    fft_size: Option<usize>,
 
-    #[clap(long, short = 'n', default_value = "16")]
-    pub bins: usize,
+    /// Overrides `dimensions.bins` from the config
+    #[clap(long, short = 'n')]
+    bins: Option<usize>,
 
-    #[clap(long, short = 'l', default_value = "144")]
-    pub length: usize,
+    /// Overrides `dimensions.length` from the config
+    #[clap(long, short = 'l')]
+    length: Option<usize>,
+
+    /// Record analyzer output to this file
+    #[clap(long)]
+    record: Option<String>,
+
+    /// Include the analyzer state in the recording
+    #[clap(long)]
+    record_state: bool,
+
+    /// Replay a recording instead of analyzing live audio
+    #[clap(long)]
+    pub replay: Option<String>,
+
+    /// Restart the replay when the end of the recording is reached
+    #[clap(long)]
+    replay_loop: bool,
 }
 
 impl Opts {
+    /// Apply the command line overrides to the configured dimensions
+    pub fn dimensions(&self, config: Dimensions) -> Dimensions {
+        Dimensions {
+            bins: self.bins.unwrap_or(config.bins),
+            length: self.length.unwrap_or(config.length),
+            fft_size: self.fft_size.unwrap_or(config.fft_size),
+            sample_block_size: self.sample_block_size.unwrap_or(config.sample_block_size),
+        }
+    }
+}
+
+/// Shape of the analyzer and of the features it produces
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Dimensions {
+    pub bins: usize,
+    pub length: usize,
+    pub fft_size: usize,
+    pub sample_block_size: usize,
+}
+
+impl Default for Dimensions {
+    fn default() -> Self {
+        Self {
+            bins: 16,
+            length: 144,
+            fft_size: 1024,
+            sample_block_size: 256,
+        }
+    }
+}
+
+impl Validate for Dimensions {
+    fn validate(&self, v: &mut Validator) {
+        v.at_least("bins", self.bins as f64, 1.);
+        // the warp grid needs two columns to draw anything
+        v.at_least("length", self.length as f64, 2.);
+        v.check(
+            "fft_size",
+            self.fft_size.is_power_of_two(),
+            format!("{} must be a power of 2", self.fft_size),
+        );
+        v.between(
+            "sample_block_size",
+            self.sample_block_size as f64,
+            1.,
+            self.fft_size as f64,
+        );
+    }
+}
+
+impl Dimensions {
     pub fn default_features(&self) -> AudioFeatures {
         AudioFeatures::new(self.bins, self.length)
     }
+
+    fn analyzer(&self) -> Analyzer {
+        Analyzer::new(
+            self.fft_size,
+            self.sample_block_size,
+            self.bins,
+            self.length,
+        )
+    }
 }
 
 #[derive(Default)]
@@ -50,6 +146,7 @@ pub struct AudioAnalysis {
 impl AudioAnalysis {
     pub(crate) fn new(
         opts: Opts,
+        dimensions: Dimensions,
         params: Params,
         stream_receiver: Recipient<AudioMessage>,
         verbose: i32,
@@ -57,10 +154,9 @@ impl AudioAnalysis {
         let Opts {
             device,
             sample_rate,
-            sample_block_size,
-            fft_size,
-            bins,
-            length,
+            record,
+            record_state,
+            ..
         } = opts;
         let (audio_data_tx, audio_data_rx) = channel();
         let (send_features, get_features) = sync_channel(1);
@@ -76,40 +172,101 @@ impl AudioAnalysis {
                 // user that is running the daemon.
                 thread::sleep(std::time::Duration::from_secs(2));
             }
+            let _audio_status = STATUS.guard(Subsystem::Audio, true);
+            let _analysis_status = STATUS.guard(Subsystem::Analysis, true);
 
-            let mut analyzer = Analyzer::new(fft_size, sample_block_size, bins, length);
+            let mut dimensions = dimensions;
+            let mut analyzer = dimensions.analyzer();
             let mut params = params;
 
-            let handle_stream = move |data: &[f32]| {
-                if verbose >= 4 {
-                    println!("tx audio");
-                }
-                let data = data.iter().map(|&x| x as f64).collect();
-                if let Err(e) = audio_data_tx.send(data) {
-                    if verbose >= 3 {
-                        println!(
-                            "[{:08}]: failed to send audio data: {}",
-                            now.elapsed().unwrap().as_millis(),
-                            e
-                        );
+            let open_stream = |device: Option<&str>, sample_block_size: usize| -> Result<_> {
+                let audio_data_tx = audio_data_tx.clone();
+                let handle_stream = move |data: &[f32]| {
+                    if verbose >= 4 {
+                        println!("tx audio");
+                    }
+                    let data = data.iter().map(|&x| x as f64).collect();
+                    match audio_data_tx.send((Instant::now(), data)) {
+                        Ok(()) => {
+                            METRICS.audio_queue_depth.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) if verbose >= 3 => {
+                            println!(
+                                "[{:08}]: failed to send audio data: {}",
+                                now.elapsed().unwrap().as_millis(),
+                                e
+                            );
+                        }
+                        Err(_) => (),
+                    }
+                };
+                // random rust thing:
+                // https://stackoverflow.com/questions/25649423/sending-trait-objects-between-threads-in-rust
+                let handle_stream = Box::new(handle_stream) as Box<dyn Fn(&[f32]) -> () + Send>;
+
+                let s = audio::Source::new(device)
+                    .map_err(|e| anyhow!("failed to get device {:?}: {:?}", device, e))?;
+                let stream = s
+                    .get_stream(
+                        1,
+                        sample_rate as u32,
+                        sample_block_size as u32,
+                        handle_stream,
+                    )
+                    .map_err(|e| anyhow!("failed to get stream: {:?}", e))?;
+                Ok((s, stream))
+            };
+
+            // Replace the stream with one opened with `to`, going back to `from` if that
+            // fails. The audio stage is marked stopped when neither can be opened.
+            let switch_stream = |stream: &mut Option<_>,
+                                 to: (Option<&str>, usize),
+                                 from: (Option<&str>, usize)|
+             -> Result<()> {
+                // drop the old stream first, some backends won't open a device twice
+                stream.take();
+                let e = match open_stream(to.0, to.1) {
+                    Ok(s) => {
+                        *stream = Some(s);
+                        return Ok(());
+                    }
+                    Err(e) => e,
+                };
+                STATUS.error(Subsystem::Audio, e.to_string());
+                match open_stream(from.0, from.1) {
+                    Ok(s) => *stream = Some(s),
+                    Err(reopen) => {
+                        log::error!("failed to reopen the previous audio stream: {}", reopen);
+                        STATUS.stopped(Subsystem::Audio, Some(reopen.to_string()));
                     }
                 }
+                Err(e)
             };
-            // random rust thing:
-            // https://stackoverflow.com/questions/25649423/sending-trait-objects-between-threads-in-rust
-            let handle_stream = Box::new(handle_stream) as Box<dyn Fn(&[f32]) -> () + Send>;
-
-            audio::Source::print_devices(false).expect("failed to print devices");
-
-            let s = audio::Source::new(device.as_deref()).expect("failed to get device");
-            let _stream = s
-                .get_stream(
-                    1,
-                    sample_rate as u32,
-                    sample_block_size as u32,
-                    handle_stream,
-                )
-                .expect("failed to get stream");
+
+            if verbose > 0 {
+                match list_devices() {
+                    Ok(devices) => {
+                        for d in devices {
+                            log::debug!("found audio device: {} ({})", d.name, d.host);
+                        }
+                    }
+                    Err(e) => log::warn!("failed to list audio devices: {}", e),
+                }
+            }
+
+            let mut recorder = record.map(|path| {
+                Recorder::create(&path, record_state, dimensions)
+                    .expect("failed to create recording")
+            });
+
+            LATENCY.set_audio_format(sample_rate, dimensions.sample_block_size);
+
+            let mut current_device = device;
+            let mut stream = Some(
+                open_stream(current_device.as_deref(), dimensions.sample_block_size)
+                    .expect("failed to get stream"),
+            );
+            STATUS.set_device(Subsystem::Audio, Some(device_name(&current_device)));
 
             loop {
                 match recv_params.try_recv() {
@@ -117,7 +274,11 @@ impl AudioAnalysis {
                         ap,
                         send_features,
                         send_state,
+                        device,
+                        dimensions: new_dimensions,
+                        reply,
                     }) => {
+                        let mut result = Ok(());
                         if let Some(ap) = ap {
                             params.ap = ap;
                         }
@@ -127,6 +288,70 @@ impl AudioAnalysis {
                         if let Some(ss) = send_state {
                             params.send_state = ss;
                         }
+                        if let Some(device) = device {
+                            let block = dimensions.sample_block_size;
+                            let previous = current_device.clone();
+                            match switch_stream(
+                                &mut stream,
+                                (device.as_deref(), block),
+                                (previous.as_deref(), block),
+                            ) {
+                                Ok(()) => {
+                                    log::info!(
+                                        "switched audio device to {}",
+                                        device.as_deref().unwrap_or("default")
+                                    );
+                                    current_device = device;
+                                    STATUS.set_device(
+                                        Subsystem::Audio,
+                                        Some(device_name(&current_device)),
+                                    );
+                                }
+                                Err(e) => {
+                                    log::error!("failed to switch audio device: {}", e);
+                                    result = Err(e);
+                                }
+                            }
+                        }
+                        if let Some(nd) = new_dimensions.filter(|&nd| nd != dimensions) {
+                            let reopened = if nd.sample_block_size != dimensions.sample_block_size {
+                                let device = current_device.as_deref();
+                                switch_stream(
+                                    &mut stream,
+                                    (device, nd.sample_block_size),
+                                    (device, dimensions.sample_block_size),
+                                )
+                                .map(|()| {
+                                    // discard blocks captured with the old block size
+                                    while audio_data_rx.try_recv().is_ok() {
+                                        METRICS.audio_queue_depth.fetch_sub(1, Ordering::Relaxed);
+                                    }
+                                    LATENCY.set_audio_format(sample_rate, nd.sample_block_size);
+                                })
+                            } else {
+                                Ok(())
+                            };
+                            match reopened {
+                                Ok(()) => {
+                                    log::info!("resizing analyzer to {:?}", nd);
+                                    analyzer = nd.analyzer();
+                                    dimensions = nd;
+                                    if recorder.take().is_some() {
+                                        // the header only holds the first dimensions
+                                        log::warn!("stopped recording, the analyzer was resized");
+                                    }
+                                }
+                                Err(e) => {
+                                    // keep analyzing with the old dimensions
+                                    log::error!("failed to resize analyzer to {:?}: {}", nd, e);
+                                    result = result.and(Err(e));
+                                }
+                            }
+                        }
+                        if let Some(reply) = reply {
+                            // the caller may have given up waiting
+                            let _ = reply.send(result);
+                        }
                     }
                     Err(TryRecvError::Empty) => (),
                     Err(e) => {
@@ -135,9 +360,17 @@ impl AudioAnalysis {
                     }
                 };
 
-                match audio_data_rx.recv() {
-                    Ok(mut data) => {
+                // time out so that a device switch can still be received without a stream
+                match audio_data_rx.recv_timeout(Duration::from_millis(100)) {
+                    Ok((captured, mut data)) => {
+                        STATUS.heartbeat(Subsystem::Audio);
+                        METRICS.audio_queue_depth.fetch_sub(1, Ordering::Relaxed);
                         if let Some(features) = analyzer.process(&mut data, &params.ap) {
+                            let timing = FrameTiming::new(captured);
+                            LATENCY.record(Stage::Analysis, timing.analyzed - captured);
+                            Metrics::inc(&METRICS.analysis_frames);
+                            STATUS.heartbeat(Subsystem::Analysis);
+
                             if verbose >= 2 && features.get_frame_count() % 32 == 0 {
                                 let mut out = String::new();
                                 analyzer
@@ -145,9 +378,11 @@ impl AudioAnalysis {
                                     .expect("failed to write debug");
                                 println!("{}", out);
                             }
-                            if let Err(e) = send_features.try_send(features.clone()) {
+                            if let Err(e) = send_features.try_send((features.clone(), timing)) {
                                 match e {
-                                    TrySendError::Full(_) => (),
+                                    TrySendError::Full(_) => {
+                                        Metrics::inc(&METRICS.dropped_features)
+                                    }
                                     e => {
                                         if verbose >= 3 {
                                             println!(
@@ -160,6 +395,18 @@ impl AudioAnalysis {
                                 }
                             }
 
+                            if let Some(rec) = recorder.as_mut() {
+                                let state = if rec.with_state() {
+                                    Some(analyzer.get_state())
+                                } else {
+                                    None
+                                };
+                                if let Err(e) = rec.write(&features, state.as_ref()) {
+                                    log::error!("failed to record features, stopping: {}", e);
+                                    recorder = None;
+                                }
+                            }
+
                             let Params {
                                 send_features,
                                 send_state,
@@ -179,8 +426,10 @@ impl AudioAnalysis {
                             }
                         }
                     }
+                    Err(RecvTimeoutError::Timeout) => (),
                     Err(e) => {
                         println!("failed to recv audio: {}", e);
+                        STATUS.stopped(Subsystem::Audio, Some(e.to_string()));
                         break;
                     }
                 };
@@ -201,10 +450,145 @@ impl AudioAnalysis {
             },
         )
     }
+
+    /// Feed frames from a recording made with `--record` instead of analyzing live audio.
+    pub(crate) fn replay(
+        opts: Opts,
+        dimensions: Dimensions,
+        params: Params,
+        stream_receiver: Recipient<AudioMessage>,
+        verbose: i32,
+    ) -> (Self, AudioSystem) {
+        let path = opts.replay.expect("replay requires a recording");
+        let replay_loop = opts.replay_loop;
+        let (send_features, get_features) = sync_channel(1);
+        let (send_params, recv_params) = sync_channel(1);
+
+        thread::spawn(move || {
+            // a replay which runs out isn't a failure unless it was supposed to loop
+            let _status = STATUS.guard(Subsystem::Analysis, replay_loop);
+            STATUS.set_device(Subsystem::Analysis, Some(format!("replay: {}", path)));
+
+            let mut params = params;
+            let mut player = Player::open(&path).expect("failed to open recording");
+            if player.dimensions() != dimensions {
+                let e = format!(
+                    "recording has dimensions {:?}, expected {:?}",
+                    player.dimensions(),
+                    dimensions
+                );
+                log::error!("failed to replay {}: {}", path, e);
+                STATUS.stopped(Subsystem::Analysis, Some(e));
+                return;
+            }
+            let mut start = Instant::now();
+
+            loop {
+                match recv_params.try_recv() {
+                    Ok(ParamsMessage {
+                        ap,
+                        send_features,
+                        send_state,
+                        device,
+                        dimensions: new_dimensions,
+                        reply,
+                    }) => {
+                        let mut result = Ok(());
+                        if ap.is_some() {
+                            log::debug!("ignoring analyzer params, the replay is already analyzed");
+                        }
+                        if let Some(sf) = send_features {
+                            params.send_features = sf;
+                        }
+                        if let Some(ss) = send_state {
+                            params.send_state = ss;
+                        }
+                        if device.is_some() {
+                            log::warn!("can't switch the audio device during a replay");
+                            result = Err(anyhow!("no audio device is used during a replay"));
+                        }
+                        if let Some(nd) = new_dimensions.filter(|&nd| nd != dimensions) {
+                            log::warn!("can't resize a replay to {:?}", nd);
+                            result = result.and(Err(anyhow!(
+                                "the replay has fixed dimensions {:?}",
+                                dimensions
+                            )));
+                        }
+                        if let Some(reply) = reply {
+                            let _ = reply.send(result);
+                        }
+                    }
+                    Err(TryRecvError::Empty) => (),
+                    Err(e) => {
+                        println!("failed to recv params: {}", e);
+                        break;
+                    }
+                };
+
+                let Frame {
+                    time,
+                    features,
+                    state,
+                } = match player.next_frame() {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) if replay_loop => {
+                        player = Player::open(&path).expect("failed to reopen recording");
+                        start = Instant::now();
+                        continue;
+                    }
+                    Ok(None) => {
+                        log::info!("replay of {} finished", path);
+                        break;
+                    }
+                    Err(e) => {
+                        log::error!("failed to read recording: {}", e);
+                        STATUS.stopped(Subsystem::Analysis, Some(e.to_string()));
+                        break;
+                    }
+                };
+
+                if let Some(wait) = time.checked_sub(start.elapsed()) {
+                    thread::sleep(wait);
+                }
+                STATUS.heartbeat(Subsystem::Analysis);
+                if verbose >= 4 {
+                    println!("replay frame #{}", features.get_frame_count());
+                }
+
+                let timing = FrameTiming::new(Instant::now());
+                if let Err(TrySendError::Disconnected(_)) =
+                    send_features.try_send((features.clone(), timing))
+                {
+                    break;
+                }
+                if params.send_features {
+                    let state = if params.send_state { state } else { None };
+                    if let Err(e) = stream_receiver.try_send(AudioMessage(features, state)) {
+                        log::error!("failed to send AudioMessage: {}", e);
+                    }
+                }
+            }
+        });
+
+        (
+            Self {
+                send_params: send_params.clone(),
+            },
+            AudioSystem {
+                get_features,
+                send_params,
+                verbose,
+            },
+        )
+    }
+}
+
+fn device_name(device: &Option<String>) -> String {
+    device.clone().unwrap_or_else(|| "default".to_string())
 }
 
 pub struct AudioSystem {
-    get_features: Receiver<AudioFeatures>,
+    get_features: Receiver<(AudioFeatures, FrameTiming)>,
     send_params: SyncSender<ParamsMessage>,
     verbose: i32,
 }
@@ -212,31 +596,56 @@ pub struct AudioSystem {
 impl ThreadLocalSystem<'static> for AudioSystem {
     fn build(self) -> Box<dyn Runnable> {
         let mut now = std::time::SystemTime::now();
+        // frames held back to honor `SyncParams::visual_delay_ms`
+        let mut delayed = VecDeque::new();
         Box::new(
             SystemBuilder::new("AudioAnalysis")
                 .write_resource::<AudioFeatures>()
+                .write_resource::<FrameTiming>()
                 .write_resource::<Option<AnalyzerParams>>()
-                .build(move |_commands, _world, (features, params), _queries| {
-                    if let Ok(feat) = self.get_features.try_recv() {
-                        if self.verbose >= 3 {
-                            log::trace!(
-                                "[{:?}] AudioAnalysis system received features #{}",
-                                now.elapsed(),
-                                feat.get_frame_count(),
-                            );
-                            now = std::time::SystemTime::now();
+                .read_resource::<SyncParams>()
+                .build(
+                    move |_commands, _world, (features, frame_timing, params, sync), _queries| {
+                        while let Ok((feat, mut timing)) = self.get_features.try_recv() {
+                            timing.received = Instant::now();
+                            LATENCY.record(Stage::Queue, timing.received - timing.analyzed);
+                            delayed.push_back((feat, timing));
                         }
-                        **features = feat;
-                    }
-                    if let Some(params) = params.take() {
-                        if let Err(e) = self.send_params.send(ParamsMessage {
-                            ap: Some(params),
-                            ..Default::default()
-                        }) {
-                            log::error!("failed to send params: {}", e);
+
+                        let delay = sync.visual_delay();
+                        while let Some((_, timing)) = delayed.front() {
+                            if timing.captured.elapsed() < delay {
+                                break;
+                            }
+                            let (feat, timing) = delayed.pop_front().unwrap();
+                            LATENCY.record_sync(sync, timing.captured.elapsed());
+                            if self.verbose >= 3 {
+                                log::trace!(
+                                    "[{:?}] AudioAnalysis system received features #{}",
+                                    now.elapsed(),
+                                    feat.get_frame_count(),
+                                );
+                                now = std::time::SystemTime::now();
+                            }
+                            **features = feat;
+                            **frame_timing = timing;
                         }
-                    }
-                }),
+                        Metrics::set(&METRICS.delay_queue_depth, delayed.len());
+
+                        // don't block the game loop on a busy audio thread, the params are
+                        // sent again on the next frame
+                        if let Some(ap) = params.take() {
+                            match self.send_params.try_send(ParamsMessage {
+                                ap: Some(ap),
+                                ..Default::default()
+                            }) {
+                                Ok(()) => (),
+                                Err(TrySendError::Full(msg)) => **params = msg.ap,
+                                Err(e) => log::error!("failed to send params: {}", e),
+                            }
+                        }
+                    },
+                ),
         )
     }
 }
@@ -246,9 +655,14 @@ use actix::{Actor, Context, Handler, Message};
 #[derive(Message, Default)]
 #[rtype(result = "()")]
 pub struct ParamsMessage {
+    /// receives the result of a device or dimensions change
+    pub reply: Option<oneshot::Sender<Result<()>>>,
     pub ap: Option<AnalyzerParams>,
     pub send_features: Option<bool>,
     pub send_state: Option<bool>,
+    /// Switch the capture device, `Some(None)` selects the default device
+    pub device: Option<Option<String>>,
+    pub dimensions: Option<Dimensions>,
 }
 
 impl Actor for AudioAnalysis {
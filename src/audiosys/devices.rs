@@ -0,0 +1,86 @@
+use anyhow::Result;
+use clap::Clap;
+use cpal::traits::{DeviceTrait, HostTrait};
+use serde::Serialize;
+
+/// List audio capture devices
+#[derive(Clap)]
+pub struct Opts {
+    /// Print the device list as JSON
+    #[clap(long)]
+    json: bool,
+}
+
+/// An audio input device which can be selected with `--device` or through the api
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceInfo {
+    pub host: String,
+    pub name: String,
+    pub is_default: bool,
+    pub channels: Option<u16>,
+    pub sample_rate: Option<u32>,
+}
+
+/// List the input devices of the default audio host, which `audio::Source` opens
+/// devices on
+pub fn list_devices() -> Result<Vec<DeviceInfo>> {
+    let host = cpal::default_host();
+    let host_name = host.id().name();
+    let default_name = host.default_input_device().and_then(|d| d.name().ok());
+    let mut devices = Vec::new();
+    for device in host.input_devices()? {
+        let name = match device.name() {
+            Ok(name) => name,
+            Err(e) => {
+                log::warn!("skipping device without a name: {}", e);
+                continue;
+            }
+        };
+        let config = device.default_input_config().ok();
+        devices.push(DeviceInfo {
+            host: host_name.to_string(),
+            is_default: default_name.as_deref() == Some(name.as_str()),
+            channels: config.as_ref().map(|c| c.channels()),
+            sample_rate: config.map(|c| c.sample_rate().0),
+            name,
+        });
+    }
+    Ok(devices)
+}
+
+pub fn print_devices(opts: &Opts) -> Result<()> {
+    let devices = list_devices()?;
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+
+    let width = devices
+        .iter()
+        .map(|d| d.name.len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "{:<8} {:<width$} {:>8} {:>11}  {}",
+        "HOST",
+        "NAME",
+        "CHANNELS",
+        "SAMPLE RATE",
+        "DEFAULT",
+        width = width
+    );
+    for d in &devices {
+        let opt = |v: Option<String>| v.unwrap_or_else(|| "-".to_string());
+        println!(
+            "{:<8} {:<width$} {:>8} {:>11}  {}",
+            d.host,
+            d.name,
+            opt(d.channels.map(|c| c.to_string())),
+            opt(d.sample_rate.map(|r| r.to_string())),
+            if d.is_default { "*" } else { "" },
+            width = width
+        );
+    }
+    Ok(())
+}
@@ -1,5 +1,7 @@
 pub mod analysis;
+pub mod devices;
 pub mod intensity;
+pub mod record;
 
 pub use audio::analyzer::{AnalyzerParams, AnalyzerState};
 pub use audio::frequency_sensor::{
@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{analysis::Dimensions, AnalyzerState, AudioFeatures};
+
+/// Followed by the `Dimensions` the frames were analyzed with
+const MAGIC: &[u8; 8] = b"VUZREC02";
+const FLUSH_INTERVAL: usize = 64;
+
+/// A single recorded analyzer frame
+#[derive(Deserialize)]
+pub struct Frame {
+    /// time since the start of the recording
+    pub time: Duration,
+    pub features: AudioFeatures,
+    pub state: Option<AnalyzerState>,
+}
+
+// Serializes identically to `Frame` without cloning the features
+#[derive(Serialize)]
+struct FrameRef<'a> {
+    time: Duration,
+    features: &'a AudioFeatures,
+    state: Option<&'a AnalyzerState>,
+}
+
+/// Writes timestamped analyzer frames to a bincode stream
+pub struct Recorder {
+    out: BufWriter<File>,
+    start: Instant,
+    with_state: bool,
+    frames: usize,
+}
+
+impl Recorder {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        with_state: bool,
+        dimensions: Dimensions,
+    ) -> Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        bincode::serialize_into(&mut out, &dimensions)?;
+        Ok(Self {
+            out,
+            start: Instant::now(),
+            with_state,
+            frames: 0,
+        })
+    }
+
+    /// Whether `AnalyzerState` should be passed along with each frame
+    pub fn with_state(&self) -> bool {
+        self.with_state
+    }
+
+    pub fn write(&mut self, features: &AudioFeatures, state: Option<&AnalyzerState>) -> Result<()> {
+        let frame = FrameRef {
+            time: self.start.elapsed(),
+            features,
+            state,
+        };
+        bincode::serialize_into(&mut self.out, &frame)?;
+
+        self.frames += 1;
+        if self.frames % FLUSH_INTERVAL == 0 {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads frames back from a file written by `Recorder`
+pub struct Player {
+    input: BufReader<File>,
+    dimensions: Dimensions,
+}
+
+impl Player {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a vuzic recording");
+        }
+        let dimensions = bincode::deserialize_from(&mut input)?;
+        Ok(Self { input, dimensions })
+    }
+
+    /// Dimensions of the analyzer the recording was made with
+    pub fn dimensions(&self) -> Dimensions {
+        self.dimensions
+    }
+
+    /// Returns the next frame, or `None` at the end of the recording
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        match bincode::deserialize_from(&mut self.input) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref io)
+                    if io.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    Ok(None)
+                }
+                _ => Err(e.into()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Player, Recorder};
+    use crate::audiosys::analysis::Dimensions;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("vuzic-{}-{}.rec", name, std::process::id()))
+    }
+
+    #[test]
+    pub fn round_trip() {
+        let path = temp_path("round-trip");
+        let dimensions = Dimensions {
+            bins: 8,
+            length: 4,
+            ..Default::default()
+        };
+        {
+            let mut recorder = Recorder::create(&path, false, dimensions).unwrap();
+            let features = dimensions.default_features();
+            for _ in 0..3 {
+                recorder.write(&features, None).unwrap();
+            }
+        }
+
+        let mut player = Player::open(&path).unwrap();
+        assert_eq!(player.dimensions(), dimensions);
+        let mut last = Duration::default();
+        for _ in 0..3 {
+            let frame = player.next_frame().unwrap().unwrap();
+            assert_eq!(frame.features.get_size(), (8, 4));
+            assert!(frame.state.is_none());
+            assert!(frame.time >= last);
+            last = frame.time;
+        }
+        assert!(player.next_frame().unwrap().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    pub fn bad_magic() {
+        let path = temp_path("bad-magic");
+        std::fs::write(&path, b"NOTAREC!").unwrap();
+        let e = Player::open(&path).err().unwrap();
+        assert_eq!(e.to_string(), "not a vuzic recording");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
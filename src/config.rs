@@ -1,34 +1,172 @@
+use std::path::PathBuf;
+
+use panel_driver::Options as LedPanelOptions;
+use patch::Patch;
 use serde::{Deserialize, Serialize};
 
-use crate::audiosys::AnalyzerParams;
+use crate::audiosys::{analysis::Dimensions, AnalyzerParams};
+use crate::latency::SyncParams;
+use crate::validate::{Validate, Validator};
+use crate::visualizer::layers::CompositorParams;
+use crate::visualizer::modulation::Modulations;
+use crate::visualizer::palette::PaletteParams;
+use crate::visualizer::strips::StripOptions;
 use crate::visualizer::Params as RenderParams;
-#[cfg(feature = "ledpanel")]
-use crate::visualizer::ledpanel::Options as LedPanelOptions;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Where an led output gets its frames from
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PanelSource {
+    /// the scene compositor on the cpu
+    Cpu,
+    /// frames read back from the warp grid on the gpu
+    Gpu,
+}
+
+/// Renderers started by the app, the config sections of the others are ignored.
+/// These are only read at startup.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub(crate) struct Renderers {
+    /// draw the warp grid in a window
+    pub window: bool,
+    /// amethyst display config of the window, amethyst's defaults if unset
+    pub display_config: Option<PathBuf>,
+    /// drive the led panel
+    pub panel: Option<PanelSource>,
+    /// drive the apa102 strips
+    pub strips: Option<PanelSource>,
+    /// write the frames read back from the gpu to this file, see `RecordFrames`
+    pub record_frames: Option<PathBuf>,
+}
+
+impl Default for Renderers {
+    /// Everything this build supports, with the gpu feeding the led outputs if it is there
+    fn default() -> Self {
+        let gpu = cfg!(feature = "gpu");
+        let source = |output: bool| match (output, gpu) {
+            (false, _) => None,
+            (true, false) => Some(PanelSource::Cpu),
+            (true, true) => Some(PanelSource::Gpu),
+        };
+        Self {
+            window: gpu,
+            display_config: None,
+            panel: source(cfg!(feature = "ledpanel")),
+            strips: source(cfg!(feature = "strips")),
+            record_frames: None,
+        }
+    }
+}
+
+impl Renderers {
+    /// Whether the amethyst rendering bundle is needed
+    pub fn uses_gpu(&self) -> bool {
+        self.window || self.reads_back()
+    }
+
+    /// Whether anything consumes frames read back from the gpu
+    pub fn reads_back(&self) -> bool {
+        self.panel == Some(PanelSource::Gpu)
+            || self.strips == Some(PanelSource::Gpu)
+            || self.record_frames.is_some()
+    }
+}
+
+impl Validate for Renderers {
+    /// Every selected renderer has to be compiled into this build
+    fn validate(&self, v: &mut Validator) {
+        let (gpu, ledpanel, strips) = (
+            cfg!(feature = "gpu"),
+            cfg!(feature = "ledpanel"),
+            cfg!(feature = "strips"),
+        );
+        v.check("window", !self.window || gpu, "needs the gpu feature");
+        v.check(
+            "panel",
+            self.panel.is_none() || ledpanel,
+            "needs the ledpanel feature",
+        );
+        v.check(
+            "panel",
+            self.panel != Some(PanelSource::Gpu) || gpu,
+            "gpu needs the gpu feature",
+        );
+        v.check(
+            "strips",
+            self.strips.is_none() || strips,
+            "needs the strips feature",
+        );
+        v.check(
+            "strips",
+            self.strips != Some(PanelSource::Gpu) || gpu,
+            "gpu needs the gpu feature",
+        );
+        v.check(
+            "record_frames",
+            self.record_frames.is_none() || gpu,
+            "needs the gpu feature",
+        );
+    }
+}
+
+/// Changes sent to the api are an `OptionalConfig`, which only holds the sections and
+/// fields that change
+#[derive(Serialize, Deserialize, Clone, Debug, Patch)]
+#[patch(name = "OptionalConfig", derive(Serialize, Deserialize, Clone, Debug))]
 pub(crate) struct Config {
+    /// only read at startup
+    #[serde(default)]
+    #[patch(skip)]
+    pub renderers: Renderers,
     pub audio: AnalyzerParams,
+    #[serde(default)]
+    pub dimensions: Dimensions,
+    #[patch(nested)]
     pub render: RenderParams,
-    #[cfg(feature = "ledpanel")]
+    #[serde(default)]
+    pub compositor: CompositorParams,
+    #[serde(default)]
+    pub modulation: Modulations,
+    #[serde(default)]
+    pub palette: PaletteParams,
+    #[serde(default)]
+    pub sync: SyncParams,
+    #[serde(default)]
+    #[patch(nested)]
     pub panel: LedPanelOptions,
+    #[serde(default)]
+    pub strips: StripOptions,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
+            renderers: Default::default(),
             audio: Default::default(),
+            dimensions: Default::default(),
             render: Default::default(),
-            #[cfg(feature = "ledpanel")]
+            compositor: Default::default(),
+            modulation: Default::default(),
+            palette: Default::default(),
+            sync: Default::default(),
             panel: Default::default(),
+            strips: Default::default(),
         }
     }
 }
 
-// TODO: make a derive macro
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub(crate) struct OptionalConfig {
-    pub audio: Option<AnalyzerParams>,
-    pub render: Option<RenderParams>,
-    #[cfg(feature = "ledpanel")]
-    pub panel: Option<LedPanelOptions>,
+impl Validate for Config {
+    fn validate(&self, v: &mut Validator) {
+        v.nested("renderers", &self.renderers);
+        v.nested("audio", &self.audio);
+        v.nested("dimensions", &self.dimensions);
+        v.nested("render", &self.render);
+        v.nested("compositor", &self.compositor);
+        v.nested("modulation", &self.modulation);
+        v.nested("palette", &self.palette);
+        v.nested("sync", &self.sync);
+        v.nested("panel", &self.panel);
+        v.nested("strips", &self.strips);
+    }
 }
@@ -0,0 +1,254 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::validate::{Validate, Validator};
+
+lazy_static! {
+    pub static ref LATENCY: Latency = Latency::new();
+}
+
+/// Upper bound on `SyncParams::visual_delay_ms`, frames are queued for this long at most
+pub const MAX_VISUAL_DELAY_MS: f32 = 2000.;
+
+/// Timestamps carried through the pipeline along with each `AudioFeatures` frame
+#[derive(Clone, Copy, Debug)]
+pub struct FrameTiming {
+    /// when the last audio block of the frame was captured
+    pub captured: Instant,
+    /// when the analyzer produced the frame
+    pub analyzed: Instant,
+    /// when the frame was picked up by the game loop
+    pub received: Instant,
+}
+
+impl FrameTiming {
+    pub fn new(captured: Instant) -> Self {
+        let now = Instant::now();
+        Self {
+            captured,
+            analyzed: now,
+            received: now,
+        }
+    }
+}
+
+impl Default for FrameTiming {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+/// Audio/visual sync settings
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+pub struct SyncParams {
+    /// Hold frames until this long after capture so visuals line up with distant speakers.
+    /// Visuals can't be shown before they are analyzed and vuzic doesn't play the audio,
+    /// so they can't be advanced. For values below the measured pipeline latency,
+    /// including negative ones, the latency report gives the delay to add to the audio
+    /// output instead.
+    pub visual_delay_ms: f32,
+}
+
+impl Default for SyncParams {
+    fn default() -> Self {
+        Self {
+            visual_delay_ms: 0.,
+        }
+    }
+}
+
+impl SyncParams {
+    /// The part of `visual_delay_ms` which can be applied by holding frames
+    pub fn visual_delay(&self) -> Duration {
+        Duration::from_secs_f32(self.visual_delay_ms.clamp(0., MAX_VISUAL_DELAY_MS) / 1000.)
+    }
+}
+
+impl Validate for SyncParams {
+    fn validate(&self, v: &mut Validator) {
+        // negative delays are reported rather than applied, see `visual_delay_ms`
+        let delay = self.visual_delay_ms;
+        v.check(
+            "visual_delay_ms",
+            delay.is_finite() && delay <= MAX_VISUAL_DELAY_MS,
+            format!("{} must be at most {}", delay, MAX_VISUAL_DELAY_MS),
+        );
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Stage {
+    /// capture to analyzed frame
+    Analysis,
+    /// analyzed frame to pickup by the game loop
+    Queue,
+    /// time spent rendering a frame on the cpu, the gpu renderer isn't timed
+    Render,
+    /// time spent handing a frame to the output device
+    Output,
+    /// capture to output, including any visual delay
+    Total,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [
+        Stage::Analysis,
+        Stage::Queue,
+        Stage::Render,
+        Stage::Output,
+        Stage::Total,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Analysis => "analysis",
+            Stage::Queue => "queue",
+            Stage::Render => "render",
+            Stage::Output => "output",
+            Stage::Total => "total",
+        }
+    }
+}
+
+/// Bucket upper bounds in milliseconds, the last bucket is unbounded
+const BUCKETS_MS: [f32; 12] = [0.25, 0.5, 1., 2., 4., 8., 16., 32., 64., 128., 256., 512.];
+
+#[derive(Clone, Default)]
+struct Histogram {
+    counts: [u64; BUCKETS_MS.len() + 1],
+    count: u64,
+    sum_ms: f64,
+    max_ms: f32,
+}
+
+impl Histogram {
+    fn record(&mut self, d: Duration) {
+        let ms = d.as_secs_f32() * 1000.;
+        let i = BUCKETS_MS
+            .iter()
+            .position(|&b| ms <= b)
+            .unwrap_or(BUCKETS_MS.len());
+        self.counts[i] += 1;
+        self.count += 1;
+        self.sum_ms += ms as f64;
+        self.max_ms = self.max_ms.max(ms);
+    }
+
+    fn report(&self, stage: Stage) -> HistogramReport {
+        HistogramReport {
+            stage: stage.name(),
+            count: self.count,
+            mean_ms: if self.count > 0 {
+                (self.sum_ms / self.count as f64) as f32
+            } else {
+                0.
+            },
+            sum_ms: self.sum_ms,
+            max_ms: self.max_ms,
+            buckets: BUCKETS_MS
+                .iter()
+                .map(|&b| Some(b))
+                .chain(std::iter::once(None))
+                .zip(self.counts.iter().copied())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HistogramReport {
+    pub stage: &'static str,
+    pub count: u64,
+    pub mean_ms: f32,
+    pub sum_ms: f64,
+    pub max_ms: f32,
+    /// `(upper bound in ms, count)`, the last bucket has no upper bound
+    pub buckets: Vec<(Option<f32>, u64)>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct AudioFormat {
+    pub sample_rate: usize,
+    pub sample_block_size: usize,
+    pub block_duration_ms: f32,
+}
+
+/// How well the last frame shown matched `SyncParams::visual_delay_ms`
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SyncReport {
+    pub visual_delay_ms: f32,
+    /// time from capture until the frame was handed to the renderers
+    pub actual_delay_ms: f32,
+    /// how much the audio output has to be delayed for the visuals to line up, when the
+    /// requested delay is shorter than the pipeline latency
+    pub audio_delay_ms: f32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LatencyReport {
+    pub audio: AudioFormat,
+    pub sync: SyncReport,
+    pub stages: Vec<HistogramReport>,
+}
+
+/// Latency histograms for each stage of the pipeline
+pub struct Latency {
+    stages: Mutex<Vec<Histogram>>,
+    audio: Mutex<AudioFormat>,
+    sync: Mutex<SyncReport>,
+}
+
+impl Latency {
+    fn new() -> Self {
+        Self {
+            stages: Mutex::new(vec![Histogram::default(); Stage::ALL.len()]),
+            audio: Mutex::new(AudioFormat::default()),
+            sync: Mutex::new(SyncReport::default()),
+        }
+    }
+
+    pub fn record(&self, stage: Stage, d: Duration) {
+        self.stages.lock().unwrap()[stage as usize].record(d);
+    }
+
+    pub fn set_audio_format(&self, sample_rate: usize, sample_block_size: usize) {
+        *self.audio.lock().unwrap() = AudioFormat {
+            sample_rate,
+            sample_block_size,
+            block_duration_ms: 1000. * sample_block_size as f32 / sample_rate as f32,
+        };
+    }
+
+    /// Record that a frame was handed to the renderers `actual` after capture
+    pub fn record_sync(&self, sync: &SyncParams, actual: Duration) {
+        let actual_ms = actual.as_secs_f32() * 1000.;
+        *self.sync.lock().unwrap() = SyncReport {
+            visual_delay_ms: sync.visual_delay_ms,
+            actual_delay_ms: actual_ms,
+            audio_delay_ms: (actual_ms - sync.visual_delay_ms).max(0.),
+        };
+    }
+
+    pub fn report(&self) -> LatencyReport {
+        let stages = self.stages.lock().unwrap();
+        LatencyReport {
+            audio: self.audio.lock().unwrap().clone(),
+            sync: self.sync.lock().unwrap().clone(),
+            stages: Stage::ALL
+                .iter()
+                .map(|&s| stages[s as usize].report(s))
+                .collect(),
+        }
+    }
+
+    /// Clear all histograms
+    pub fn reset(&self) {
+        for h in self.stages.lock().unwrap().iter_mut() {
+            *h = Histogram::default();
+        }
+        *self.sync.lock().unwrap() = SyncReport::default();
+    }
+}
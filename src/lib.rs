@@ -0,0 +1,7 @@
+//! Rendering and instrumentation shared by the visualizer binary and the benchmarks
+
+pub mod latency;
+pub mod metrics;
+pub mod status;
+pub mod validate;
+pub mod visualizer;
@@ -1,14 +1,15 @@
 use actix::*;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::Clap;
 use log::info;
 
 mod api;
 mod audiosys;
 mod config;
-mod visualizer;
 use api::ApiServer;
+use vuzic::{latency, metrics, status, validate, visualizer};
 use config::Config;
+use vuzic::validate::validate;
 
 /// Vuzic Audio Visualizer
 #[derive(Clap)]
@@ -30,6 +31,7 @@ struct Opts {
 enum Command {
     Init,
     Run(audiosys::analysis::Opts),
+    Devices(audiosys::devices::Opts),
 }
 
 fn get_config(opts: &Opts) -> Result<Config> {
@@ -45,7 +47,8 @@ fn get_config(opts: &Opts) -> Result<Config> {
     }
 
     let config = match std::fs::File::open(config_path.clone()) {
-        Ok(f) => serde_yaml::from_reader(f)?,
+        Ok(f) => serde_yaml::from_reader(f)
+            .with_context(|| format!("failed to parse {}", config_path.display()))?,
         Err(_) => {
             let config = Config::default();
             if let Command::Init = opts.cmd {
@@ -61,6 +64,7 @@ fn get_config(opts: &Opts) -> Result<Config> {
         info!("{:?}", config)
     }
 
+    validate(&config).with_context(|| format!("invalid config {}", config_path.display()))?;
     Ok(config)
 }
 
@@ -87,40 +91,71 @@ fn setup_logging(verbose: i32) {
 mod app;
 use app::App;
 use audiosys::analysis::AudioAnalysis;
+use audiosys::record::Player;
 
-fn main() {
+fn main() -> Result<()> {
     let opts = Opts::parse();
 
     setup_logging(opts.verbose);
 
-    let config = get_config(&opts).expect("failed to get config");
+    let mut config = get_config(&opts)?;
 
     let verbose = opts.verbose;
     match opts.cmd {
         Command::Init => (),
+        Command::Devices(devices_opts) => {
+            audiosys::devices::print_devices(&devices_opts).context("failed to list devices")?
+        }
         Command::Run(audio_opts) => {
+            config.dimensions = audio_opts.dimensions(config.dimensions);
+            if let Some(path) = &audio_opts.replay {
+                // the features of a replay have the shape they were recorded with
+                let player = Player::open(path)
+                    .with_context(|| format!("failed to open recording {}", path))?;
+                let recorded = player.dimensions();
+                if recorded != config.dimensions {
+                    info!("using the dimensions of {}: {:?}", path, recorded);
+                    config.dimensions = recorded;
+                }
+            }
+            // the dimensions may have come from the command line or the recording
+            validate(&config).context("invalid dimensions")?;
+            let dimensions = config.dimensions;
+
             let mut sys = System::new("system");
             sys.block_on(async move {
                 let server = ApiServer::create(|ctx| {
                     let server = ctx.address();
 
-                    let (audio, audio_sys) = AudioAnalysis::new(
-                        audio_opts.clone(),
-                        Default::default(),
-                        server.recipient(),
-                        verbose,
-                    );
+                    let (audio, audio_sys) = if audio_opts.replay.is_some() {
+                        AudioAnalysis::replay(
+                            audio_opts.clone(),
+                            dimensions,
+                            Default::default(),
+                            server.recipient(),
+                            verbose,
+                        )
+                    } else {
+                        AudioAnalysis::new(
+                            audio_opts.clone(),
+                            dimensions,
+                            Default::default(),
+                            server.recipient(),
+                            verbose,
+                        )
+                    };
 
                     let audio_addr = audio.start();
 
-                    let app = App::new(config, audio_opts, audio_sys, verbose).start();
+                    let app = App::new(config, audio_sys, audio_addr.clone(), verbose).start();
                     ApiServer::new(app, audio_addr)
                 });
                 api::run("127.0.0.1", "8080", server).await
             })
-            .expect("failed to create actix system");
+            .context("api server error")?;
 
-            sys.run().expect("actix system runtime error");
+            sys.run().context("actix system runtime error")?;
         }
     }
+    Ok(())
 }
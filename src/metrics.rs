@@ -0,0 +1,185 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use crate::latency::LATENCY;
+
+lazy_static! {
+    pub static ref METRICS: Metrics = Metrics::default();
+}
+
+/// Counters and gauges exported in the prometheus text format on `/metrics`
+#[derive(Default)]
+pub struct Metrics {
+    pub analysis_frames: AtomicU64,
+    /// features dropped because the game loop hadn't picked up the previous frame
+    pub dropped_features: AtomicU64,
+    /// audio blocks waiting for the analyzer
+    pub audio_queue_depth: AtomicI64,
+    /// frames held back by the visual delay
+    pub delay_queue_depth: AtomicU64,
+    pub render_frames: AtomicU64,
+    render_micros: AtomicU64,
+    pub panel_frames: AtomicU64,
+    panel_fps: AtomicU32,
+    pub strip_frames: AtomicU64,
+    strip_fps: AtomicU32,
+    pub ws_sessions: AtomicU64,
+    pub ws_audio_subscribers: AtomicU64,
+}
+
+impl Metrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set(gauge: &AtomicU64, v: usize) {
+        gauge.store(v as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_render(&self, d: Duration) {
+        self.render_frames.fetch_add(1, Ordering::Relaxed);
+        self.render_micros
+            .fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_panel_fps(&self, fps: f32) {
+        self.panel_fps.store(fps.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_strip_fps(&self, fps: f32) {
+        self.strip_fps.store(fps.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Render all metrics in the prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let get = |v: &AtomicU64| v.load(Ordering::Relaxed);
+
+        metric(
+            &mut out,
+            "vuzic_analysis_frames_total",
+            "counter",
+            "Feature frames produced by the analyzer",
+            get(&self.analysis_frames),
+        );
+        metric(
+            &mut out,
+            "vuzic_dropped_feature_frames_total",
+            "counter",
+            "Feature frames dropped because the render loop was busy",
+            get(&self.dropped_features),
+        );
+        metric(
+            &mut out,
+            "vuzic_audio_queue_depth",
+            "gauge",
+            "Audio blocks waiting to be analyzed",
+            self.audio_queue_depth.load(Ordering::Relaxed),
+        );
+        metric(
+            &mut out,
+            "vuzic_delay_queue_depth",
+            "gauge",
+            "Feature frames held back by the visual delay",
+            get(&self.delay_queue_depth),
+        );
+        let _ = writeln!(
+            out,
+            "# HELP vuzic_render_seconds Time spent rendering frames on the cpu"
+        );
+        let _ = writeln!(out, "# TYPE vuzic_render_seconds summary");
+        let _ = writeln!(
+            out,
+            "vuzic_render_seconds_sum {}",
+            get(&self.render_micros) as f64 / 1e6
+        );
+        let _ = writeln!(
+            out,
+            "vuzic_render_seconds_count {}",
+            get(&self.render_frames)
+        );
+        metric(
+            &mut out,
+            "vuzic_panel_frames_total",
+            "counter",
+            "Frames displayed on the led panel",
+            get(&self.panel_frames),
+        );
+        metric(
+            &mut out,
+            "vuzic_panel_fps",
+            "gauge",
+            "Led panel frame rate",
+            f32::from_bits(self.panel_fps.load(Ordering::Relaxed)),
+        );
+        metric(
+            &mut out,
+            "vuzic_strip_frames_total",
+            "counter",
+            "Frames written to the apa102 strips",
+            get(&self.strip_frames),
+        );
+        metric(
+            &mut out,
+            "vuzic_strip_fps",
+            "gauge",
+            "Apa102 strip frame rate",
+            f32::from_bits(self.strip_fps.load(Ordering::Relaxed)),
+        );
+        metric(
+            &mut out,
+            "vuzic_ws_sessions",
+            "gauge",
+            "Connected websocket sessions",
+            get(&self.ws_sessions),
+        );
+        metric(
+            &mut out,
+            "vuzic_ws_audio_subscribers",
+            "gauge",
+            "Websocket sessions subscribed to audio features",
+            get(&self.ws_audio_subscribers),
+        );
+
+        let report = LATENCY.report();
+        let _ = writeln!(
+            out,
+            "# HELP vuzic_latency_seconds Latency of each pipeline stage"
+        );
+        let _ = writeln!(out, "# TYPE vuzic_latency_seconds histogram");
+        for stage in report.stages {
+            let mut cumulative = 0;
+            for (le, count) in stage.buckets {
+                cumulative += count;
+                let le = le.map_or("+Inf".to_string(), |ms| (ms / 1000.).to_string());
+                let _ = writeln!(
+                    out,
+                    "vuzic_latency_seconds_bucket{{stage=\"{}\",le=\"{}\"}} {}",
+                    stage.stage, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "vuzic_latency_seconds_sum{{stage=\"{}\"}} {}",
+                stage.stage,
+                stage.sum_ms / 1000.
+            );
+            let _ = writeln!(
+                out,
+                "vuzic_latency_seconds_count{{stage=\"{}\"}} {}",
+                stage.stage, stage.count
+            );
+        }
+
+        out
+    }
+}
+
+fn metric<T: std::fmt::Display>(out: &mut String, name: &str, kind: &str, help: &str, v: T) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, v);
+}
@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+lazy_static! {
+    pub static ref STATUS: Status = Status::new();
+}
+
+/// A critical stage which hasn't reported a frame for this long is considered dead
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    Audio,
+    Analysis,
+    GameLoop,
+    Panel,
+    Strips,
+    Recorder,
+    Api,
+}
+
+#[derive(Clone, Debug)]
+struct Stage {
+    alive: bool,
+    critical: bool,
+    frames: u64,
+    last_frame: Option<(Instant, SystemTime)>,
+    device: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StageReport {
+    pub alive: bool,
+    pub critical: bool,
+    pub stalled: bool,
+    pub frames: u64,
+    /// milliseconds since the unix epoch
+    pub last_frame: Option<u128>,
+    pub last_frame_age_ms: Option<u128>,
+    pub device: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StatusReport {
+    pub healthy: bool,
+    pub uptime_s: u64,
+    pub subsystems: BTreeMap<Subsystem, StageReport>,
+}
+
+/// Liveness of each stage of the pipeline
+pub struct Status {
+    started: Instant,
+    stages: Mutex<BTreeMap<Subsystem, Stage>>,
+}
+
+impl Status {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            stages: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Stage)>(&self, sub: Subsystem, f: F) {
+        let mut stages = self.stages.lock().unwrap();
+        let stage = stages.entry(sub).or_insert(Stage {
+            alive: false,
+            critical: false,
+            frames: 0,
+            last_frame: None,
+            device: None,
+            error: None,
+        });
+        f(stage)
+    }
+
+    /// Mark a stage as running. The process exits when a critical stage dies.
+    pub fn start(&self, sub: Subsystem, critical: bool) {
+        self.update(sub, |s| {
+            s.alive = true;
+            s.critical = critical;
+            s.error = None;
+        })
+    }
+
+    /// Returns a guard which marks the stage as stopped when the owning thread exits or panics
+    pub fn guard(&'static self, sub: Subsystem, critical: bool) -> StageGuard {
+        self.start(sub, critical);
+        StageGuard { status: self, sub }
+    }
+
+    pub fn heartbeat(&self, sub: Subsystem) {
+        self.update(sub, |s| {
+            s.frames += 1;
+            s.last_frame = Some((Instant::now(), SystemTime::now()));
+        })
+    }
+
+    pub fn set_device(&self, sub: Subsystem, device: Option<String>) {
+        self.update(sub, |s| s.device = device)
+    }
+
+    /// Record an error without marking the stage as dead
+    pub fn error(&self, sub: Subsystem, error: String) {
+        self.update(sub, |s| s.error = Some(error))
+    }
+
+    pub fn stopped(&self, sub: Subsystem, error: Option<String>) {
+        self.update(sub, |s| {
+            s.alive = false;
+            if error.is_some() {
+                s.error = error;
+            }
+        })
+    }
+
+    /// Returns the first critical stage which has died or stalled
+    pub fn failed_critical(&self) -> Option<(Subsystem, String)> {
+        self.report()
+            .subsystems
+            .into_iter()
+            .find(|(_, s)| s.critical && (!s.alive || s.stalled))
+            .map(|(sub, s)| {
+                let reason = s.error.unwrap_or_else(|| {
+                    if s.stalled {
+                        "stalled".to_string()
+                    } else {
+                        "stopped".to_string()
+                    }
+                });
+                (sub, reason)
+            })
+    }
+
+    pub fn report(&self) -> StatusReport {
+        let stages = self.stages.lock().unwrap();
+        let subsystems: BTreeMap<_, _> = stages
+            .iter()
+            .map(|(&sub, s)| {
+                let age = s.last_frame.map(|(i, _)| i.elapsed());
+                // stages only count as stalled once they've produced a frame
+                let stalled = s.alive && age.map_or(false, |a| a > STALL_TIMEOUT);
+                let report = StageReport {
+                    alive: s.alive,
+                    critical: s.critical,
+                    stalled,
+                    frames: s.frames,
+                    last_frame: s
+                        .last_frame
+                        .and_then(|(_, t)| t.duration_since(UNIX_EPOCH).ok())
+                        .map(|d| d.as_millis()),
+                    last_frame_age_ms: age.map(|a| a.as_millis()),
+                    device: s.device.clone(),
+                    error: s.error.clone(),
+                };
+                (sub, report)
+            })
+            .collect();
+        StatusReport {
+            healthy: subsystems.values().all(|s| s.alive && !s.stalled),
+            uptime_s: self.started.elapsed().as_secs(),
+            subsystems,
+        }
+    }
+}
+
+pub struct StageGuard {
+    status: &'static Status,
+    sub: Subsystem,
+}
+
+impl Drop for StageGuard {
+    fn drop(&mut self) {
+        let error = if std::thread::panicking() {
+            Some("thread panicked".to_string())
+        } else {
+            None
+        };
+        self.status.stopped(self.sub, error);
+    }
+}
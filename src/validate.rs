@@ -0,0 +1,335 @@
+use std::fmt;
+
+use audio::analyzer::AnalyzerParams;
+use panel_driver::{ColorCorrection, Options as PanelOptions};
+
+/// Checks the values of a config section which deserialize fine but would break
+/// rendering or analysis, like a zero divisor or a NaN
+pub trait Validate {
+    /// Report every invalid field to `v`
+    fn validate(&self, v: &mut Validator);
+}
+
+/// Validate `value`, collecting every invalid field instead of stopping at the first
+pub fn validate<T: Validate + ?Sized>(value: &T) -> Result<(), Errors> {
+    let mut v = Validator::default();
+    value.validate(&mut v);
+    v.finish()
+}
+
+/// Invalid fields of a config, each with its path like `render.color_period`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Errors(pub Vec<String>);
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid config")?;
+        for e in self.0.iter() {
+            write!(f, "\n  {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Errors {}
+
+/// Collects errors along with the path of the field they were found in
+#[derive(Debug, Default)]
+pub struct Validator {
+    path: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl Validator {
+    /// Validate a nested section
+    pub fn nested<T: Validate + ?Sized>(&mut self, name: &str, value: &T) {
+        self.path.push(name.to_string());
+        value.validate(self);
+        self.path.pop();
+    }
+
+    /// Add an error for `name` unless `ok`
+    pub fn check(&mut self, name: &str, ok: bool, message: impl fmt::Display) {
+        if ok {
+            return;
+        }
+        let mut path = String::new();
+        for segment in self.path.iter().map(String::as_str).chain(Some(name)) {
+            // list indices like `[0]` follow their list directly
+            if !path.is_empty() && !segment.starts_with('[') {
+                path.push('.');
+            }
+            path.push_str(segment);
+        }
+        self.errors.push(format!("{}: {}", path, message));
+    }
+
+    pub fn finite(&mut self, name: &str, value: impl Into<f64>) {
+        let value = value.into();
+        self.check(
+            name,
+            value.is_finite(),
+            format!("{} is not a finite number", value),
+        );
+    }
+
+    pub fn positive(&mut self, name: &str, value: impl Into<f64>) {
+        let value = value.into();
+        self.check(
+            name,
+            value.is_finite() && value > 0.,
+            format!("{} must be greater than 0", value),
+        );
+    }
+
+    pub fn at_least(&mut self, name: &str, value: impl Into<f64>, min: f64) {
+        let value = value.into();
+        self.check(
+            name,
+            value.is_finite() && value >= min,
+            format!("{} must be at least {}", value, min),
+        );
+    }
+
+    pub fn between(&mut self, name: &str, value: impl Into<f64>, min: f64, max: f64) {
+        let value = value.into();
+        self.check(
+            name,
+            (min..=max).contains(&value),
+            format!("{} must be between {} and {}", value, min, max),
+        );
+    }
+
+    pub fn finish(self) -> Result<(), Errors> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Errors(self.errors))
+        }
+    }
+}
+
+impl Validate for AnalyzerParams {
+    fn validate(&self, v: &mut Validator) {
+        v.at_least("preemphasis", self.preemphasis, 0.);
+        v.finite("diff_gain", self.diff_gain);
+        v.finite("amp_scale", self.amp_scale);
+        v.finite("amp_offset", self.amp_offset);
+        v.between("sync", self.sync, 0., 1.);
+        v.between("drag", self.drag, 0., 1.);
+
+        let gc = &self.gain_control;
+        let filters = [
+            ("amp_filter", &self.amp_filter),
+            ("amp_feedback", &self.amp_feedback),
+            ("diff_filter", &self.diff_filter),
+            ("diff_feedback", &self.diff_feedback),
+            ("pos_scale_filter", &self.pos_scale_filter),
+            ("neg_scale_filter", &self.neg_scale_filter),
+            ("gain_control.filter_params", &gc.filter_params),
+        ];
+        for &(name, filter) in filters.iter() {
+            // the filters divide by tau
+            v.positive(&format!("{}.tau", name), filter.tau);
+            v.finite(&format!("{}.gain", name), filter.gain);
+        }
+
+        v.finite("gain_control.kp", gc.kp);
+        v.finite("gain_control.kd", gc.kd);
+        v.finite("gain_control.ki", gc.ki);
+        v.finite("gain_control.pre_gain", gc.pre_gain);
+    }
+}
+
+impl Validate for PanelOptions {
+    fn validate(&self, v: &mut Validator) {
+        let sizes = [
+            ("cols", self.cols),
+            ("rows", self.rows),
+            ("chain_length", self.chain_length),
+            ("parallel", self.parallel),
+        ];
+        for &(name, size) in sizes.iter() {
+            if let Some(size) = size {
+                v.at_least(name, size, 1.);
+            }
+        }
+        if let Some(bits) = self.pwm_dither_bits {
+            v.between("pwm_dither_bits", bits, 0., 2.);
+        }
+        if let Some(ns) = self.pwm_lsb_nanoseconds {
+            v.at_least("pwm_lsb_nanoseconds", ns, 1.);
+        }
+        if let Some(slowdown) = self.gpio_slowdown {
+            v.between("gpio_slowdown", slowdown, 0., 4.);
+        }
+        v.nested("color", &self.color);
+    }
+}
+
+impl Validate for ColorCorrection {
+    fn validate(&self, v: &mut Validator) {
+        let channels = self.gamma.iter().zip(self.white_point.iter());
+        for (c, (&gamma, &white)) in channels.enumerate() {
+            v.positive(&format!("gamma[{}]", c), gamma);
+            v.between(&format!("white_point[{}]", c), white, 0., 1.);
+        }
+        if let Some(k) = self.temperature {
+            v.between("temperature", k, 1000., 40000.);
+        }
+        v.between("max_brightness", self.max_brightness, 0., 1.);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use audio::analyzer::AnalyzerParams;
+    use panel_driver::Options as PanelOptions;
+    use serde_json::json;
+
+    use super::{Validate, Validator};
+    use crate::latency::SyncParams;
+    use crate::visualizer::{
+        layers::{CompositorParams, Layer},
+        modulation::{LfoShape, Modulation, Modulations, Source, Target},
+        palette::{Color, PaletteDef, PaletteParams, PaletteSource, Stop},
+        Params, SceneKind,
+    };
+
+    /// Errors of `value` validated as the config section `name`
+    fn errors<T: Validate>(name: &str, value: &T) -> Vec<String> {
+        let mut v = Validator::default();
+        v.nested(name, value);
+        v.finish().err().map_or_else(Vec::new, |e| e.0)
+    }
+
+    #[test]
+    pub fn valid_defaults() {
+        assert!(errors("render", &Params::default()).is_empty());
+        assert!(errors("audio", &AnalyzerParams::default()).is_empty());
+        assert!(errors("panel", &PanelOptions::default()).is_empty());
+        assert!(errors("palette", &PaletteParams::default()).is_empty());
+    }
+
+    #[test]
+    pub fn render_path() {
+        let mut render = serde_json::to_value(Params::default()).unwrap();
+        render["color_period"] = json!(0.);
+        render["blur"] = json!(-1.);
+        let render: Params = serde_json::from_value(render).unwrap();
+        assert_eq!(
+            errors("render", &render),
+            vec![
+                "render.color_period: 0 must be at least 1",
+                "render.blur: -1 must be at least 0",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn panel_path() {
+        let panel = PanelOptions {
+            cols: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            errors("panel", &panel),
+            vec!["panel.cols: 0 must be at least 1"]
+        );
+    }
+
+    #[test]
+    pub fn audio_path() {
+        let mut audio = AnalyzerParams::default();
+        audio.amp_filter.tau = 0.;
+        assert_eq!(
+            errors("audio", &audio),
+            vec!["audio.amp_filter.tau: 0 must be greater than 0"]
+        );
+    }
+
+    #[test]
+    pub fn list_paths() {
+        let lfo = Modulation {
+            target: Target::Blur,
+            source: Source::Lfo {
+                hz: -1.,
+                shape: LfoShape::Sine,
+            },
+            depth: f32::NAN,
+            offset: 0.,
+            smoothing_s: 0.,
+        };
+        let modulation = Modulations(vec![
+            Modulation {
+                source: Source::Loudness,
+                depth: 1.,
+                ..lfo
+            },
+            lfo,
+        ]);
+        assert_eq!(
+            errors("modulation", &modulation),
+            vec![
+                "modulation[1].depth: NaN is not a finite number",
+                "modulation[1].source.hz: -1 must be at least 0",
+            ]
+        );
+
+        let mut faded = Layer::new(SceneKind::Particles);
+        faded.opacity = 2.;
+        let compositor = CompositorParams {
+            sets: vec![vec![Layer::new(SceneKind::WarpGrid), faded]],
+            ..Default::default()
+        };
+        assert_eq!(
+            errors("compositor", &compositor),
+            vec!["compositor.sets[0][1].opacity: 2 must be between 0 and 1"]
+        );
+    }
+
+    #[test]
+    pub fn palette_path() {
+        let stop = |pos| Stop {
+            pos,
+            color: Color([255, 0, 0]),
+        };
+        let mut palettes = BTreeMap::new();
+        palettes.insert(
+            "warm".to_string(),
+            PaletteDef {
+                source: PaletteSource::Gradient(vec![stop(0.), stop(f32::NAN), stop(1.5)]),
+                gamma: 1.,
+            },
+        );
+        let palette = PaletteParams {
+            active: "cold".to_string(),
+            palettes,
+        };
+        assert_eq!(
+            errors("palette", &palette),
+            vec![
+                "palette.active: unknown palette \"cold\"",
+                "palette.palettes.warm.gradient[1].pos: NaN must be between 0 and 1",
+                "palette.palettes.warm.gradient[2].pos: 1.5 must be between 0 and 1",
+            ]
+        );
+    }
+
+    #[test]
+    pub fn sync_path() {
+        let sync = SyncParams {
+            visual_delay_ms: 5000.,
+        };
+        assert_eq!(
+            errors("sync", &sync),
+            vec!["sync.visual_delay_ms: 5000 must be at most 2000"]
+        );
+        let ahead = SyncParams {
+            visual_delay_ms: -50.,
+        };
+        assert!(errors("sync", &ahead).is_empty());
+    }
+}
@@ -1,86 +1,110 @@
 use std::sync::Mutex;
+use std::time::Instant;
 
-use image::{DynamicImage, ImageBuffer, Pixel, RgbImage, Rgba, RgbaImage};
+use audio::frequency_sensor::Features as AudioFeatures;
+use image::{RgbImage, Rgba};
 use lazy_static::lazy_static;
 
-use crate::audiosys::AudioFeatures;
-use super::Params;
-
+use super::{
+    palette::{self, Palette},
+    warp, Params, Splat, Symmetry,
+};
 
 pub struct Visualizer {
     verbose: i32,
     image: (u32, u32),
+    /// `[alpha, r, g, b]` of every bin in the history, column major
+    colors: Vec<Rgba<f32>>,
+    vt_warp: Vec<f32>,
+    hz_warp: Vec<f32>,
+    /// `[alpha, r, g, b]` summed over every sample landing on an output pixel
+    accum: Vec<Rgba<f32>>,
+    /// output rgb, blurred in place
+    rgb: Vec<[f32; 3]>,
+    scratch: Vec<[f32; 3]>,
+    /// reference for the kaleidoscope rotation
+    start: Instant,
 }
 
 lazy_static! {
     // static ref SIGMOID: Sigmoid = Sigmoid::new();
-    static ref CLUT: Clut = Clut::new();
     static ref COUNT: Mutex<usize> = Mutex::new(0);
 }
 
-type F32Image = ImageBuffer<Rgba<f32>, Vec<f32>>;
-
 impl Visualizer {
     pub fn new(w: u32, h: u32, verbose: i32) -> Self {
+        let size = (w * h) as usize;
         Self {
             verbose,
             image: (w, h),
+            colors: Vec::new(),
+            vt_warp: Vec::new(),
+            hz_warp: Vec::new(),
+            accum: vec![Rgba([0.; 4]); size],
+            rgb: vec![[0.; 3]; size],
+            scratch: vec![[0.; 3]; size],
+            start: Instant::now(),
         }
     }
 
-    pub fn render(&self, params: &Params, features: &AudioFeatures) -> RgbImage {
-        let (bins, length) = features.get_size();
+    pub fn render(&mut self, params: &Params, features: &AudioFeatures) -> RgbImage {
+        let (w, h) = self.image;
+        let mut out = RgbImage::new(w, h);
+        self.render_into(params, features, &mut out);
+        out
+    }
 
+    /// Render into `out`, which must be the size given to `new`. Once the buffers are
+    /// sized for the feature dimensions this doesn't allocate.
+    pub fn render_into(&mut self, params: &Params, features: &AudioFeatures, out: &mut RgbImage) {
+        let (bins, length) = features.get_size();
         let scales = features.get_scales();
         let energy = features.get_energy();
-        let diff = features.get_diff();
-
-        let mut color_buffer = F32Image::new(length as u32, bins as u32);
-
-        let mut vt_warp = vec![1.; length];
+        let palette = palette::current();
 
+        self.colors.resize(length * bins, Rgba([0.; 4]));
         for i in 0..length {
             let amp = features.get_amplitudes(i);
-            let mut s = 0.;
+            let colors = &mut self.colors[i * bins..(i + 1) * bins];
             for j in 0..bins {
                 let val = scales[j] * (amp[j] - 1.0);
-                color_buffer.put_pixel(
-                    i as u32,
-                    j as u32,
-                    self.get_hsv(params, val as f32, energy[j] as f32, i as f32),
-                );
-
-                s += amp[j];
+                colors[j] = get_hsv(params, &palette, val as f32, energy[j] as f32, i as f32);
             }
-            let warp = s as f32 / bins as f32;
-            vt_warp[i] = params.vt_warp.0 * warp + params.vt_warp.1;
         }
 
-        let mut hz_warp: Vec<f32> = diff
-            .iter()
-            .map(|&x| params.hz_warp.0 * x as f32 + params.hz_warp.1)
-            .collect();
-        for i in 1..hz_warp.len() - 1 {
-            hz_warp[i] = (hz_warp[i - 1] + hz_warp[i] + hz_warp[i + 1]) / 3.;
-        }
+        warp::vt_warp(params, features, &mut self.vt_warp);
+        warp::hz_warp(params, features, &mut self.hz_warp);
 
         let (w, h) = self.image;
-        let (wf, hf) = (w as f32, h as f32);
-        let (wo, ho) = (wf / 2., hf / 2.);
+        let (wu, hu) = (w as usize, h as usize);
         let (lf, bf) = (length as f32, bins as f32);
-        let mut image = F32Image::new(w, h);
+        let splat = Splatter::new(params.splat, wu, hu);
+        let mirror = Mirror::new(params.symmetry, w, h, self.start.elapsed().as_secs_f32());
+        self.accum.iter_mut().for_each(|px| *px = Rgba([0.; 4]));
         for i in 0..length {
             for j in 0..bins {
                 let p = Point(i as f32 / lf, j as f32 / bf);
-                let Point(x, y) = apply_warp(p, hz_warp[j], vt_warp[i]);
-
-                let cpx = color_buffer.get_pixel(i as u32, j as u32);
-                for (r, q) in &[(-1., -1.), (-1., 1.), (1., -1.), (1., 1.)] {
-                    let (x, y) = (wo + r * x * wo, ho + q * y * ho);
-                    let px =
-                        image.get_pixel_mut((x as u32).clamp(0, w - 1), (y as u32).clamp(0, h - 1));
-                    px.apply2(cpx, |a, b| a + b);
-                }
+                let Point(x, y) = apply_warp(p, self.hz_warp[j], self.vt_warp[i]);
+
+                let cpx = self.colors[i * bins + j];
+                let accum = &mut self.accum;
+                mirror.each(x, y, |px, py| splat.add(accum, px, py, cpx));
+            }
+        }
+
+        for (rgb, px) in self.rgb.iter_mut().zip(self.accum.iter()) {
+            let a = px[0];
+            *rgb = [
+                (px[1] * a).clamp(0., 1.),
+                (px[2] * a).clamp(0., 1.),
+                (px[3] * a).clamp(0., 1.),
+            ];
+        }
+
+        if params.blur > 0. {
+            for r in gaussian_boxes(params.blur) {
+                box_blur(&self.rgb, &mut self.scratch, hu, wu, wu, 1, r);
+                box_blur(&self.scratch, &mut self.rgb, wu, hu, 1, wu, r);
             }
         }
 
@@ -89,58 +113,253 @@ impl Visualizer {
             (x * 255.5).clamp(0., 255.5) as u8
         }
 
-        let out = RgbaImage::from_vec(
-            w,
-            h,
-            image
-                .pixels()
-                .flat_map(|px| {
-                    vec![
-                        to_u8(px[1] * px[0]),
-                        to_u8(px[2] * px[0]),
-                        to_u8(px[3] * px[0]),
-                        to_u8(px[0]),
-                    ]
-                    .into_iter()
-                })
-                .collect(),
-        )
-        .unwrap();
-
-        let out = if params.blur > 0. {
-            DynamicImage::ImageRgba8(out).blur(params.blur)
-        } else {
-            DynamicImage::ImageRgba8(out)
+        for (px, rgb) in out.pixels_mut().zip(self.rgb.iter()) {
+            px.0 = [to_u8(rgb[0]), to_u8(rgb[1]), to_u8(rgb[2])];
         }
-        .to_rgb8();
 
         let mut count = COUNT.lock().unwrap();
         *count += 1;
-
-        out
     }
+}
 
-    fn get_hsv(&self, params: &Params, val: f32, e: f32, phi: f32) -> Rgba<f32> {
+/// Most copies a kaleidoscope can make
+const MAX_FOLDS: usize = 32;
+
+/// Maps a warped sample in `[0, 1]^2` to every pixel position it is copied to
+struct Mirror {
+    symmetry: Symmetry,
+    center: (f32, f32),
+    /// cos and sin of the center angle of each kaleidoscope wedge
+    wedges: [(f32, f32); MAX_FOLDS],
+    folds: usize,
+}
+
+impl Mirror {
+    fn new(symmetry: Symmetry, w: u32, h: u32, t: f32) -> Self {
         use std::f32::consts::PI;
 
-        let vs = params.value_scale;
-        let ls = params.lightness_scale;
-        let als = params.alpha_scale;
-        let cs = params.color_cycle_rate;
-        let phi = 2.0 * PI * phi / params.color_period;
-
-        let hue = 0.5 * (cs * e + phi) / PI;
-        // let value = ls.0 * SIGMOID.f(vs.0 * val + vs.1) + ls.1;
-        let value = ls.0 * sigmoid_fast(vs.0 * val + vs.1) + ls.1;
-        // let alpha = params.max_alpha * SIGMOID.f(als.0 * val + als.1);
-        let alpha = params.max_alpha * sigmoid_fast(als.0 * val + als.1);
-
-        // if *COUNT.lock().unwrap() % 256 == 0 {
-        //     println!("hue: {}, value: {}", hue, value);
-        // }
-        let color = CLUT.lookup(hue, value);
-        Rgba([alpha, color.0, color.1, color.2])
+        let mut wedges = [(0., 0.); MAX_FOLDS];
+        let mut folds = 0;
+        if let Symmetry::Kaleidoscope {
+            folds: n,
+            rotation_hz,
+        } = symmetry
+        {
+            folds = (n as usize).clamp(1, MAX_FOLDS);
+            let rotation = 2. * PI * (rotation_hz * t).fract();
+            for (k, wedge) in wedges.iter_mut().take(folds).enumerate() {
+                let (s, c) = (rotation + 2. * PI * k as f32 / folds as f32).sin_cos();
+                *wedge = (c, s);
+            }
+        }
+        Self {
+            symmetry,
+            center: (w as f32 / 2., h as f32 / 2.),
+            wedges,
+            folds,
+        }
     }
+
+    /// Call `f` with the pixel coordinates of every copy of `(x, y)`. `x` is the distance
+    /// along the time axis and `y` along the frequency axis.
+    #[inline]
+    fn each(&self, x: f32, y: f32, mut f: impl FnMut(f32, f32)) {
+        let (wo, ho) = self.center;
+        match self.symmetry {
+            Symmetry::None => f(2. * x * wo, 2. * y * ho),
+            Symmetry::Horizontal => {
+                for &r in &[-1., 1.] {
+                    f(wo + r * x * wo, 2. * y * ho);
+                }
+            }
+            Symmetry::Vertical => {
+                for &q in &[-1., 1.] {
+                    f(2. * x * wo, ho + q * y * ho);
+                }
+            }
+            Symmetry::Quad => {
+                // the four quadrants are mirror images of each other
+                for &(r, q) in &[(-1., -1.), (-1., 1.), (1., -1.), (1., 1.)] {
+                    f(wo + r * x * wo, ho + q * y * ho);
+                }
+            }
+            Symmetry::Kaleidoscope { .. } => {
+                // time runs outwards from the center and frequency across each wedge,
+                // mirrored about the wedge center so neighbouring wedges meet seamlessly
+                let half = std::f32::consts::PI / self.folds as f32;
+                let (sa, ca) = (y * half).sin_cos();
+                for &(c, s) in &self.wedges[..self.folds] {
+                    for &m in &[-1., 1.] {
+                        let (cos, sin) = (c * ca - m * s * sa, s * ca + m * c * sa);
+                        f(wo + x * cos * wo, ho + x * sin * ho);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Largest gaussian splat radius in pixels, the kernel is truncated at 3 sigma
+const MAX_SPLAT_RADIUS: usize = 8;
+
+/// Spreads samples over the pixels around their position according to a `Splat` kernel
+struct Splatter {
+    kernel: Splat,
+    size: (usize, usize),
+    /// gaussian radius and 1 / (2 sigma^2)
+    radius: usize,
+    falloff: f32,
+}
+
+impl Splatter {
+    fn new(kernel: Splat, w: usize, h: usize) -> Self {
+        let (radius, falloff) = match kernel {
+            Splat::Gaussian { sigma } => {
+                let sigma = sigma.max(0.1);
+                let radius = ((3. * sigma).ceil() as usize).min(MAX_SPLAT_RADIUS);
+                (radius, 1. / (2. * sigma * sigma))
+            }
+            _ => (0, 0.),
+        };
+        Self {
+            kernel,
+            size: (w, h),
+            radius,
+            falloff,
+        }
+    }
+
+    /// Add `c` at pixel coordinates `(x, y)`, where pixel `i` covers `[i, i + 1)`
+    #[inline]
+    fn add(&self, accum: &mut [Rgba<f32>], x: f32, y: f32, c: Rgba<f32>) {
+        let (w, h) = self.size;
+        let mut put = |px: isize, py: isize, weight: f32| {
+            if px >= 0 && py >= 0 && (px as usize) < w && (py as usize) < h {
+                let acc = &mut accum[py as usize * w + px as usize];
+                for (a, b) in acc.0.iter_mut().zip(c.0.iter()) {
+                    *a += weight * b;
+                }
+            }
+        };
+
+        match self.kernel {
+            Splat::Nearest => {
+                let px = x.clamp(0., (w - 1) as f32) as isize;
+                let py = y.clamp(0., (h - 1) as f32) as isize;
+                put(px, py, 1.);
+            }
+            Splat::Bilinear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (x0, y0) = (x.floor(), y.floor());
+                let (tx, ty) = (x - x0, y - y0);
+                let (x0, y0) = (x0 as isize, y0 as isize);
+                put(x0, y0, (1. - tx) * (1. - ty));
+                put(x0 + 1, y0, tx * (1. - ty));
+                put(x0, y0 + 1, (1. - tx) * ty);
+                put(x0 + 1, y0 + 1, tx * ty);
+            }
+            Splat::Gaussian { .. } => {
+                // the kernel is separable so only 2 * (2r + 1) exps are needed
+                let r = self.radius as isize;
+                let (cx, cy) = (x.floor() as isize, y.floor() as isize);
+                let mut wx = [0.; 2 * MAX_SPLAT_RADIUS + 1];
+                let mut wy = [0.; 2 * MAX_SPLAT_RADIUS + 1];
+                let (mut sx, mut sy) = (0., 0.);
+                for k in -r..=r {
+                    let dx = (cx + k) as f32 + 0.5 - x;
+                    let dy = (cy + k) as f32 + 0.5 - y;
+                    let (ex, ey) = (
+                        (-dx * dx * self.falloff).exp(),
+                        (-dy * dy * self.falloff).exp(),
+                    );
+                    wx[(k + r) as usize] = ex;
+                    wy[(k + r) as usize] = ey;
+                    sx += ex;
+                    sy += ey;
+                }
+                let norm = 1. / (sx * sy);
+                for ky in -r..=r {
+                    let wy = wy[(ky + r) as usize] * norm;
+                    for kx in -r..=r {
+                        put(cx + kx, cy + ky, wx[(kx + r) as usize] * wy);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Radii of three box blurs which together approximate a gaussian blur with `sigma`
+fn gaussian_boxes(sigma: f32) -> [usize; 3] {
+    const N: f32 = 3.;
+    let ideal = (12. * sigma * sigma / N + 1.).sqrt();
+    let mut wl = ideal.floor() as i32;
+    if wl % 2 == 0 {
+        wl -= 1;
+    }
+    let wlf = wl as f32;
+    let m = ((12. * sigma * sigma - N * wlf * wlf - 4. * N * wlf - 3. * N) / (-4. * wlf - 4.))
+        .round() as i32;
+    let mut radii = [0; 3];
+    for (i, r) in radii.iter_mut().enumerate() {
+        let size = if (i as i32) < m { wl } else { wl + 2 };
+        *r = (size.max(1) as usize - 1) / 2;
+    }
+    radii
+}
+
+/// One box blur pass of radius `r` over `lines` lines of `len` pixels, pixel `i` of line
+/// `l` is at `l * line_stride + i * stride`. Edges are clamped.
+fn box_blur(
+    src: &[[f32; 3]],
+    dst: &mut [[f32; 3]],
+    lines: usize,
+    len: usize,
+    line_stride: usize,
+    stride: usize,
+    r: usize,
+) {
+    let norm = 1. / (2 * r + 1) as f32;
+    let last = len - 1;
+    for l in 0..lines {
+        let at = |i: usize| l * line_stride + i.min(last) * stride;
+        let mut acc = [0.; 3];
+        for k in 0..=2 * r {
+            for (a, b) in acc.iter_mut().zip(src[at(k.saturating_sub(r))].iter()) {
+                *a += b;
+            }
+        }
+        for i in 0..len {
+            dst[at(i)] = [acc[0] * norm, acc[1] * norm, acc[2] * norm];
+            let (add, sub) = (src[at(i + r + 1)], src[at(i.saturating_sub(r))]);
+            for ((a, x), y) in acc.iter_mut().zip(add.iter()).zip(sub.iter()) {
+                *a += x - y;
+            }
+        }
+    }
+}
+
+/// Color of a sample as `[alpha, r, g, b]`, shared by all the cpu scenes
+pub(super) fn get_hsv(params: &Params, palette: &Palette, val: f32, e: f32, phi: f32) -> Rgba<f32> {
+    use std::f32::consts::PI;
+
+    let vs = params.value_scale;
+    let ls = params.lightness_scale;
+    let als = params.alpha_scale;
+    let cs = params.color_cycle_rate;
+    let phi = 2.0 * PI * phi / params.color_period;
+
+    let hue = 0.5 * (cs * e + phi) / PI;
+    // let value = ls.0 * SIGMOID.f(vs.0 * val + vs.1) + ls.1;
+    let value = ls.0 * sigmoid_fast(vs.0 * val + vs.1) + ls.1;
+    // let alpha = params.max_alpha * SIGMOID.f(als.0 * val + als.1);
+    let alpha = params.max_alpha * sigmoid_fast(als.0 * val + als.1);
+
+    // if *COUNT.lock().unwrap() % 256 == 0 {
+    //     println!("hue: {}, value: {}", hue, value);
+    // }
+    let color = palette.lookup(hue, value);
+    Rgba([alpha, color.0, color.1, color.2])
 }
 
 struct Point(f32, f32);
@@ -152,7 +371,7 @@ fn powf_fast(x: f32, y: f32) -> f32 {
 }
 
 #[inline]
-fn sigmoid_fast(x: f32) -> f32 {
+pub(super) fn sigmoid_fast(x: f32) -> f32 {
     use fast_math::exp_raw;
     1. / (1. + exp_raw(-x))
 }
@@ -204,37 +423,3 @@ fn apply_warp(xy: Point, w: f32, s: f32) -> Point {
 //         }
 //     }
 // }
-
-struct Clut {
-    lut: Vec<Vec<(f32, f32, f32)>>,
-}
-
-impl Clut {
-    const HUES: usize = 360;
-    const VALUES: usize = 256;
-
-    fn new() -> Self {
-        use hsluv::hsluv_to_rgb;
-        let mut lut = vec![vec![(0., 0., 0.); Self::VALUES]; Self::HUES];
-        for h in 0..Self::HUES {
-            for v in 0..Self::VALUES {
-                let c = hsluv_to_rgb((h as f64, 100., 100. * v as f64 / 256.));
-                let c = Self::gamma(c);
-                lut[h][v] = (c.0 as f32, c.1 as f32, c.2 as f32);
-            }
-        }
-        Self { lut }
-    }
-
-    fn gamma(c: (f64, f64, f64)) -> (f64, f64, f64) {
-        (c.0 * c.0, c.1 * c.1, c.2 * c.2)
-    }
-
-    /// lookup hue + value normalized to range [0,1)
-    fn lookup(&self, h: f32, v: f32) -> (f32, f32, f32) {
-        let hu = ((h * Self::HUES as f32) as isize % Self::HUES as isize).abs() as usize;
-        let vi = (v * Self::VALUES as f32) as isize;
-        let vu = isize::max(isize::min(vi, Self::VALUES as isize - 1), 0) as usize;
-        self.lut[hu][vu]
-    }
-}
@@ -0,0 +1,111 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use amethyst::{core::dispatcher::ThreadLocalSystem, ecs::*};
+use anyhow::Result;
+use image::RgbImage;
+
+use crate::status::{Subsystem, STATUS};
+
+/// Latest frame of a renderer, shared with the outputs that consume it. Each frame is
+/// numbered so consumers can tell when a new one arrives.
+#[derive(Clone, Default)]
+pub struct SharedFrame(Arc<Mutex<(u64, RgbImage)>>);
+
+impl SharedFrame {
+    /// Update the frame in place, resizing it to `width` × `height` first
+    pub fn publish(&self, width: u32, height: u32, f: impl FnOnce(&mut RgbImage)) {
+        let mut frame = self.0.lock().unwrap();
+        if frame.1.dimensions() != (width, height) {
+            frame.1 = RgbImage::new(width, height);
+        }
+        f(&mut frame.1);
+        frame.0 += 1;
+    }
+
+    /// A copy of the latest frame if it is newer than `seen`, which is then updated
+    pub fn newer(&self, seen: &mut u64) -> Option<RgbImage> {
+        self.with_newer(seen, |frame| frame.clone())
+    }
+
+    /// Call `f` with the latest frame if it is newer than `seen`, which is then updated
+    pub fn with_newer<T>(&self, seen: &mut u64, f: impl FnOnce(&RgbImage) -> T) -> Option<T> {
+        let frame = self.0.lock().unwrap();
+        if frame.0 == *seen {
+            return None;
+        }
+        *seen = frame.0;
+        Some(f(&frame.1))
+    }
+}
+
+/// Writes every new frame to a file as raw rgb24 without timestamps, e.g. to be played with
+/// `ffplay -f rawvideo -pixel_format rgb24 -video_size <w>x<h> <path>`
+pub struct RecordFrames {
+    frames: SharedFrame,
+    seen: u64,
+    /// unbuffered, every frame is a single large write
+    out: Option<File>,
+    /// size of the first frame, which every other frame has to match
+    size: Option<(u32, u32)>,
+}
+
+impl RecordFrames {
+    pub fn create<P: AsRef<Path>>(path: P, frames: SharedFrame) -> Result<Self> {
+        let out = File::create(&path)?;
+        STATUS.start(Subsystem::Recorder, false);
+        STATUS.set_device(
+            Subsystem::Recorder,
+            Some(path.as_ref().display().to_string()),
+        );
+        Ok(Self {
+            frames,
+            seen: 0,
+            out: Some(out),
+            size: None,
+        })
+    }
+
+    fn write(&mut self) -> Result<()> {
+        let (out, size) = match &mut self.out {
+            Some(out) => (out, &mut self.size),
+            None => return Ok(()),
+        };
+        let written = self.frames.with_newer(&mut self.seen, |frame| {
+            let dimensions = frame.dimensions();
+            match size {
+                Some(size) if *size != dimensions => {
+                    anyhow::bail!("frame size changed from {:?} to {:?}", size, dimensions)
+                }
+                Some(_) => (),
+                None => {
+                    log::info!("recording {}x{} frames", dimensions.0, dimensions.1);
+                    *size = Some(dimensions);
+                }
+            }
+            out.write_all(frame.as_raw())?;
+            Ok(())
+        });
+        if let Some(result) = written {
+            result?;
+            STATUS.heartbeat(Subsystem::Recorder);
+        }
+        Ok(())
+    }
+}
+
+impl ThreadLocalSystem<'static> for RecordFrames {
+    fn build(mut self) -> Box<dyn Runnable> {
+        Box::new(SystemBuilder::new("frame recorder").build(
+            move |_commands, _world, _resources, _query| {
+                if let Err(e) = self.write() {
+                    log::error!("failed to record frame, recording stopped: {}", e);
+                    STATUS.stopped(Subsystem::Recorder, Some(e.to_string()));
+                    self.out = None;
+                }
+            },
+        ))
+    }
+}
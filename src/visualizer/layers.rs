@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+
+use super::SceneKind;
+use crate::validate::{Validate, Validator};
+
+/// How a layer is combined with the layers below it
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    Add,
+    Screen,
+    Multiply,
+    Max,
+}
+
+impl Default for BlendMode {
+    fn default() -> Self {
+        BlendMode::Add
+    }
+}
+
+impl BlendMode {
+    /// Blend channel `b` onto `a` with `opacity`, all in [0, 1]
+    #[inline]
+    pub fn apply(&self, a: f32, b: f32, opacity: f32) -> f32 {
+        let blended = match self {
+            BlendMode::Add => (a + b).min(1.),
+            BlendMode::Screen => 1. - (1. - a) * (1. - b),
+            BlendMode::Multiply => a * b,
+            BlendMode::Max => a.max(b),
+        };
+        a + (blended - a) * opacity
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Layer {
+    pub scene: SceneKind,
+    #[serde(default = "full_opacity")]
+    pub opacity: f32,
+    #[serde(default)]
+    pub blend: BlendMode,
+    /// How much the overall loudness drives the opacity, 0 is static and 1 is fully reactive
+    #[serde(default)]
+    pub reactive: f32,
+}
+
+fn full_opacity() -> f32 {
+    1.
+}
+
+impl Layer {
+    pub fn new(scene: SceneKind) -> Self {
+        Self {
+            scene,
+            opacity: full_opacity(),
+            blend: Default::default(),
+            reactive: 0.,
+        }
+    }
+
+    /// Opacity after applying the audio reaction for a loudness in [0, 1]
+    pub fn opacity(&self, loudness: f32) -> f32 {
+        let r = self.reactive.clamp(0., 1.);
+        self.opacity * (1. - r + r * loudness)
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransitionKind {
+    Cut,
+    Crossfade,
+    /// the new set is revealed from left to right
+    Wipe,
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Transition {
+    pub kind: TransitionKind,
+    pub duration_s: f32,
+}
+
+impl Default for Transition {
+    fn default() -> Self {
+        Self {
+            kind: TransitionKind::Crossfade,
+            duration_s: 2.,
+        }
+    }
+}
+
+/// Layered scenes for the cpu renderer
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct CompositorParams {
+    /// Sets of layers drawn bottom to top. With no sets only `render.scene` is drawn.
+    #[serde(default)]
+    pub sets: Vec<Vec<Layer>>,
+    /// Seconds each set is shown before moving to the next one, 0 stays on the first set
+    #[serde(default)]
+    pub hold_s: f32,
+    /// Used when moving between sets and when the sets are changed
+    #[serde(default)]
+    pub transition: Transition,
+}
+
+impl Validate for Layer {
+    fn validate(&self, v: &mut Validator) {
+        v.between("opacity", self.opacity, 0., 1.);
+        v.between("reactive", self.reactive, 0., 1.);
+    }
+}
+
+impl Validate for CompositorParams {
+    fn validate(&self, v: &mut Validator) {
+        for (i, set) in self.sets.iter().enumerate() {
+            for (j, layer) in set.iter().enumerate() {
+                v.nested(&format!("sets[{}][{}]", i, j), layer);
+            }
+        }
+        v.at_least("hold_s", self.hold_s, 0.);
+        v.at_least("transition.duration_s", self.transition.duration_s, 0.);
+    }
+}
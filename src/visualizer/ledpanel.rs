@@ -1,35 +1,119 @@
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
 use amethyst::{core::dispatcher::ThreadLocalSystem, ecs::*};
+use audio::frequency_sensor::Features as AudioFeatures;
+use image::RgbImage;
 
-use super::{Params, cpurender::Visualizer};
-use crate::audiosys::AudioFeatures;
-use panel_driver::Panel;
+use super::{
+    frame::SharedFrame, layers::CompositorParams, modulation::ModulatedParams, scenes::Compositor,
+};
+use crate::latency::{FrameTiming, Stage, LATENCY};
+use crate::metrics::METRICS;
+use crate::status::{Subsystem, STATUS};
 pub use panel_driver::Options;
+use panel_driver::{ColorCorrection, Panel};
 
 pub struct RenderToPanel {
-    vis: Visualizer,
+    compositor: Compositor,
     panel: Panel,
+    color: ColorCorrection,
+    /// `(width, height)` of the whole chain
+    size: (u32, u32),
+    /// frames read back from the gpu renderer along with the last one sent
+    gpu: Option<(SharedFrame, u64)>,
+    /// capture time of the last audio frame whose latency was recorded
+    last_captured: Option<Instant>,
 }
 
 impl RenderToPanel {
     pub fn new(verbose: i32, options: Options) -> Self {
+        let (w, h) = options.frame_size();
+        let color = options.color.clone();
         let panel = Panel::new(verbose, options);
-        let vis = Visualizer::new(192, 64, verbose);
-        Self { vis, panel }
+        let compositor = Compositor::new(w, h, verbose);
+        STATUS.start(Subsystem::Panel, true);
+        STATUS.set_device(Subsystem::Panel, Some(format!("led panel {}x{}", w, h)));
+        Self {
+            compositor,
+            panel,
+            color,
+            size: (w, h),
+            gpu: None,
+            last_captured: None,
+        }
+    }
+
+    /// Send the frames rendered on the gpu instead of rendering on the cpu
+    pub fn with_gpu_frames(mut self, frames: SharedFrame) -> Self {
+        self.gpu = Some((frames, 0));
+        self
     }
 }
 
 impl ThreadLocalSystem<'static> for RenderToPanel {
-    fn build(self) -> Box<dyn Runnable> {
+    fn build(mut self) -> Box<dyn Runnable> {
         Box::new(
             SystemBuilder::new("led panel renderer")
-                .read_resource::<Params>()
+                .read_resource::<ModulatedParams>()
                 .read_resource::<AudioFeatures>()
-                .build(move |_commands, _world, (params, features), _query| {
-                    let image = self.vis.render(params, features);
-                    if let Err(e) = self.panel.send_frame(image) {
-                        log::error!("failed to send frame: {}", e);
-                    }
-                }),
+                .read_resource::<FrameTiming>()
+                .read_resource::<CompositorParams>()
+                .read_resource::<Options>()
+                .build(
+                    move |_commands, _world, (params, features, timing, comp, options), _query| {
+                        if options.color != self.color {
+                            self.color = options.color.clone();
+                            if let Err(e) = self.panel.set_color_correction(self.color.clone()) {
+                                log::error!("failed to update color correction: {}", e);
+                            }
+                        }
+
+                        let start = Instant::now();
+                        let image = match &mut self.gpu {
+                            Some((frames, seen)) => match frames.newer(seen) {
+                                Some(image) => image,
+                                None => return,
+                            },
+                            None => {
+                                // the panel's output thread takes ownership of every frame
+                                let mut image = RgbImage::new(self.size.0, self.size.1);
+                                self.compositor
+                                    .render(&params.0, comp, features, &mut image);
+                                image
+                            }
+                        };
+                        let rendered = Instant::now();
+                        // the panel redraws between audio frames, only the first draw of
+                        // a frame counts towards its latency
+                        let new_frame = self.last_captured != Some(timing.captured);
+                        self.last_captured = Some(timing.captured);
+                        // frames from the gpu were rendered elsewhere, timing them here would
+                        // only measure the copy
+                        if self.gpu.is_none() {
+                            if new_frame {
+                                LATENCY.record(Stage::Render, rendered - start);
+                            }
+                            METRICS.record_render(rendered - start);
+                        }
+
+                        match self.panel.send_frame(image) {
+                            Ok(()) => STATUS.heartbeat(Subsystem::Panel),
+                            Err(e) => {
+                                log::error!("failed to send frame: {}", e);
+                                STATUS.stopped(Subsystem::Panel, Some(e.to_string()));
+                            }
+                        }
+                        if new_frame {
+                            LATENCY.record(Stage::Output, rendered.elapsed());
+                            LATENCY.record(Stage::Total, timing.captured.elapsed());
+                        }
+                        METRICS
+                            .panel_frames
+                            .store(self.panel.frames(), Ordering::Relaxed);
+                        METRICS.set_panel_fps(self.panel.fps());
+                    },
+                ),
         )
     }
 }
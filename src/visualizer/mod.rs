@@ -1,3 +1,4 @@
+use patch::Patch;
 use serde::{Serialize, Deserialize};
 
 #[cfg(feature = "gpu")]
@@ -5,10 +6,24 @@ pub mod warpgrid;
 
 #[cfg(feature = "ledpanel")]
 pub mod ledpanel;
-#[cfg(feature = "ledpanel")]
+
 pub mod cpurender;
+pub mod frame;
+pub mod layers;
+pub mod modulation;
+pub mod palette;
+pub mod scenes;
+pub mod strips;
+pub mod update;
+pub mod warp;
+
+use crate::validate::{Validate, Validator};
+
+/// Shortest hue cycle, one bin per cycle
+pub const MIN_COLOR_PERIOD: f32 = 1.;
 
-#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Patch)]
+#[patch(derive(Serialize, Deserialize, Copy, Clone, Debug))]
 pub struct Params {
     value_scale: (f32, f32),
     lightness_scale: (f32, f32),
@@ -19,6 +34,99 @@ pub struct Params {
     blur: f32,
     hz_warp: (f32, f32),
     vt_warp: (f32, f32),
+    #[serde(default)]
+    scene: SceneKind,
+    #[serde(default)]
+    splat: Splat,
+    #[serde(default)]
+    symmetry: Symmetry,
+}
+
+/// Selects which cpu scene renders the output
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SceneKind {
+    WarpGrid,
+    SpectrumBars,
+    RadialSpectrum,
+    Spectrogram,
+    Particles,
+    /// the gpu warp grid's update pass rendered on the cpu, for machines without a gpu
+    ShaderReference,
+}
+
+impl Default for SceneKind {
+    fn default() -> Self {
+        SceneKind::WarpGrid
+    }
+}
+
+/// How the warp grid spreads each sample over the output pixels. Smoother kernels leave
+/// no holes between samples, so `blur` can be turned down or set to 0 to skip it.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(tag = "kernel", rename_all = "snake_case")]
+pub enum Splat {
+    /// add to the single pixel the sample lands on
+    Nearest,
+    /// split between the four nearest pixels
+    Bilinear,
+    /// gaussian footprint, truncated at 3 sigma or 8 pixels
+    Gaussian { sigma: f32 },
+}
+
+impl Default for Splat {
+    fn default() -> Self {
+        Splat::Nearest
+    }
+}
+
+/// How the warp grid copies the analysis around the image
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum Symmetry {
+    /// a single copy with the newest audio in the top left corner
+    None,
+    /// mirrored left to right
+    Horizontal,
+    /// mirrored top to bottom
+    Vertical,
+    /// mirrored into four quadrants
+    Quad,
+    /// `folds` mirrored wedges around the center, turning `rotation_hz` times a second
+    Kaleidoscope { folds: u32, rotation_hz: f32 },
+}
+
+impl Default for Symmetry {
+    fn default() -> Self {
+        Symmetry::Quad
+    }
+}
+
+impl Validate for Params {
+    fn validate(&self, v: &mut Validator) {
+        let pairs = [
+            ("value_scale", self.value_scale),
+            ("lightness_scale", self.lightness_scale),
+            ("alpha_scale", self.alpha_scale),
+            ("hz_warp", self.hz_warp),
+            ("vt_warp", self.vt_warp),
+        ];
+        for &(name, (scale, offset)) in pairs.iter() {
+            v.finite(&format!("{}[0]", name), scale);
+            v.finite(&format!("{}[1]", name), offset);
+        }
+        v.between("max_alpha", self.max_alpha, 0., 1.);
+        v.finite("color_cycle_rate", self.color_cycle_rate);
+        v.at_least("color_period", self.color_period, MIN_COLOR_PERIOD as f64);
+        v.at_least("blur", self.blur, 0.);
+        if let Splat::Gaussian { sigma } = self.splat {
+            v.positive("splat.sigma", sigma);
+        }
+        if let Symmetry::Kaleidoscope { folds, rotation_hz } = self.symmetry {
+            v.at_least("symmetry.folds", folds, 1.);
+            v.finite("symmetry.rotation_hz", rotation_hz);
+        }
+    }
 }
 
 impl Default for Params {
@@ -33,6 +141,9 @@ impl Default for Params {
             blur: 1.0,
             hz_warp: (1.0, 1.0),
             vt_warp: (1.0, 1.0),
+            scene: Default::default(),
+            splat: Default::default(),
+            symmetry: Default::default(),
         }
     }
 }
@@ -0,0 +1,402 @@
+use std::f32::consts::PI;
+use std::time::{Duration, Instant};
+
+use amethyst::{core::dispatcher::ThreadLocalSystem, ecs::*};
+use audio::frequency_sensor::Features as AudioFeatures;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    scenes::{bin_level, loudness},
+    Params, MIN_COLOR_PERIOD,
+};
+use crate::validate::{Validate, Validator};
+
+/// Onsets closer together than this are treated as one beat
+const MIN_BEAT_INTERVAL: Duration = Duration::from_millis(150);
+/// Beat interval assumed until two onsets have been seen, 120 bpm
+const DEFAULT_BEAT_INTERVAL_S: f32 = 0.5;
+
+/// Numeric render parameter that can be driven by a modulation
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+    #[serde(rename = "value_scale.0")]
+    ValueScaleGain,
+    #[serde(rename = "value_scale.1")]
+    ValueScaleOffset,
+    #[serde(rename = "lightness_scale.0")]
+    LightnessScaleGain,
+    #[serde(rename = "lightness_scale.1")]
+    LightnessScaleOffset,
+    #[serde(rename = "alpha_scale.0")]
+    AlphaScaleGain,
+    #[serde(rename = "alpha_scale.1")]
+    AlphaScaleOffset,
+    #[serde(rename = "max_alpha")]
+    MaxAlpha,
+    #[serde(rename = "color_cycle_rate")]
+    ColorCycleRate,
+    #[serde(rename = "color_period")]
+    ColorPeriod,
+    #[serde(rename = "blur")]
+    Blur,
+    #[serde(rename = "hz_warp.0")]
+    HzWarpGain,
+    #[serde(rename = "hz_warp.1")]
+    HzWarpOffset,
+    #[serde(rename = "vt_warp.0")]
+    VtWarpGain,
+    #[serde(rename = "vt_warp.1")]
+    VtWarpOffset,
+}
+
+impl Target {
+    fn get_mut(self, params: &mut Params) -> &mut f32 {
+        match self {
+            Target::ValueScaleGain => &mut params.value_scale.0,
+            Target::ValueScaleOffset => &mut params.value_scale.1,
+            Target::LightnessScaleGain => &mut params.lightness_scale.0,
+            Target::LightnessScaleOffset => &mut params.lightness_scale.1,
+            Target::AlphaScaleGain => &mut params.alpha_scale.0,
+            Target::AlphaScaleOffset => &mut params.alpha_scale.1,
+            Target::MaxAlpha => &mut params.max_alpha,
+            Target::ColorCycleRate => &mut params.color_cycle_rate,
+            Target::ColorPeriod => &mut params.color_period,
+            Target::Blur => &mut params.blur,
+            Target::HzWarpGain => &mut params.hz_warp.0,
+            Target::HzWarpOffset => &mut params.hz_warp.1,
+            Target::VtWarpGain => &mut params.vt_warp.0,
+            Target::VtWarpOffset => &mut params.vt_warp.1,
+        }
+    }
+
+    /// `value` limited to the range the renderers accept for the target
+    fn limit(self, value: f32) -> f32 {
+        match self {
+            Target::MaxAlpha => value.clamp(0., 1.),
+            Target::ColorPeriod => value.max(MIN_COLOR_PERIOD),
+            Target::Blur => value.max(0.),
+            _ => value,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LfoShape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+}
+
+impl LfoShape {
+    /// Value in [0, 1] at `phase` in [0, 1)
+    fn eval(&self, phase: f32) -> f32 {
+        match self {
+            LfoShape::Sine => 0.5 - 0.5 * (2. * PI * phase).cos(),
+            LfoShape::Triangle => 1. - (2. * phase - 1.).abs(),
+            LfoShape::Saw => phase,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+}
+
+/// Signal driving a modulation, every source produces values in [0, 1]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Source {
+    /// level of a single frequency bin, as the scenes see it
+    Band {
+        band: usize,
+    },
+    /// mean level over all bins, the same loudness that drives layer opacity
+    Loudness,
+    /// ramps from 0 to 1 between onsets detected in `band`
+    BeatPhase {
+        band: usize,
+    },
+    Lfo {
+        hz: f32,
+        shape: LfoShape,
+    },
+}
+
+/// `target = base + offset + depth * source`, where the source is low-pass filtered
+/// with a time constant of `smoothing_s`
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Modulation {
+    pub target: Target,
+    pub source: Source,
+    pub depth: f32,
+    #[serde(default)]
+    pub offset: f32,
+    #[serde(default)]
+    pub smoothing_s: f32,
+}
+
+/// The modulations declared in config, applied in order
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(transparent)]
+pub struct Modulations(pub Vec<Modulation>);
+
+/// Render parameters after modulation, this is what the renderers read
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ModulatedParams(pub Params);
+
+#[derive(Clone, Debug)]
+struct BeatTracker {
+    last: Option<Instant>,
+    interval_s: f32,
+    above: bool,
+}
+
+impl BeatTracker {
+    fn new() -> Self {
+        Self {
+            last: None,
+            interval_s: DEFAULT_BEAT_INTERVAL_S,
+            above: false,
+        }
+    }
+
+    fn update(&mut self, now: Instant, onset: bool) -> f32 {
+        if onset && !self.above {
+            if let Some(last) = self.last {
+                let dt = now - last;
+                if dt >= MIN_BEAT_INTERVAL {
+                    let dt = dt.as_secs_f32();
+                    // ignore gaps in the music when estimating the tempo
+                    if dt < 4. * self.interval_s {
+                        self.interval_s += 0.25 * (dt - self.interval_s);
+                    }
+                    self.last = Some(now);
+                }
+            } else {
+                self.last = Some(now);
+            }
+        }
+        self.above = onset;
+
+        match self.last {
+            Some(last) => ((now - last).as_secs_f32() / self.interval_s).fract(),
+            None => 0.,
+        }
+    }
+}
+
+/// Evaluates the modulations against the audio features, keeping the per-modulation
+/// smoothing and beat tracking state between frames
+pub struct Modulator {
+    start: Instant,
+    last: Instant,
+    smoothed: Vec<f32>,
+    beats: Vec<BeatTracker>,
+}
+
+impl Default for Modulator {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+            smoothed: Vec::new(),
+            beats: Vec::new(),
+        }
+    }
+}
+
+impl Modulator {
+    pub fn apply(&mut self, mods: &Modulations, base: &Params, features: &AudioFeatures) -> Params {
+        let now = Instant::now();
+        let dt = (now - self.last).as_secs_f32();
+        self.last = now;
+
+        if self.smoothed.len() != mods.0.len() {
+            self.smoothed = vec![0.; mods.0.len()];
+            self.beats = vec![BeatTracker::new(); mods.0.len()];
+        }
+
+        let (bins, _) = features.get_size();
+        let scales = features.get_scales();
+        let diff = features.get_diff();
+        let amp = features.get_amplitudes(0);
+
+        let mut params = *base;
+        for (i, m) in mods.0.iter().enumerate() {
+            let x = match m.source {
+                Source::Band { band } if band < bins => bin_level(base, &scales, &amp, band).1,
+                Source::BeatPhase { band } if band < bins => {
+                    self.beats[i].update(now, diff[band] > 0.)
+                }
+                Source::Band { .. } | Source::BeatPhase { .. } => 0.,
+                Source::Loudness => loudness(base, features),
+                Source::Lfo { hz, shape } => {
+                    shape.eval(((now - self.start).as_secs_f32() * hz).fract())
+                }
+            };
+
+            let y = smooth(self.smoothed[i], x, dt, m.smoothing_s);
+            self.smoothed[i] = y;
+
+            let value = m.target.get_mut(&mut params);
+            let modulated = m.target.limit(*value + m.offset + m.depth * y);
+            // keep the unmodulated value rather than hand the renderers a NaN
+            if modulated.is_finite() {
+                *value = modulated;
+            }
+        }
+        params
+    }
+}
+
+impl Validate for Modulation {
+    fn validate(&self, v: &mut Validator) {
+        v.finite("depth", self.depth);
+        v.finite("offset", self.offset);
+        v.at_least("smoothing_s", self.smoothing_s, 0.);
+        if let Source::Lfo { hz, .. } = self.source {
+            v.at_least("source.hz", hz, 0.);
+        }
+    }
+}
+
+impl Validate for Modulations {
+    fn validate(&self, v: &mut Validator) {
+        for (i, m) in self.0.iter().enumerate() {
+            v.nested(&format!("[{}]", i), m);
+        }
+    }
+}
+
+/// `y` moved towards `x` by a low-pass filter with a time constant of `smoothing_s`
+fn smooth(y: f32, x: f32, dt: f32, smoothing_s: f32) -> f32 {
+    if smoothing_s > 0. {
+        y + (x - y) * (1. - (-dt / smoothing_s).exp())
+    } else {
+        x
+    }
+}
+
+/// Writes `ModulatedParams` each frame, must run before the renderers
+#[derive(Default)]
+pub struct ModulationSystem {
+    modulator: Modulator,
+}
+
+impl ThreadLocalSystem<'static> for ModulationSystem {
+    fn build(mut self) -> Box<dyn Runnable> {
+        Box::new(
+            SystemBuilder::new("modulation")
+                .read_resource::<Params>()
+                .read_resource::<Modulations>()
+                .read_resource::<AudioFeatures>()
+                .write_resource::<ModulatedParams>()
+                .build(
+                    move |_commands, _world, (base, mods, features, out), _query| {
+                        out.0 = self.modulator.apply(mods, base, features);
+                    },
+                ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        smooth, BeatTracker, LfoShape, Modulation, Modulations, Modulator, Source, Target,
+        DEFAULT_BEAT_INTERVAL_S,
+    };
+    use crate::visualizer::{Params, MIN_COLOR_PERIOD};
+    use audio::frequency_sensor::Features as AudioFeatures;
+    use std::time::{Duration, Instant};
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    pub fn lfo_endpoints() {
+        let shapes = [
+            LfoShape::Sine,
+            LfoShape::Triangle,
+            LfoShape::Saw,
+            LfoShape::Square,
+        ];
+        let at = |phase, expected: [f32; 4]| {
+            for (s, e) in shapes.iter().zip(expected.iter()) {
+                assert!(close(s.eval(phase), *e), "{:?} at {}", s, phase);
+            }
+        };
+        at(0., [0., 0., 0., 1.]);
+        at(0.25, [0.5, 0.5, 0.25, 1.]);
+        at(0.5, [1., 1., 0.5, 0.]);
+        at(0.75, [0.5, 0.5, 0.75, 0.]);
+    }
+
+    #[test]
+    pub fn beat_interval() {
+        let t0 = Instant::now();
+        let at = |ms| t0 + Duration::from_millis(ms);
+        let mut beats = BeatTracker::new();
+        assert_eq!(beats.update(at(0), true), 0.);
+        assert!(close(beats.interval_s, DEFAULT_BEAT_INTERVAL_S));
+
+        // a held onset is a single beat
+        beats.update(at(100), true);
+        assert_eq!(beats.last, Some(at(0)));
+
+        beats.update(at(200), false);
+        assert_eq!(beats.update(at(600), true), 0.);
+        assert!(close(beats.interval_s, 0.525));
+
+        // too soon after the last beat
+        beats.update(at(650), false);
+        beats.update(at(700), true);
+        assert_eq!(beats.last, Some(at(600)));
+        assert!(close(beats.interval_s, 0.525));
+
+        // a gap in the music doesn't change the tempo
+        beats.update(at(800), false);
+        beats.update(at(5000), true);
+        assert_eq!(beats.last, Some(at(5000)));
+        assert!(close(beats.interval_s, 0.525));
+    }
+
+    #[test]
+    pub fn smoothing() {
+        assert_eq!(smooth(0., 1., 0.1, 0.), 1.);
+        assert!(close(smooth(0., 1., 0.5, 0.5), 1. - (-1f32).exp()));
+        assert!(close(smooth(1., 1., 0.5, 0.5), 1.));
+        assert_eq!(smooth(0.25, 1., 0., 0.5), 0.25);
+    }
+
+    #[test]
+    pub fn target_limits() {
+        assert_eq!(Target::MaxAlpha.limit(1.5), 1.);
+        assert_eq!(Target::MaxAlpha.limit(-0.5), 0.);
+        assert_eq!(Target::ColorPeriod.limit(0.), MIN_COLOR_PERIOD);
+        assert_eq!(Target::Blur.limit(-1.), 0.);
+        assert_eq!(Target::HzWarpGain.limit(-1.), -1.);
+    }
+
+    #[test]
+    pub fn band_out_of_range() {
+        let base = Params::default();
+        let features = AudioFeatures::new(4, 2);
+        let mods = Modulations(vec![Modulation {
+            target: Target::Blur,
+            source: Source::Band { band: 4 },
+            depth: 1.,
+            offset: 0.,
+            smoothing_s: 0.,
+        }]);
+        let params = Modulator::default().apply(&mods, &base, &features);
+        assert_eq!(params.blur, base.blur);
+    }
+}
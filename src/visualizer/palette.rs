@@ -0,0 +1,470 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::{anyhow, bail, Result};
+use image::RgbImage;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::validate::{Validate, Validator};
+
+lazy_static! {
+    static ref PALETTE: RwLock<Arc<Palette>> =
+        RwLock::new(Arc::new(Palette::build(&PaletteDef::default()).unwrap()));
+    static ref GENERATION: AtomicU64 = AtomicU64::new(0);
+}
+
+/// The active palette, renderers should grab this once per frame
+pub fn current() -> Arc<Palette> {
+    PALETTE.read().unwrap().clone()
+}
+
+/// Incremented every time the active palette changes, used by the gpu renderer to
+/// know when to upload a new texture
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// Build the palette selected in `params` and make it the active one
+pub fn activate(params: &PaletteParams) -> Result<()> {
+    let palette = Palette::build(params.get(&params.active)?)?;
+    *PALETTE.write().unwrap() = Arc::new(palette);
+    GENERATION.fetch_add(1, Ordering::Release);
+    Ok(())
+}
+
+/// Palette selection along with user defined palettes
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PaletteParams {
+    pub active: String,
+    #[serde(default)]
+    pub palettes: BTreeMap<String, PaletteDef>,
+}
+
+impl Default for PaletteParams {
+    fn default() -> Self {
+        Self {
+            active: "hsluv".into(),
+            palettes: BTreeMap::new(),
+        }
+    }
+}
+
+impl PaletteParams {
+    /// Look up a palette by name, user defined palettes shadow the built-in ones
+    pub fn get(&self, name: &str) -> Result<&PaletteDef> {
+        if let Some(def) = self.palettes.get(name) {
+            return Ok(def);
+        }
+        BUILTIN
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, def)| def)
+            .ok_or_else(|| anyhow!("unknown palette {:?}", name))
+    }
+}
+
+lazy_static! {
+    static ref BUILTIN: Vec<(&'static str, PaletteDef)> = vec![
+        ("hsluv", PaletteDef::default()),
+        (
+            "hsv",
+            PaletteDef {
+                source: PaletteSource::Space(ColorSpace::Hsv { saturation: 1. }),
+                gamma: 2.,
+            }
+        ),
+        (
+            "oklch",
+            PaletteDef {
+                source: PaletteSource::Space(ColorSpace::Oklch { chroma: 0.15 }),
+                gamma: 1.,
+            }
+        ),
+    ];
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PaletteDef {
+    #[serde(flatten)]
+    pub source: PaletteSource,
+    /// applied to every channel after the lookup, 2 matches the original HSLuv table
+    #[serde(default = "default_gamma")]
+    pub gamma: f32,
+}
+
+fn default_gamma() -> f32 {
+    2.
+}
+
+impl Default for PaletteDef {
+    fn default() -> Self {
+        Self {
+            source: PaletteSource::Space(ColorSpace::Hsluv { saturation: 100. }),
+            gamma: default_gamma(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaletteSource {
+    /// hue and value map directly onto a color space
+    Space(ColorSpace),
+    /// hue runs through the stops and back so the cycle has no seam, value scales the color
+    Gradient(Vec<Stop>),
+    /// hue runs along the x axis and value from the bottom row to the top row,
+    /// a single row image is scaled by value like a gradient
+    Image(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(tag = "model", rename_all = "snake_case")]
+pub enum ColorSpace {
+    /// saturation in [0, 100]
+    Hsluv { saturation: f32 },
+    /// saturation in [0, 1]
+    Hsv { saturation: f32 },
+    /// chroma around 0.1 to 0.3, out of gamut colors are clipped
+    Oklch { chroma: f32 },
+}
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+pub struct Stop {
+    /// position along the gradient in [0, 1]
+    pub pos: f32,
+    pub color: Color,
+}
+
+/// An sRGB color written as `"#rrggbb"`
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Color(pub [u8; 3]);
+
+impl TryFrom<String> for Color {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let hex = s.trim_start_matches('#');
+        let value = u32::from_str_radix(hex, 16).map_err(|e| format!("{:?}: {}", s, e))?;
+        if hex.len() != 6 {
+            return Err(format!("{:?}: expected #rrggbb", s));
+        }
+        Ok(Color([
+            (value >> 16) as u8,
+            (value >> 8) as u8,
+            value as u8,
+        ]))
+    }
+}
+
+impl From<Color> for String {
+    fn from(c: Color) -> Self {
+        format!("#{:02x}{:02x}{:02x}", c.0[0], c.0[1], c.0[2])
+    }
+}
+
+impl Color {
+    fn to_f32(self) -> (f32, f32, f32) {
+        let [r, g, b] = self.0;
+        (r as f32 / 255., g as f32 / 255., b as f32 / 255.)
+    }
+}
+
+/// Color lookup table indexed by hue and value
+pub struct Palette {
+    lut: Vec<(f32, f32, f32)>,
+}
+
+impl Palette {
+    pub const HUES: usize = 360;
+    pub const VALUES: usize = 256;
+
+    pub fn build(def: &PaletteDef) -> Result<Self> {
+        let mut lut = Vec::with_capacity(Self::HUES * Self::VALUES);
+        match &def.source {
+            PaletteSource::Space(space) => Self::fill(&mut lut, |h, v| space.to_rgb(h, v)),
+            PaletteSource::Gradient(stops) => {
+                if stops.is_empty() {
+                    bail!("gradient palette has no stops");
+                }
+                if let Some(stop) = stops.iter().find(|s| !s.pos.is_finite()) {
+                    bail!("gradient stop at {} is not a finite position", stop.pos);
+                }
+                let mut stops = stops.clone();
+                stops.sort_by(|a, b| a.pos.total_cmp(&b.pos));
+                Self::fill(&mut lut, |h, v| {
+                    let c = gradient(&stops, 1. - (2. * h - 1.).abs());
+                    (c.0 * v, c.1 * v, c.2 * v)
+                })
+            }
+            PaletteSource::Image(path) => {
+                let img = image::open(path)
+                    .map_err(|e| anyhow!("failed to load palette {:?}: {}", path, e))?
+                    .to_rgb8();
+                Self::fill_image(&mut lut, &img, path)?
+            }
+        }
+
+        let g = def.gamma;
+        for c in lut.iter_mut() {
+            *c = (c.0.powf(g), c.1.powf(g), c.2.powf(g));
+        }
+        Ok(Self { lut })
+    }
+
+    fn fill(lut: &mut Vec<(f32, f32, f32)>, f: impl Fn(f32, f32) -> (f32, f32, f32)) {
+        for h in 0..Self::HUES {
+            for v in 0..Self::VALUES {
+                let c = f(h as f32 / Self::HUES as f32, v as f32 / Self::VALUES as f32);
+                lut.push((c.0.clamp(0., 1.), c.1.clamp(0., 1.), c.2.clamp(0., 1.)));
+            }
+        }
+    }
+
+    fn fill_image(lut: &mut Vec<(f32, f32, f32)>, img: &RgbImage, path: &Path) -> Result<()> {
+        let (w, h) = img.dimensions();
+        if w == 0 || h == 0 {
+            bail!("palette image {:?} is empty", path);
+        }
+        let px = |x: f32, y: f32| {
+            let x = ((x * w as f32) as u32).min(w - 1);
+            let y = ((y * h as f32) as u32).min(h - 1);
+            let p = img.get_pixel(x, y).0;
+            Color(p).to_f32()
+        };
+        Self::fill(lut, |hue, v| {
+            if h == 1 {
+                let c = px(hue, 0.);
+                (c.0 * v, c.1 * v, c.2 * v)
+            } else {
+                px(hue, 1. - v)
+            }
+        });
+        Ok(())
+    }
+
+    /// lookup hue + value normalized to range [0,1), the hue repeats outside of it
+    #[inline]
+    pub fn lookup(&self, h: f32, v: f32) -> (f32, f32, f32) {
+        let hu =
+            ((h * Self::HUES as f32).floor() as isize).rem_euclid(Self::HUES as isize) as usize;
+        let vi = (v * Self::VALUES as f32) as isize;
+        let vu = isize::max(isize::min(vi, Self::VALUES as isize - 1), 0) as usize;
+        self.lut[hu * Self::VALUES + vu]
+    }
+
+    /// RGBA8 texels for the gpu, hue along x and value along y
+    pub fn texture_data(&self) -> Vec<[u8; 4]> {
+        let to_u8 = |x: f32| (x * 255.5) as u8;
+        let mut data = Vec::with_capacity(self.lut.len());
+        for v in 0..Self::VALUES {
+            for h in 0..Self::HUES {
+                let c = self.lut[h * Self::VALUES + v];
+                data.push([to_u8(c.0), to_u8(c.1), to_u8(c.2), 255]);
+            }
+        }
+        data
+    }
+}
+
+impl ColorSpace {
+    fn to_rgb(self, h: f32, v: f32) -> (f32, f32, f32) {
+        match self {
+            ColorSpace::Hsluv { saturation } => {
+                use hsluv::hsluv_to_rgb;
+                let c = hsluv_to_rgb((360. * h as f64, saturation as f64, 100. * v as f64));
+                (c.0 as f32, c.1 as f32, c.2 as f32)
+            }
+            ColorSpace::Hsv { saturation } => hsv_to_rgb(h, saturation, v),
+            ColorSpace::Oklch { chroma } => oklch_to_rgb(v, chroma, h),
+        }
+    }
+}
+
+fn gradient(stops: &[Stop], t: f32) -> (f32, f32, f32) {
+    let first = &stops[0];
+    if t <= first.pos {
+        return first.color.to_f32();
+    }
+    for w in stops.windows(2) {
+        let (a, b) = (&w[0], &w[1]);
+        if t <= b.pos {
+            let f = (t - a.pos) / (b.pos - a.pos).max(f32::EPSILON);
+            let (ca, cb) = (a.color.to_f32(), b.color.to_f32());
+            return (
+                ca.0 + (cb.0 - ca.0) * f,
+                ca.1 + (cb.1 - ca.1) * f,
+                ca.2 + (cb.2 - ca.2) * f,
+            );
+        }
+    }
+    stops[stops.len() - 1].color.to_f32()
+}
+
+fn hsv_to_rgb(h: f32, s: f32, v: f32) -> (f32, f32, f32) {
+    let h = 6. * (h - h.floor());
+    let c = v * s;
+    let x = c * (1. - (h % 2. - 1.).abs());
+    let m = v - c;
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.),
+        1 => (x, c, 0.),
+        2 => (0., c, x),
+        3 => (0., x, c),
+        4 => (x, 0., c),
+        _ => (c, 0., x),
+    };
+    (r + m, g + m, b + m)
+}
+
+/// Lightness and chroma from OKLCH, hue in turns
+fn oklch_to_rgb(l: f32, c: f32, h: f32) -> (f32, f32, f32) {
+    use std::f32::consts::PI;
+
+    let (a, b) = (c * (2. * PI * h).cos(), c * (2. * PI * h).sin());
+    let l_ = l + 0.396_337_78 * a + 0.215_803_76 * b;
+    let m_ = l - 0.105_561_346 * a - 0.063_854_17 * b;
+    let s_ = l - 0.089_484_18 * a - 1.291_485_5 * b;
+    let (l3, m3, s3) = (l_ * l_ * l_, m_ * m_ * m_, s_ * s_ * s_);
+
+    let r = 4.076_741_7 * l3 - 3.307_711_6 * m3 + 0.230_969_94 * s3;
+    let g = -1.268_438 * l3 + 2.609_757_4 * m3 - 0.341_319_38 * s3;
+    let b = -0.004_196_086_3 * l3 - 0.703_418_6 * m3 + 1.707_614_7 * s3;
+    (srgb_encode(r), srgb_encode(g), srgb_encode(b))
+}
+
+fn srgb_encode(x: f32) -> f32 {
+    let x = x.clamp(0., 1.);
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1. / 2.4) - 0.055
+    }
+}
+
+impl Validate for PaletteParams {
+    fn validate(&self, v: &mut Validator) {
+        v.check(
+            "active",
+            self.get(&self.active).is_ok(),
+            format!("unknown palette {:?}", self.active),
+        );
+        for (name, def) in self.palettes.iter() {
+            v.nested(&format!("palettes.{}", name), def);
+        }
+    }
+}
+
+impl Validate for PaletteDef {
+    fn validate(&self, v: &mut Validator) {
+        v.positive("gamma", self.gamma);
+        match &self.source {
+            PaletteSource::Space(ColorSpace::Hsluv { saturation }) => {
+                v.between("space.saturation", *saturation, 0., 100.)
+            }
+            PaletteSource::Space(ColorSpace::Hsv { saturation }) => {
+                v.between("space.saturation", *saturation, 0., 1.)
+            }
+            PaletteSource::Space(ColorSpace::Oklch { chroma }) => {
+                v.at_least("space.chroma", *chroma, 0.)
+            }
+            PaletteSource::Gradient(stops) => {
+                v.check("gradient", !stops.is_empty(), "needs at least one stop");
+                for (i, stop) in stops.iter().enumerate() {
+                    v.between(&format!("gradient[{}].pos", i), stop.pos, 0., 1.);
+                }
+            }
+            PaletteSource::Image(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{gradient, Color, ColorSpace, Palette, PaletteDef, PaletteSource, Stop};
+    use image::RgbImage;
+    use std::path::Path;
+
+    fn stop(pos: f32, v: u8) -> Stop {
+        Stop {
+            pos,
+            color: Color([v; 3]),
+        }
+    }
+
+    fn gradient_def(stops: Vec<Stop>) -> PaletteDef {
+        PaletteDef {
+            source: PaletteSource::Gradient(stops),
+            gamma: 1.,
+        }
+    }
+
+    fn close(a: (f32, f32, f32), b: f32) -> bool {
+        [a.0, a.1, a.2].iter().all(|c| (c - b).abs() < 1e-5)
+    }
+
+    #[test]
+    pub fn gradient_interpolation() {
+        let stops = [stop(0.25, 0), stop(0.5, 255), stop(1., 51)];
+        assert!(close(gradient(&stops, 0.), 0.));
+        assert!(close(gradient(&stops, 0.25), 0.));
+        assert!(close(gradient(&stops, 0.375), 0.5));
+        assert!(close(gradient(&stops, 0.5), 1.));
+        assert!(close(gradient(&stops, 0.75), 0.6));
+        assert!(close(gradient(&stops, 2.), 0.2));
+        // stops at the same position don't divide by zero
+        let stops = [stop(0.5, 0), stop(0.5, 255)];
+        assert!(gradient(&stops, 0.5).0.is_finite());
+    }
+
+    #[test]
+    pub fn invalid_stops() {
+        assert!(Palette::build(&gradient_def(vec![])).is_err());
+        assert!(Palette::build(&gradient_def(vec![stop(f32::NAN, 0)])).is_err());
+        assert!(Palette::build(&gradient_def(vec![stop(f32::INFINITY, 0)])).is_err());
+        // unsorted stops are fine
+        assert!(Palette::build(&gradient_def(vec![stop(1., 0), stop(0., 255)])).is_ok());
+    }
+
+    #[test]
+    pub fn empty_image() {
+        let mut lut = Vec::new();
+        let empty = RgbImage::new(0, 4);
+        assert!(Palette::fill_image(&mut lut, &empty, Path::new("empty.png")).is_err());
+        let single = RgbImage::new(4, 1);
+        assert!(Palette::fill_image(&mut lut, &single, Path::new("row.png")).is_ok());
+        assert_eq!(lut.len(), Palette::HUES * Palette::VALUES);
+    }
+
+    #[test]
+    pub fn texture_data() {
+        let palette = Palette::build(&gradient_def(vec![stop(0., 255)])).unwrap();
+        let data = palette.texture_data();
+        assert_eq!(data.len(), Palette::HUES * Palette::VALUES);
+        // value along y, black at the bottom row and white at the top
+        assert_eq!(data[0], [0, 0, 0, 255]);
+        assert_eq!(data[Palette::HUES - 1], [0, 0, 0, 255]);
+        assert_eq!(data[data.len() - 1], [254, 254, 254, 255]);
+        assert!(data.iter().all(|t| t[3] == 255));
+    }
+
+    #[test]
+    pub fn lookup_wraps_hue() {
+        let palette = Palette::build(&PaletteDef {
+            source: PaletteSource::Space(ColorSpace::Hsv { saturation: 1. }),
+            gamma: 1.,
+        })
+        .unwrap();
+        assert_eq!(palette.lookup(-0.25, 1.), palette.lookup(0.75, 1.));
+        assert_eq!(palette.lookup(1.25, 1.), palette.lookup(0.25, 1.));
+        // just below 0 is the last hue, not the first
+        assert_eq!(palette.lookup(-0.001, 1.), palette.lookup(0.999, 1.));
+        assert_ne!(palette.lookup(-0.001, 1.), palette.lookup(0., 1.));
+        assert_eq!(palette.lookup(0.5, 2.), palette.lookup(0.5, 0.999));
+        assert_eq!(palette.lookup(0.5, -1.), palette.lookup(0.5, 0.));
+    }
+}
@@ -0,0 +1,99 @@
+use audio::frequency_sensor::Features as AudioFeatures;
+use image::{Rgb, RgbImage};
+
+use super::{bin_level, sample_color, to_rgb, Scene};
+use crate::visualizer::{palette, Params};
+
+const PEAK_DECAY: f32 = 0.97;
+
+/// One bar per frequency bin mirrored around the horizontal center, with falling peaks
+pub struct SpectrumBars {
+    size: (u32, u32),
+    peaks: Vec<f32>,
+}
+
+impl SpectrumBars {
+    pub fn new(w: u32, h: u32) -> Self {
+        Self {
+            size: (w, h),
+            peaks: Vec::new(),
+        }
+    }
+}
+
+impl Scene for SpectrumBars {
+    fn render(&mut self, params: &Params, features: &AudioFeatures, out: &mut RgbImage) {
+        let (w, h) = self.size;
+        let (bins, _) = features.get_size();
+        let scales = features.get_scales();
+        let energy = features.get_energy();
+        let palette = palette::current();
+        let amp = features.get_amplitudes(0);
+
+        if self.peaks.len() != bins {
+            self.peaks = vec![0.; bins];
+        }
+
+        for px in out.pixels_mut() {
+            *px = Rgb([0; 3]);
+        }
+        let half = h / 2;
+        let bar_w = w as f32 / bins as f32;
+
+        for j in 0..bins {
+            let (val, level) = bin_level(params, &scales, &amp, j);
+            self.peaks[j] = level.max(self.peaks[j] * PEAK_DECAY);
+
+            let c = sample_color(params, &palette, val, energy[j] as f32, 0.);
+
+            let x0 = (j as f32 * bar_w) as u32;
+            let x1 = (((j + 1) as f32 * bar_w) as u32).clamp(x0 + 1, w);
+            // leave a gap between bars when they're wide enough
+            let x1 = if x1 - x0 >= 3 { x1 - 1 } else { x1 };
+
+            if h < 2 {
+                // too short to mirror, a single row shows the level as brightness
+                if h == 1 {
+                    for x in x0..x1 {
+                        out.put_pixel(x, 0, to_rgb(c, level));
+                    }
+                }
+                continue;
+            }
+
+            let bar = to_rgb(c, 1.);
+            let peak = to_rgb(c, 0.5);
+            let bar_h = ((level * half as f32) as u32).min(half);
+            let peak_h = ((self.peaks[j] * half as f32) as u32).min(half - 1);
+
+            for x in x0..x1 {
+                for dy in 0..bar_h {
+                    out.put_pixel(x, half - 1 - dy, bar);
+                    out.put_pixel(x, half + dy, bar);
+                }
+                if peak_h >= bar_h {
+                    out.put_pixel(x, half - 1 - peak_h, peak);
+                    out.put_pixel(x, half + peak_h, peak);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Scene, SpectrumBars};
+    use crate::visualizer::Params;
+    use audio::frequency_sensor::Features as AudioFeatures;
+    use image::RgbImage;
+
+    #[test]
+    pub fn short_frames() {
+        let features = AudioFeatures::new(16, 4);
+        for h in 0..4 {
+            let mut out = RgbImage::new(144, h);
+            let mut bars = SpectrumBars::new(144, h);
+            bars.render(&Params::default(), &features, &mut out);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::time::Instant;
+
+use audio::frequency_sensor::Features as AudioFeatures;
+use image::{Rgb, RgbImage};
+
+use super::{loudness, to_u8, Scenes};
+use crate::visualizer::{
+    layers::{CompositorParams, Layer, TransitionKind},
+    Params, SceneKind,
+};
+
+type F32Buffer = Vec<[f32; 3]>;
+
+struct ActiveTransition {
+    from: Vec<Layer>,
+    start: Instant,
+}
+
+/// Stacks scenes according to `CompositorParams` and transitions between sets of layers
+pub struct Compositor {
+    scenes: Scenes,
+    size: (u32, u32),
+    config: CompositorParams,
+    current: Vec<Layer>,
+    set_index: usize,
+    set_started: Instant,
+    transition: Option<ActiveTransition>,
+    /// scenes rendered in the current frame
+    rendered: Vec<SceneKind>,
+    /// the current layers composited, mixed with `from` during a transition
+    to: F32Buffer,
+    /// the layers being transitioned away from
+    from: F32Buffer,
+}
+
+impl Compositor {
+    pub fn new(w: u32, h: u32, verbose: i32) -> Self {
+        let size = (w * h) as usize;
+        Self {
+            scenes: Scenes::new(w, h, verbose),
+            size: (w, h),
+            config: Default::default(),
+            current: Vec::new(),
+            set_index: 0,
+            set_started: Instant::now(),
+            transition: None,
+            rendered: Vec::new(),
+            to: vec![[0.; 3]; size],
+            from: vec![[0.; 3]; size],
+        }
+    }
+
+    /// Render into `out`, which must be the size given to `new`
+    pub fn render(
+        &mut self,
+        params: &Params,
+        config: &CompositorParams,
+        features: &AudioFeatures,
+        out: &mut RgbImage,
+    ) {
+        self.update_sets(params, config);
+
+        // render every scene once per frame so stateful scenes advance at the same rate
+        // no matter how many layers use them
+        self.rendered.clear();
+        let kinds = self
+            .current
+            .iter()
+            .chain(self.transition.iter().flat_map(|t| t.from.iter()))
+            .map(|l| l.scene);
+        for kind in kinds {
+            if !self.rendered.contains(&kind) {
+                self.rendered.push(kind);
+                self.scenes.render(kind, params, features);
+            }
+        }
+
+        let loudness = loudness(params, features);
+        composite(&self.scenes, &self.current, loudness, &mut self.to);
+
+        let progress = match &self.transition {
+            Some(t) => progress(
+                t.start.elapsed().as_secs_f32(),
+                config.transition.duration_s,
+            ),
+            None => 1.,
+        };
+        match &self.transition {
+            Some(t) if progress < 1. => {
+                composite(&self.scenes, &t.from, loudness, &mut self.from);
+                let (w, _) = self.size;
+                mix(
+                    config.transition.kind,
+                    &self.from,
+                    &mut self.to,
+                    progress,
+                    w,
+                );
+            }
+            _ => self.transition = None,
+        }
+
+        for (px, c) in out.pixels_mut().zip(self.to.iter()) {
+            *px = Rgb([to_u8(c[0]), to_u8(c[1]), to_u8(c[2])]);
+        }
+    }
+
+    fn update_sets(&mut self, params: &Params, config: &CompositorParams) {
+        if *config != self.config {
+            self.config = config.clone();
+            self.set_index = 0;
+            self.set_started = Instant::now();
+        }
+
+        let sets = &self.config.sets;
+        if !sets.is_empty()
+            && self.config.hold_s > 0.
+            && self.set_started.elapsed().as_secs_f32() > self.config.hold_s
+        {
+            self.set_index = (self.set_index + 1) % sets.len();
+            self.set_started = Instant::now();
+        }
+
+        let wanted = if sets.is_empty() {
+            vec![Layer::new(params.scene)]
+        } else {
+            sets[self.set_index % sets.len()].clone()
+        };
+
+        if wanted != self.current {
+            let from = std::mem::replace(&mut self.current, wanted);
+            if !from.is_empty() && self.config.transition.kind != TransitionKind::Cut {
+                self.transition = Some(ActiveTransition {
+                    from,
+                    start: Instant::now(),
+                });
+            }
+        }
+    }
+}
+
+/// Blend `layers` over black into `out`
+fn composite(scenes: &Scenes, layers: &[Layer], loudness: f32, out: &mut [[f32; 3]]) {
+    for o in out.iter_mut() {
+        *o = [0.; 3];
+    }
+    for layer in layers {
+        let frame = match scenes.frame(layer.scene) {
+            Some(frame) => frame,
+            None => continue,
+        };
+        let opacity = layer.opacity(loudness);
+        for (o, px) in out.iter_mut().zip(frame.pixels()) {
+            for c in 0..3 {
+                o[c] = layer.blend.apply(o[c], px[c] as f32 / 255., opacity);
+            }
+        }
+    }
+}
+
+/// Progress of a transition lasting `duration_s` after `elapsed_s`, 1 once it's complete
+fn progress(elapsed_s: f32, duration_s: f32) -> f32 {
+    if duration_s > 0. {
+        (elapsed_s / duration_s).min(1.)
+    } else {
+        1.
+    }
+}
+
+/// Mix `from` into `to` at transition progress `p`, `w` is the width of the frame
+fn mix(kind: TransitionKind, from: &[[f32; 3]], to: &mut [[f32; 3]], p: f32, w: u32) {
+    let edge = (p * w as f32) as usize;
+    for (i, (a, b)) in from.iter().zip(to.iter_mut()).enumerate() {
+        *b = match kind {
+            TransitionKind::Wipe if i % w as usize >= edge => *a,
+            TransitionKind::Wipe | TransitionKind::Cut => *b,
+            TransitionKind::Crossfade => [
+                a[0] + (b[0] - a[0]) * p,
+                a[1] + (b[1] - a[1]) * p,
+                a[2] + (b[2] - a[2]) * p,
+            ],
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{mix, progress, Compositor};
+    use crate::visualizer::{
+        layers::{BlendMode, CompositorParams, Layer, Transition, TransitionKind},
+        Params, SceneKind,
+    };
+    use audio::frequency_sensor::Features as AudioFeatures;
+    use image::RgbImage;
+
+    #[test]
+    pub fn blend_modes() {
+        let modes = [
+            (BlendMode::Add, 0.75),
+            (BlendMode::Screen, 0.625),
+            (BlendMode::Multiply, 0.125),
+            (BlendMode::Max, 0.5),
+        ];
+        for (mode, blended) in modes.iter() {
+            assert_eq!(mode.apply(0.25, 0.5, 1.), *blended, "{:?}", mode);
+            assert_eq!(mode.apply(0.25, 0.5, 0.), 0.25, "{:?}", mode);
+            assert_eq!(
+                mode.apply(0.25, 0.5, 0.5),
+                (0.25 + blended) / 2.,
+                "{:?}",
+                mode
+            );
+        }
+        assert_eq!(BlendMode::Add.apply(0.75, 0.5, 1.), 1.);
+    }
+
+    #[test]
+    pub fn transition_progress() {
+        assert_eq!(progress(0., 2.), 0.);
+        assert_eq!(progress(1., 2.), 0.5);
+        assert_eq!(progress(3., 2.), 1.);
+        assert_eq!(progress(0., 0.), 1.);
+    }
+
+    #[test]
+    pub fn mix_kinds() {
+        let from = vec![[0.; 3]; 4];
+        let mixed = |kind, p| {
+            let mut to = vec![[1.; 3]; 4];
+            mix(kind, &from, &mut to, p, 4);
+            to.iter().map(|c| c[0]).collect::<Vec<_>>()
+        };
+        assert_eq!(mixed(TransitionKind::Crossfade, 0.), vec![0.; 4]);
+        assert_eq!(mixed(TransitionKind::Crossfade, 0.25), vec![0.25; 4]);
+        assert_eq!(mixed(TransitionKind::Crossfade, 1.), vec![1.; 4]);
+        assert_eq!(mixed(TransitionKind::Wipe, 0.), vec![0.; 4]);
+        assert_eq!(mixed(TransitionKind::Wipe, 0.5), vec![1., 1., 0., 0.]);
+        assert_eq!(mixed(TransitionKind::Wipe, 1.), vec![1.; 4]);
+        assert_eq!(mixed(TransitionKind::Cut, 0.), vec![1.; 4]);
+    }
+
+    /// Whether a transition is still running after switching from one set to another
+    fn transitioning(transition: Transition) -> bool {
+        let params = Params::default();
+        let features = AudioFeatures::new(16, 4);
+        let mut out = RgbImage::new(8, 4);
+        let mut compositor = Compositor::new(8, 4, 0);
+        let mut config = CompositorParams {
+            sets: vec![vec![Layer::new(SceneKind::SpectrumBars)]],
+            transition,
+            ..Default::default()
+        };
+        compositor.render(&params, &config, &features, &mut out);
+        assert!(compositor.transition.is_none());
+
+        config.sets = vec![vec![Layer::new(SceneKind::Spectrogram)]];
+        compositor.render(&params, &config, &features, &mut out);
+        compositor.transition.is_some()
+    }
+
+    #[test]
+    pub fn transition_completion() {
+        let transition = |kind, duration_s| Transition { kind, duration_s };
+        assert!(transitioning(transition(TransitionKind::Crossfade, 60.)));
+        assert!(transitioning(transition(TransitionKind::Wipe, 60.)));
+        assert!(!transitioning(transition(TransitionKind::Crossfade, 0.)));
+        assert!(!transitioning(transition(TransitionKind::Cut, 60.)));
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use audio::frequency_sensor::Features as AudioFeatures;
+use image::{Rgb, RgbImage};
+
+use super::{
+    cpurender::{get_hsv, sigmoid_fast, Visualizer as WarpGrid},
+    palette::Palette,
+    Params, SceneKind,
+};
+
+mod bars;
+mod compositor;
+mod particles;
+mod radial;
+mod reference;
+mod spectrogram;
+
+pub use bars::SpectrumBars;
+pub use compositor::Compositor;
+pub use particles::Particles;
+pub use radial::RadialSpectrum;
+pub use reference::ShaderReference;
+pub use spectrogram::Spectrogram;
+
+/// A look rendered on the cpu from the latest audio features
+pub trait Scene: Send {
+    /// Draw the next frame over all of `out`, which has the size the scene was created with
+    fn render(&mut self, params: &Params, features: &AudioFeatures, out: &mut RgbImage);
+}
+
+impl Scene for WarpGrid {
+    fn render(&mut self, params: &Params, features: &AudioFeatures, out: &mut RgbImage) {
+        self.render_into(params, features, out)
+    }
+}
+
+pub fn new_scene(kind: SceneKind, w: u32, h: u32, verbose: i32) -> Box<dyn Scene> {
+    match kind {
+        SceneKind::WarpGrid => Box::new(WarpGrid::new(w, h, verbose)),
+        SceneKind::SpectrumBars => Box::new(SpectrumBars::new(w, h)),
+        SceneKind::RadialSpectrum => Box::new(RadialSpectrum::new(w, h)),
+        SceneKind::Spectrogram => Box::new(Spectrogram::new(w, h)),
+        SceneKind::Particles => Box::new(Particles::new(w, h)),
+        SceneKind::ShaderReference => Box::new(ShaderReference::new(w, h)),
+    }
+}
+
+/// Creates scenes on first use and keeps their state while they're inactive
+pub struct Scenes {
+    size: (u32, u32),
+    verbose: i32,
+    /// `(bins, length)` of the features the scenes were created for
+    dimensions: Option<(usize, usize)>,
+    /// every scene along with the last frame it rendered
+    scenes: HashMap<SceneKind, (Box<dyn Scene>, RgbImage)>,
+}
+
+impl Scenes {
+    pub fn new(w: u32, h: u32, verbose: i32) -> Self {
+        Self {
+            size: (w, h),
+            verbose,
+            dimensions: None,
+            scenes: HashMap::new(),
+        }
+    }
+
+    pub fn render(
+        &mut self,
+        kind: SceneKind,
+        params: &Params,
+        features: &AudioFeatures,
+    ) -> &RgbImage {
+        // like the gpu renderer's rebuild, start over with fresh state when the analyzer
+        // is resized
+        let dimensions = Some(features.get_size());
+        if dimensions != self.dimensions {
+            self.dimensions = dimensions;
+            self.scenes.clear();
+        }
+        let (w, h) = self.size;
+        let verbose = self.verbose;
+        let (scene, frame) = self
+            .scenes
+            .entry(kind)
+            .or_insert_with(|| (new_scene(kind, w, h, verbose), RgbImage::new(w, h)));
+        scene.render(params, features, frame);
+        frame
+    }
+
+    /// The frame `kind` rendered last
+    pub fn frame(&self, kind: SceneKind) -> Option<&RgbImage> {
+        self.scenes.get(&kind).map(|(_, frame)| frame)
+    }
+}
+
+#[inline]
+fn to_u8(x: f32) -> u8 {
+    (x * 255.5).clamp(0., 255.5) as u8
+}
+
+/// Color of a sample without the alpha used by the warp grid's accumulation
+#[inline]
+fn sample_color(
+    params: &Params,
+    palette: &Palette,
+    val: f32,
+    e: f32,
+    phi: f32,
+) -> (f32, f32, f32) {
+    let c = get_hsv(params, palette, val, e, phi);
+    (c[1], c[2], c[3])
+}
+
+/// Scale a color by `brightness` and convert it to a pixel
+#[inline]
+fn to_rgb(c: (f32, f32, f32), brightness: f32) -> Rgb<u8> {
+    Rgb([
+        to_u8(c.0 * brightness),
+        to_u8(c.1 * brightness),
+        to_u8(c.2 * brightness),
+    ])
+}
+
+/// Scaled amplitude of bin `j` and its `[0, 1)` level after the `value_scale` sigmoid
+#[inline]
+pub(crate) fn bin_level(params: &Params, scales: &[f64], amp: &[f64], j: usize) -> (f32, f32) {
+    let val = (scales[j] * (amp[j] - 1.0)) as f32;
+    let vs = params.value_scale;
+    (val, sigmoid_fast(vs.0 * val + vs.1))
+}
+
+/// Mean level of the newest column of amplitudes in [0, 1)
+pub(crate) fn loudness(params: &Params, features: &AudioFeatures) -> f32 {
+    let (bins, _) = features.get_size();
+    let scales = features.get_scales();
+    let amp = features.get_amplitudes(0);
+    let sum: f32 = (0..bins)
+        .map(|j| bin_level(params, &scales, &amp, j).1)
+        .sum();
+    sum / bins as f32
+}
@@ -0,0 +1,117 @@
+use audio::frequency_sensor::Features as AudioFeatures;
+use image::{Rgb, RgbImage};
+use rand::Rng;
+
+use super::{bin_level, sample_color, to_u8, Scene};
+use crate::visualizer::{palette, Params};
+
+const MAX_PARTICLES: usize = 2048;
+/// Minimum rise of a bin's level in one frame which triggers a burst
+const ONSET: f32 = 0.08;
+/// Frames a particle lives
+const LIFETIME: f32 = 90.;
+const DRAG: f32 = 0.96;
+const TRAIL_DECAY: f32 = 0.8;
+
+struct Particle {
+    pos: (f32, f32),
+    vel: (f32, f32),
+    life: f32,
+    color: (f32, f32, f32),
+}
+
+/// Bursts of particles emitted from each bin's position when its level jumps, leaving trails
+pub struct Particles {
+    size: (u32, u32),
+    particles: Vec<Particle>,
+    levels: Vec<f32>,
+    trails: Vec<(f32, f32, f32)>,
+}
+
+impl Particles {
+    pub fn new(w: u32, h: u32) -> Self {
+        Self {
+            size: (w, h),
+            particles: Vec::new(),
+            levels: Vec::new(),
+            trails: vec![(0., 0., 0.); (w * h) as usize],
+        }
+    }
+
+    fn spawn(&mut self, params: &Params, features: &AudioFeatures) {
+        use std::f32::consts::PI;
+
+        let (w, h) = self.size;
+        let (bins, _) = features.get_size();
+        let scales = features.get_scales();
+        let energy = features.get_energy();
+        let palette = palette::current();
+        let amp = features.get_amplitudes(0);
+
+        if self.levels.len() != bins {
+            self.levels = vec![0.; bins];
+        }
+
+        let mut rng = rand::thread_rng();
+        let speed = h as f32 / 32.;
+        for j in 0..bins {
+            let (val, level) = bin_level(params, &scales, &amp, j);
+            let rise = level - self.levels[j];
+            self.levels[j] = level;
+            if rise < ONSET {
+                continue;
+            }
+
+            let color = sample_color(params, &palette, val, energy[j] as f32, 0.);
+            let origin = ((j as f32 + 0.5) / bins as f32 * w as f32, h as f32 / 2.);
+            let count = (rise * 64.) as usize;
+            for _ in 0..count {
+                let a = rng.gen_range(0.0..2.0 * PI);
+                let v = speed * (0.5 + level) * rng.gen_range(0.5..1.5);
+                self.particles.push(Particle {
+                    pos: origin,
+                    vel: (v * a.cos(), v * a.sin()),
+                    life: 1.,
+                    color,
+                });
+            }
+        }
+
+        if self.particles.len() > MAX_PARTICLES {
+            let excess = self.particles.len() - MAX_PARTICLES;
+            self.particles.drain(..excess);
+        }
+    }
+}
+
+impl Scene for Particles {
+    fn render(&mut self, params: &Params, features: &AudioFeatures, out: &mut RgbImage) {
+        self.spawn(params, features);
+
+        let (w, h) = self.size;
+        for t in self.trails.iter_mut() {
+            *t = (t.0 * TRAIL_DECAY, t.1 * TRAIL_DECAY, t.2 * TRAIL_DECAY);
+        }
+
+        for p in self.particles.iter_mut() {
+            p.pos = (p.pos.0 + p.vel.0, p.pos.1 + p.vel.1);
+            p.vel = (p.vel.0 * DRAG, p.vel.1 * DRAG);
+            p.life -= 1. / LIFETIME;
+
+            let (x, y) = p.pos;
+            if x >= 0. && y >= 0. && x < w as f32 && y < h as f32 {
+                let t = &mut self.trails[y as usize * w as usize + x as usize];
+                *t = (
+                    t.0 + p.color.0 * p.life,
+                    t.1 + p.color.1 * p.life,
+                    t.2 + p.color.2 * p.life,
+                );
+            }
+        }
+        self.particles.retain(|p| p.life > 0.);
+
+        for (px, t) in out.pixels_mut().zip(self.trails.iter()) {
+            *px = Rgb([to_u8(t.0), to_u8(t.1), to_u8(t.2)]);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use audio::frequency_sensor::Features as AudioFeatures;
+use image::{Rgb, RgbImage};
+
+use super::{bin_level, sample_color, to_rgb, Scene};
+use crate::visualizer::{palette, Params};
+
+/// Radius of the empty center as a fraction of the full radius
+const INNER_RADIUS: f32 = 0.2;
+
+/// Spectrum drawn as rays around the center, low frequencies on the right, mirrored vertically
+pub struct RadialSpectrum {
+    /// `(angle in [0, 1], radius)` of every pixel, the ellipse fills the whole image
+    polar: Vec<(f32, f32)>,
+    /// length and color of the ray of every bin
+    rays: Vec<(f32, (f32, f32, f32))>,
+}
+
+impl RadialSpectrum {
+    pub fn new(w: u32, h: u32) -> Self {
+        use std::f32::consts::PI;
+
+        let (cx, cy) = (w as f32 / 2., h as f32 / 2.);
+        let polar = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let nx = (x as f32 + 0.5 - cx) / cx;
+                let ny = (y as f32 + 0.5 - cy) / cy;
+                (ny.atan2(nx).abs() / PI, nx.hypot(ny))
+            })
+            .collect();
+        Self {
+            polar,
+            rays: Vec::new(),
+        }
+    }
+}
+
+impl Scene for RadialSpectrum {
+    fn render(&mut self, params: &Params, features: &AudioFeatures, out: &mut RgbImage) {
+        let (bins, _) = features.get_size();
+        let scales = features.get_scales();
+        let energy = features.get_energy();
+        let palette = palette::current();
+        let amp = features.get_amplitudes(0);
+
+        self.rays.clear();
+        self.rays.extend((0..bins).map(|j| {
+            let (val, level) = bin_level(params, &scales, &amp, j);
+            let length = level * (1. - INNER_RADIUS);
+            (
+                length,
+                sample_color(params, &palette, val, energy[j] as f32, 0.),
+            )
+        }));
+
+        for (px, &(angle, r)) in out.pixels_mut().zip(self.polar.iter()) {
+            let j = ((angle * bins as f32) as usize).min(bins - 1);
+            let (length, color) = self.rays[j];
+            let d = r - INNER_RADIUS;
+            *px = if d >= 0. && d < length {
+                // fade out towards the tip of the ray
+                to_rgb(color, 1. - 0.5 * d / length)
+            } else {
+                Rgb([0; 3])
+            };
+        }
+    }
+}
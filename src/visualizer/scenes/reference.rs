@@ -0,0 +1,53 @@
+use audio::frequency_sensor::Features as AudioFeatures;
+use image::RgbImage;
+
+use super::Scene;
+use crate::visualizer::{
+    update::{AudioHistory, Reference, UniformData},
+    Params,
+};
+
+/// The gpu warp grid's update pass rendered in software and stretched over the output,
+/// so machines without a gpu can still show its look
+pub struct ShaderReference {
+    size: (u32, u32),
+    reference: Reference,
+    history: Option<AudioHistory>,
+    /// `length` × `bins` output of the update pass
+    state: RgbImage,
+}
+
+impl ShaderReference {
+    pub fn new(w: u32, h: u32) -> Self {
+        Self {
+            size: (w, h),
+            reference: Reference::default(),
+            history: None,
+            state: RgbImage::new(0, 0),
+        }
+    }
+}
+
+impl Scene for ShaderReference {
+    fn render(&mut self, params: &Params, features: &AudioFeatures, out: &mut RgbImage) {
+        let (bins, length) = features.get_size();
+        if self.history.as_ref().map(|h| h.size()) != Some((bins, length)) {
+            self.history = Some(AudioHistory::new(bins, length));
+            self.state = RgbImage::new(length as u32, bins as u32);
+        }
+        let history = self.history.as_mut().unwrap();
+        history.maintain(features);
+
+        let mut uniforms = UniformData::from(params);
+        uniforms.set_history(history);
+        self.reference
+            .render_into(&uniforms, history, &mut self.state);
+
+        let (w, h) = self.size;
+        for (x, y, px) in out.enumerate_pixels_mut() {
+            let sx = x as usize * length / w as usize;
+            let sy = y as usize * bins / h as usize;
+            *px = *self.state.get_pixel(sx as u32, sy as u32);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use audio::frequency_sensor::Features as AudioFeatures;
+use image::{Rgb, RgbImage};
+
+use super::{sample_color, to_rgb, Scene};
+use crate::visualizer::{palette, Params};
+
+/// Scrolling time/frequency plot of the amplitude history, newest column on the right and
+/// low frequencies at the bottom
+pub struct Spectrogram {
+    size: (u32, u32),
+    /// colors of the bins of the column being drawn
+    column: Vec<Rgb<u8>>,
+}
+
+impl Spectrogram {
+    pub fn new(w: u32, h: u32) -> Self {
+        Self {
+            size: (w, h),
+            column: Vec::new(),
+        }
+    }
+}
+
+impl Scene for Spectrogram {
+    fn render(&mut self, params: &Params, features: &AudioFeatures, out: &mut RgbImage) {
+        let (w, h) = self.size;
+        let (bins, length) = features.get_size();
+        let scales = features.get_scales();
+        let energy = features.get_energy();
+        let palette = palette::current();
+
+        for x in 0..w {
+            let i = ((w - 1 - x) as usize * length / w as usize).min(length - 1);
+            let amp = features.get_amplitudes(i);
+            self.column.clear();
+            self.column.extend((0..bins).map(|j| {
+                let val = (scales[j] * (amp[j] - 1.0)) as f32;
+                to_rgb(
+                    sample_color(params, &palette, val, energy[j] as f32, i as f32),
+                    1.,
+                )
+            }));
+            for y in 0..h {
+                let j = ((h - 1 - y) as usize * bins / h as usize).min(bins - 1);
+                out.put_pixel(x, y, self.column[j]);
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use serde::{Deserialize, Serialize};
+
+use crate::validate::{Validate, Validator};
+pub use panel_driver::ColorCorrection;
+
+/// Apa102 strips driven in parallel, the strip and spi settings are only read at startup
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct StripOptions {
+    /// leds on each strip
+    pub length: u32,
+    /// number of strips, each is a row of the frame
+    pub rows: u32,
+    pub spi_mhz: u32,
+    /// preset of the strip select counter, the driver's default if unset
+    pub counter_preset: Option<u8>,
+    /// overall brightness from 0 to 31, the driver picks the global brightness of each
+    /// led to keep the precision of 16-bit frames
+    pub brightness: u8,
+    pub color: ColorCorrection,
+    /// current budget for each strip in mA
+    pub strip_budget_ma: Option<f32>,
+    /// current budget for all strips together in mA
+    pub total_budget_ma: Option<f32>,
+}
+
+impl Default for StripOptions {
+    fn default() -> Self {
+        Self {
+            length: 144,
+            rows: 16,
+            spi_mhz: 16,
+            counter_preset: None,
+            brightness: 31,
+            color: Default::default(),
+            strip_budget_ma: None,
+            total_budget_ma: None,
+        }
+    }
+}
+
+impl StripOptions {
+    pub fn frame_size(&self) -> (u32, u32) {
+        (self.length, self.rows)
+    }
+}
+
+impl Validate for StripOptions {
+    fn validate(&self, v: &mut Validator) {
+        v.at_least("length", self.length, 1.);
+        // the driver shifts one bit of every strip into a 16-bit word
+        v.between("rows", self.rows, 1., 16.);
+        v.at_least("spi_mhz", self.spi_mhz, 1.);
+        if let Some(preset) = self.counter_preset {
+            v.between("counter_preset", preset, 0., 15.);
+        }
+        v.between("brightness", self.brightness, 0., 31.);
+        v.nested("color", &self.color);
+        if let Some(ma) = self.strip_budget_ma {
+            v.at_least("strip_budget_ma", ma, 0.);
+        }
+        if let Some(ma) = self.total_budget_ma {
+            v.at_least("total_budget_ma", ma, 0.);
+        }
+    }
+}
+
+#[cfg(feature = "strips")]
+pub use output::RenderToStrips;
+
+#[cfg(feature = "strips")]
+mod output {
+    use std::sync::atomic::Ordering;
+
+    use amethyst::{core::dispatcher::ThreadLocalSystem, ecs::*};
+    use anyhow::Result;
+    use audio::frequency_sensor::Features as AudioFeatures;
+    use image::{imageops, Rgb, RgbImage};
+    use parallel_strip_driver::{APA102Parallel, Hardware, PowerLimit, Rgb16Image};
+
+    use super::StripOptions;
+    use crate::metrics::METRICS;
+    use crate::status::{Subsystem, STATUS};
+    use crate::visualizer::{
+        frame::SharedFrame, layers::CompositorParams, modulation::ModulatedParams,
+        scenes::Compositor,
+    };
+
+    pub struct RenderToStrips {
+        compositor: Compositor,
+        strips: APA102Parallel,
+        /// `(length, rows)` the strips were started with
+        size: (u32, u32),
+        options: StripOptions,
+        /// frames read back from the gpu renderer along with the last one sent
+        gpu: Option<(SharedFrame, u64)>,
+        frame: RgbImage,
+    }
+
+    impl RenderToStrips {
+        pub fn new(verbose: i32, options: StripOptions) -> Result<Self> {
+            let (w, h) = options.frame_size();
+            // pins of the vuzic strip board, see the driver's demo
+            let hardware = Hardware::new(
+                options.spi_mhz * 1_000_000,
+                17,
+                22,
+                27,
+                5,
+                6,
+                13,
+                19,
+                options.counter_preset,
+            )?;
+            let strips = APA102Parallel::new(w, h, hardware);
+            strips.set_color_correction(options.color.clone());
+            strips.set_power_limit(power_limit(&options));
+            STATUS.start(Subsystem::Strips, true);
+            STATUS.set_device(
+                Subsystem::Strips,
+                Some(format!("apa102 strips {}x{}", w, h)),
+            );
+            Ok(Self {
+                compositor: Compositor::new(w, h, verbose),
+                strips,
+                size: (w, h),
+                options,
+                gpu: None,
+                frame: RgbImage::new(w, h),
+            })
+        }
+
+        /// Send the frames rendered on the gpu instead of rendering on the cpu
+        pub fn with_gpu_frames(mut self, frames: SharedFrame) -> Self {
+            self.gpu = Some((frames, 0));
+            self
+        }
+
+        /// Apply the settings which can change while running
+        fn update_options(&mut self, options: &StripOptions) {
+            if options.color != self.options.color {
+                self.strips.set_color_correction(options.color.clone());
+            }
+            if power_limit(options) != power_limit(&self.options) {
+                self.strips.set_power_limit(power_limit(options));
+            }
+            self.options = options.clone();
+        }
+    }
+
+    fn power_limit(options: &StripOptions) -> PowerLimit {
+        PowerLimit {
+            strip_budget_ma: options.strip_budget_ma,
+            total_budget_ma: options.total_budget_ma,
+            ..Default::default()
+        }
+    }
+
+    /// `frame` dimmed to `brightness` and scaled to the strips, widened to 16 bits first
+    /// so neither step loses precision
+    fn to_strips(frame: &RgbImage, size: (u32, u32), brightness: u8) -> Rgb16Image {
+        let scale = brightness.min(31) as u32 * 257;
+        let level = |v: u8| (v as u32 * scale / 31) as u16;
+        let (fw, fh) = frame.dimensions();
+        let wide = Rgb16Image::from_fn(fw, fh, |x, y| {
+            let p = frame.get_pixel(x, y);
+            Rgb([level(p[0]), level(p[1]), level(p[2])])
+        });
+        if wide.dimensions() == size {
+            wide
+        } else {
+            imageops::resize(&wide, size.0, size.1, imageops::FilterType::Triangle)
+        }
+    }
+
+    impl ThreadLocalSystem<'static> for RenderToStrips {
+        fn build(mut self) -> Box<dyn Runnable> {
+            Box::new(
+                SystemBuilder::new("apa102 strip renderer")
+                    .read_resource::<ModulatedParams>()
+                    .read_resource::<AudioFeatures>()
+                    .read_resource::<CompositorParams>()
+                    .read_resource::<StripOptions>()
+                    .build(
+                        move |_commands, _world, (params, features, comp, options), _query| {
+                            if **options != self.options {
+                                self.update_options(options);
+                            }
+
+                            let size = self.size;
+                            let brightness = self.options.brightness;
+                            let image = match &mut self.gpu {
+                                Some((frames, seen)) => {
+                                    match frames
+                                        .with_newer(seen, |f| to_strips(f, size, brightness))
+                                    {
+                                        Some(image) => image,
+                                        None => return,
+                                    }
+                                }
+                                None => {
+                                    self.compositor.render(
+                                        &params.0,
+                                        comp,
+                                        features,
+                                        &mut self.frame,
+                                    );
+                                    to_strips(&self.frame, size, brightness)
+                                }
+                            };
+
+                            self.strips.display16(image);
+                            STATUS.heartbeat(Subsystem::Strips);
+                            METRICS
+                                .strip_frames
+                                .store(self.strips.frames(), Ordering::Relaxed);
+                            METRICS.set_strip_fps(self.strips.fps());
+                        },
+                    ),
+            )
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use glsl_layout::{float, int, vec2, Uniform};
+use image::RgbImage;
+
+use audio::frequency_sensor::Features as AudioFeatures;
+
+use super::palette::{self, Palette};
+use super::Params;
+
+/// Uniform block `ColorParams` of `update.frag`
+#[derive(Clone, Copy, Debug, Uniform)]
+#[repr(C, align(4))]
+pub struct UniformData {
+    pub value_scale: vec2,
+    pub lightness_scale: vec2,
+    pub alpha_scale: vec2,
+    pub max_alpha: float,
+    pub period: float,
+    pub cycle: float,
+
+    pub state_size: vec2,
+    pub column_index: int,
+}
+
+/// `state_size` and `column_index` are filled in by the renderer every frame
+impl From<&Params> for UniformData {
+    fn from(params: &Params) -> Self {
+        let pair = |(s, t): (f32, f32)| -> vec2 { [s, t].into() };
+        Self {
+            value_scale: pair(params.value_scale),
+            lightness_scale: pair(params.lightness_scale),
+            alpha_scale: pair(params.alpha_scale),
+            max_alpha: params.max_alpha,
+            period: params.color_period,
+            cycle: params.color_cycle_rate,
+
+            state_size: [0., 0.].into(),
+            column_index: 0,
+        }
+    }
+}
+
+impl UniformData {
+    /// Point the shader at the newest row of `history`
+    pub fn set_history(&mut self, history: &AudioHistory) {
+        let (bins, length) = history.size();
+        self.state_size = [length as f32, bins as f32].into();
+        self.column_index = history.column_index() as i32;
+    }
+}
+
+/// The contents of `texAmplitudes` and `texDrivers`: a ring buffer of amplitudes with
+/// one row of `bins` per frame, and `[scale, energy]` for each bin
+pub struct AudioHistory {
+    bins: usize,
+    length: usize,
+    amplitudes: Vec<f32>,
+    drivers: Vec<[f32; 2]>,
+    /// row holding the newest amplitudes
+    column: usize,
+    frame_count: Option<usize>,
+}
+
+impl AudioHistory {
+    pub fn new(bins: usize, length: usize) -> Self {
+        Self {
+            bins,
+            length,
+            amplitudes: vec![1.; bins * length],
+            drivers: vec![[0., 0.]; bins],
+            column: 0,
+            frame_count: None,
+        }
+    }
+
+    /// `(bins, length)`
+    pub fn size(&self) -> (usize, usize) {
+        (self.bins, self.length)
+    }
+
+    /// Row of the newest amplitudes, the shader walks backwards from here
+    pub fn column_index(&self) -> usize {
+        self.column
+    }
+
+    /// Amplitudes of ring buffer row `column`
+    pub fn row(&self, column: usize) -> &[f32] {
+        &self.amplitudes[column * self.bins..(column + 1) * self.bins]
+    }
+
+    pub fn drivers(&self) -> &[[f32; 2]] {
+        &self.drivers
+    }
+
+    /// Push the frames of `features` which haven't been seen yet, including any the
+    /// renderer missed in between. Returns the number of rows written, which are the
+    /// ones before and including `column_index`.
+    pub fn maintain(&mut self, features: &AudioFeatures) -> usize {
+        let frame_count = features.get_frame_count();
+        let last = self.frame_count.replace(frame_count);
+        if last == Some(frame_count) {
+            return 0;
+        }
+
+        if features.get_size() != self.size() {
+            // the owner is expected to replace us with one of the new size
+            return 0;
+        }
+        // start over when the analyzer was restarted
+        let rows = match last {
+            Some(last) if last < frame_count => (frame_count - last).min(self.length),
+            _ => self.length,
+        };
+
+        let bins = self.bins;
+        for i in (0..rows).rev() {
+            self.column = (self.column + 1) % self.length;
+            let row = &mut self.amplitudes[self.column * bins..(self.column + 1) * bins];
+            for (dst, &a) in row.iter_mut().zip(features.get_amplitudes(i).iter()) {
+                *dst = a as f32;
+            }
+        }
+        let (scales, energy) = (features.get_scales(), features.get_energy());
+        for (d, (&s, &e)) in self
+            .drivers
+            .iter_mut()
+            .zip(scales.iter().zip(energy.iter()))
+        {
+            *d = [s as f32, e as f32];
+        }
+        rows
+    }
+}
+
+/// Software port of `update.frag`, renders the image the gpu would show for the same
+/// uniforms and audio history. The output is `length` × `bins`, with the newest audio in
+/// the left column, composited over black like the window.
+#[derive(Default)]
+pub struct Reference {
+    /// palette texture, refreshed when the active palette changes
+    texels: Vec<[u8; 4]>,
+    generation: Option<u64>,
+}
+
+impl Reference {
+    pub fn render(&mut self, uniforms: &UniformData, history: &AudioHistory) -> RgbImage {
+        let (bins, length) = history.size();
+        let mut out = RgbImage::new(length as u32, bins as u32);
+        self.render_into(uniforms, history, &mut out);
+        out
+    }
+
+    /// Render into `out`, which must be `length` × `bins`. `uniforms` needs to be
+    /// pointed at `history` with `set_history` first.
+    pub fn render_into(
+        &mut self,
+        uniforms: &UniformData,
+        history: &AudioHistory,
+        out: &mut RgbImage,
+    ) {
+        let generation = palette::generation();
+        if self.generation != Some(generation) {
+            self.texels = palette::current().texture_data();
+            self.generation = Some(generation);
+        }
+
+        let state_size: [f32; 2] = uniforms.state_size.into();
+        let length = state_size[0] as i32;
+
+        for (x, y, px) in out.enumerate_pixels_mut() {
+            // gl_FragCoord is at the pixel center
+            let fx = x as f32 + 0.5;
+            let phi = fx * (2. * std::f32::consts::PI) / uniforms.period;
+
+            let decay = fx / state_size[0];
+            let decay = 1. - decay * decay;
+
+            let mut index = uniforms.column_index - x as i32;
+            if index < 0 {
+                index += length;
+            }
+            let amp = history.row(index as usize)[y as usize];
+            let [scale, energy] = history.drivers()[y as usize];
+
+            let amp = scale * (amp - 1.);
+            let [r, g, b, a] = self.color(uniforms, amp, energy, phi);
+
+            // fragColor = color * decay, alpha blended onto black
+            let to_u8 = |c: f32| ((c * decay).clamp(0., 1.) * a.clamp(0., 1.) * 255.).round() as u8;
+            px.0 = [to_u8(r), to_u8(g), to_u8(b)];
+        }
+    }
+
+    /// `getColor` from the shader
+    fn color(&self, uniforms: &UniformData, amp: f32, energy: f32, phi: f32) -> [f32; 4] {
+        use std::f32::consts::PI;
+
+        let vs: [f32; 2] = uniforms.value_scale.into();
+        let ls: [f32; 2] = uniforms.lightness_scale.into();
+        let als: [f32; 2] = uniforms.alpha_scale.into();
+
+        let hue = 0.5 * (uniforms.cycle * energy + phi) / PI;
+        let val = ls[0] * sigmoid(vs[0] * amp + vs[1]) + ls[1];
+        let alpha = uniforms.max_alpha * sigmoid(als[0] * amp + als[1]);
+
+        let [r, g, b] = self.sample(hue, val);
+        [r, g, b, alpha]
+    }
+
+    /// Bilinear lookup like the palette sampler, repeating the hue and clamping the value
+    fn sample(&self, u: f32, v: f32) -> [f32; 3] {
+        let (w, h) = (Palette::HUES as i32, Palette::VALUES as i32);
+        let (x, y) = (u * w as f32 - 0.5, v * h as f32 - 0.5);
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let texel = |i: i32, j: i32| {
+            let (i, j) = (i.rem_euclid(w), j.clamp(0, h - 1));
+            let t = self.texels[(j * w + i) as usize];
+            [t[0] as f32 / 255., t[1] as f32 / 255., t[2] as f32 / 255.]
+        };
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let (a, b) = (texel(x0, y0), texel(x0 + 1, y0));
+        let (c, d) = (texel(x0, y0 + 1), texel(x0 + 1, y0 + 1));
+
+        let mut out = [0.; 3];
+        for (k, o) in out.iter_mut().enumerate() {
+            let top = a[k] + (b[k] - a[k]) * fx;
+            let bottom = c[k] + (d[k] - c[k]) * fx;
+            *o = top + (bottom - top) * fy;
+        }
+        out
+    }
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1. / (1. + (-x).exp())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AudioHistory, Reference, UniformData};
+    use crate::visualizer::{
+        palette::{self, Palette},
+        Params,
+    };
+    use image::RgbImage;
+
+    /// A reference with a palette whose color only depends on the value row
+    fn reference(texel: impl Fn(usize) -> [u8; 3]) -> Reference {
+        let texels = (0..Palette::VALUES)
+            .flat_map(|j| {
+                let [r, g, b] = texel(j);
+                vec![[r, g, b, 255]; Palette::HUES]
+            })
+            .collect();
+        Reference {
+            texels,
+            generation: Some(palette::generation()),
+        }
+    }
+
+    /// Two bins and four frames, the frames in `lit` loud and the rest silent
+    fn history(column: usize, lit: &[usize]) -> AudioHistory {
+        let mut history = AudioHistory::new(2, 4);
+        history.column = column;
+        for (i, a) in history.amplitudes.chunks_exact_mut(2).enumerate() {
+            let amp = if lit.contains(&i) { 2. } else { 0. };
+            a.copy_from_slice(&[amp, amp]);
+        }
+        history.drivers = vec![[1., 0.]; 2];
+        history
+    }
+
+    /// Opaque where the amplitude is loud, transparent where it's silent, at `value`
+    fn uniforms(history: &AudioHistory, value: f32) -> UniformData {
+        let mut uniforms = UniformData::from(&Params::default());
+        uniforms.value_scale = [0., 0.].into();
+        uniforms.lightness_scale = [0., value].into();
+        uniforms.alpha_scale = [20., 0.].into();
+        uniforms.max_alpha = 1.;
+        uniforms.period = 64.;
+        uniforms.cycle = 0.;
+        uniforms.set_history(history);
+        uniforms
+    }
+
+    fn render(
+        reference: &mut Reference,
+        uniforms: &UniformData,
+        history: &AudioHistory,
+    ) -> Vec<[u8; 3]> {
+        let mut out = RgbImage::new(4, 2);
+        reference.render_into(uniforms, history, &mut out);
+        let rows: Vec<_> = out.pixels().map(|p| p.0).collect();
+        // every bin has the same amplitudes
+        assert_eq!(rows[..4], rows[4..]);
+        rows[..4].to_vec()
+    }
+
+    #[test]
+    pub fn golden_ring_buffer() {
+        // the newest frame is in row 1, the columns walk back through rows 0, 3 and 2
+        let history = history(1, &[1, 3]);
+        let mut reference = reference(|_| [255, 128, 0]);
+        let pixels = render(&mut reference, &uniforms(&history, 0.5), &history);
+        assert_eq!(
+            pixels,
+            vec![[251, 126, 0], [0, 0, 0], [155, 78, 0], [0, 0, 0]]
+        );
+    }
+
+    #[test]
+    pub fn golden_value_clamped() {
+        let history = history(0, &[0, 1, 2, 3]);
+        let top = Palette::VALUES - 1;
+        let mut reference = reference(|j| if j == top { [255, 0, 0] } else { [0, 0, 255] });
+
+        let pixels = render(&mut reference, &uniforms(&history, 2.), &history);
+        assert_eq!(pixels[0], [251, 0, 0]);
+        let pixels = render(&mut reference, &uniforms(&history, -1.), &history);
+        assert_eq!(pixels[0], [0, 0, 251]);
+    }
+}
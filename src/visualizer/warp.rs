@@ -0,0 +1,30 @@
+use audio::frequency_sensor::Features as AudioFeatures;
+
+use super::Params;
+
+/// Warp exponent of every bin along the time axis, from the spectral difference and
+/// smoothed over neighbouring bins
+pub fn hz_warp(params: &Params, features: &AudioFeatures, out: &mut Vec<f32>) {
+    out.clear();
+    out.extend(
+        features
+            .get_diff()
+            .iter()
+            .map(|&x| params.hz_warp.0 * x as f32 + params.hz_warp.1),
+    );
+    for i in 1..out.len().saturating_sub(1) {
+        out[i] = (out[i - 1] + out[i] + out[i + 1]) / 3.;
+    }
+}
+
+/// Warp exponent of every column of the history along the frequency axis, from the
+/// mean amplitude of the column. The newest column comes first.
+pub fn vt_warp(params: &Params, features: &AudioFeatures, out: &mut Vec<f32>) {
+    let (bins, length) = features.get_size();
+    out.clear();
+    out.extend((0..length).map(|i| {
+        let s: f64 = features.get_amplitudes(i).iter().sum();
+        let warp = s as f32 / bins as f32;
+        params.vt_warp.0 * warp + params.vt_warp.1
+    }));
+}
@@ -1,9 +1,9 @@
 use amethyst::{
-    core::ecs::{DispatcherBuilder, World},
+    core::ecs::World,
     error::Error,
     prelude::*,
     renderer::{
-        bundle::{RenderOrder, RenderPlan, RenderPlugin, Target},
+        bundle::{RenderPlan, RenderPlugin, Target},
         pipeline::{PipelineDescBuilder, PipelinesBuilder},
         rendy::{
             command::{QueueId, RenderPassEncoder},
@@ -26,25 +26,34 @@ use glsl_layout::Uniform;
 
 use audio::frequency_sensor::Features as AudioFeatures;
 
+use crate::visualizer::{frame::SharedFrame, modulation::ModulatedParams};
+
 mod shaders;
 pub use shaders::update::UniformData;
+use shaders::update::VertexArgs;
+
+mod readback;
 
 mod texture;
+mod warp;
+use texture::{AudioTextures, PaletteTexture};
+
+/// Descriptor sets of the update pipeline, these have to match `update.frag`
+const SET_PALETTE: u32 = 0;
+const SET_PARAMS: u32 = 1;
+const SET_AUDIO: u32 = 2;
 
 /// Warpgrid visualizer
 #[derive(Clone, Debug, PartialEq)]
-pub struct WarpGridDesc;
-
-impl WarpGridDesc {
-    /// Create instance of WarpGrid renderer
-    pub fn new() -> Self {
-        Default::default()
-    }
+pub struct WarpGridDesc {
+    bins: usize,
+    length: usize,
 }
 
-impl Default for WarpGridDesc {
-    fn default() -> Self {
-        Self {}
+impl WarpGridDesc {
+    /// Create instance of WarpGrid renderer for audio features of `bins` × `length`
+    pub fn new(bins: usize, length: usize) -> Self {
+        Self { bins, length }
     }
 }
 
@@ -53,7 +62,7 @@ impl<B: Backend> RenderGroupDesc<B, GraphAuxData> for WarpGridDesc {
         self,
         _ctx: &GraphContext<B>,
         factory: &mut Factory<B>,
-        _queue: QueueId,
+        queue: QueueId,
         _world: &GraphAuxData,
         framebuffer_width: u32,
         framebuffer_height: u32,
@@ -62,48 +71,32 @@ impl<B: Backend> RenderGroupDesc<B, GraphAuxData> for WarpGridDesc {
         _images: Vec<NodeImage>,
     ) -> Result<Box<dyn RenderGroup<B, GraphAuxData>>, pso::CreationError> {
         let uniform_data = DynamicUniform::new(factory, pso::ShaderStageFlags::FRAGMENT)?;
-        let uniform_index = DynamicUniform::new(factory, pso::ShaderStageFlags::FRAGMENT)?;
+        let palette = PaletteTexture::new(factory, queue, SET_PALETTE)?;
+        let audio = AudioTextures::new(factory, queue, SET_AUDIO, self.bins, self.length)?;
 
         // let uniforms = UniformsDesc::new(factory)?;
-        let mut vertex = DynamicVertexBuffer::new();
-
-        use shaders::update::VertexArgs;
-        vertex.write(
-            factory,
-            0,
-            4,
-            Some(
-                [
-                    VertexArgs {
-                        pos: [-1., -1.].into(),
-                    },
-                    VertexArgs {
-                        pos: [1., -1.].into(),
-                    },
-                    VertexArgs {
-                        pos: [-1., 1.].into(),
-                    },
-                    VertexArgs {
-                        pos: [1., 1.].into(),
-                    },
-                ]
-                .iter(),
-            ),
-        );
+        let vertex = quad(factory);
 
         let (pipeline, pipeline_layout) = build_pipeline(
             factory,
             subpass,
             framebuffer_width,
             framebuffer_height,
-            vec![uniform_data.raw_layout(), uniform_index.raw_layout()],
+            vec![
+                palette.raw_layout(),
+                uniform_data.raw_layout(),
+                audio.raw_layout(),
+            ],
+            PipelineShaders::quad(&shaders::update::FRAGMENT),
+            Some(pso::BlendState::ALPHA),
         )?;
 
         Ok(Box::new(WarpGrid::<B> {
             pipeline,
             pipeline_layout,
             uniform_data,
-            uniform_index,
+            palette,
+            audio,
             vertex,
             vertex_count: 4,
             change: Default::default(),
@@ -116,8 +109,9 @@ pub struct WarpGrid<B: Backend> {
     pipeline: B::GraphicsPipeline,
     pipeline_layout: B::PipelineLayout,
     uniform_data: DynamicUniform<B, UniformData>,
-    uniform_index: DynamicUniform<B, i32>,
-    vertex: DynamicVertexBuffer<B, shaders::update::VertexArgs>,
+    palette: PaletteTexture<B>,
+    audio: AudioTextures<B>,
+    vertex: DynamicVertexBuffer<B, VertexArgs>,
     vertex_count: usize,
     change: ChangeDetection,
 }
@@ -131,14 +125,17 @@ impl<B: Backend> RenderGroup<B, GraphAuxData> for WarpGrid<B> {
         _subpass: hal::pass::Subpass<'_, B>,
         aux: &GraphAuxData,
     ) -> PrepareResult {
-        let params = aux.resources.get::<UniformData>().unwrap();
-        // let audio_features = aux.resources.get::<AudioFeatures>().unwrap();
+        let mut changed = self.palette.maintain(factory);
+        if let Some(features) = aux.resources.get::<AudioFeatures>() {
+            changed |= self.audio.maintain(factory, &features);
+        }
 
+        let params = aux.resources.get::<ModulatedParams>().unwrap();
+        let mut params = UniformData::from(&params.0);
+        params.set_history(self.audio.history());
         self.uniform_data.write(factory, index, params.std140());
-        // self.uniform_index
-        //     .write(factory, index, audio_features.get_index());
 
-        self.change.prepare_result(index, false)
+        self.change.prepare_result(index, changed)
     }
 
     fn draw_inline(
@@ -149,8 +146,13 @@ impl<B: Backend> RenderGroup<B, GraphAuxData> for WarpGrid<B> {
         _aux: &GraphAuxData,
     ) {
         encoder.bind_graphics_pipeline(&self.pipeline);
+        self.palette
+            .bind(&self.pipeline_layout, SET_PALETTE, &mut encoder);
         self.uniform_data
-            .bind(index, &self.pipeline_layout, 1, &mut encoder);
+            .bind(index, &self.pipeline_layout, SET_PARAMS, &mut encoder);
+        self.audio
+            .bind(&self.pipeline_layout, SET_AUDIO, &mut encoder);
+        self.vertex.bind(0, 0, 0, &mut encoder);
         unsafe {
             encoder.draw(0..self.vertex_count as u32, 0..1);
         }
@@ -166,14 +168,65 @@ impl<B: Backend> RenderGroup<B, GraphAuxData> for WarpGrid<B> {
     }
 }
 
+/// Full screen quad for `update.vert`, written at index 0
+fn quad<B: Backend>(factory: &Factory<B>) -> DynamicVertexBuffer<B, VertexArgs> {
+    let mut vertex = DynamicVertexBuffer::new();
+    vertex.write(
+        factory,
+        0,
+        4,
+        Some(
+            [
+                VertexArgs {
+                    pos: [-1., -1.].into(),
+                },
+                VertexArgs {
+                    pos: [1., -1.].into(),
+                },
+                VertexArgs {
+                    pos: [-1., 1.].into(),
+                },
+                VertexArgs {
+                    pos: [1., 1.].into(),
+                },
+            ]
+            .iter(),
+        ),
+    );
+    vertex
+}
+
+/// Shaders of a pipeline and how its vertices are assembled
+struct PipelineShaders<'a> {
+    vertex: &'a SpirvShader,
+    fragment: &'a SpirvShader,
+    /// whether the vertex shader reads the `quad` vertex buffer, otherwise it has no inputs
+    quad: bool,
+    primitive: pso::Primitive,
+}
+
+impl<'a> PipelineShaders<'a> {
+    /// Full screen `update.vert` quad shaded by `fragment`
+    fn quad(fragment: &'a SpirvShader) -> Self {
+        Self {
+            vertex: &shaders::update::VERTEX,
+            fragment,
+            quad: true,
+            primitive: pso::Primitive::TriangleStrip,
+        }
+    }
+}
+
 fn build_pipeline<B: Backend>(
     factory: &Factory<B>,
     subpass: hal::pass::Subpass<'_, B>,
     framebuffer_width: u32,
     framebuffer_height: u32,
     layouts: Vec<&B::DescriptorSetLayout>,
+    shaders: PipelineShaders<'_>,
+    blend: Option<pso::BlendState>,
 ) -> Result<(B::GraphicsPipeline, B::PipelineLayout), pso::CreationError> {
-    println!("New pipeline with layout {:#?}", layouts);
+    log::debug!("new pipeline with layout {:#?}", layouts);
 
     let pipeline_layout = unsafe {
         factory
@@ -181,19 +234,21 @@ fn build_pipeline<B: Backend>(
             .create_pipeline_layout(layouts, None as Option<(_, _)>)
     }?;
 
-    use shaders::update::{VertexArgs, FRAGMENT, VERTEX};
-
     // Load the shaders
-    let shader_vertex = unsafe { VERTEX.module(factory).unwrap() };
-    let shader_fragment = unsafe { FRAGMENT.module(factory).unwrap() };
+    let shader_vertex = unsafe { shaders.vertex.module(factory).unwrap() };
+    let shader_fragment = unsafe { shaders.fragment.module(factory).unwrap() };
 
     // Build the pipeline
+    let vertex_desc = if shaders.quad {
+        vec![(VertexArgs::vertex(), pso::VertexInputRate::Vertex)]
+    } else {
+        Vec::new()
+    };
     let pipes = PipelinesBuilder::new()
         .with_pipeline(
             PipelineDescBuilder::new()
-                // This Pipeline uses our custom vertex description and does not use instancing
-                .with_vertex_desc(&[(VertexArgs::vertex(), pso::VertexInputRate::Vertex)])
-                .with_input_assembler(pso::InputAssemblerDesc::new(pso::Primitive::TriangleStrip))
+                .with_vertex_desc(&vertex_desc)
+                .with_input_assembler(pso::InputAssemblerDesc::new(shaders.primitive))
                 // Add the shaders
                 .with_shaders(util::simple_shader_set(
                     &shader_vertex,
@@ -202,10 +257,9 @@ fn build_pipeline<B: Backend>(
                 .with_layout(&pipeline_layout)
                 .with_subpass(subpass)
                 .with_framebuffer_size(framebuffer_width, framebuffer_height)
-                // We are using alpha blending
                 .with_blend_targets(vec![pso::ColorBlendDesc {
                     mask: pso::ColorMask::ALL,
-                    blend: Some(pso::BlendState::ALPHA),
+                    blend,
                 }]),
         )
         .build(factory, None);
@@ -228,24 +282,49 @@ fn build_pipeline<B: Backend>(
     }
 }
 
-#[derive(Debug)]
-pub struct WarpGridRender {}
+#[derive(Default)]
+pub struct WarpGridRender {
+    /// `(bins, length)` of the audio features the graph was planned for
+    dimensions: Option<(usize, usize)>,
+    readback: Option<((u32, u32), SharedFrame)>,
+    /// only draw into the readback target, there is no window
+    offscreen_only: bool,
+}
+
+impl std::fmt::Debug for WarpGridRender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WarpGridRender")
+            .field("dimensions", &self.dimensions)
+            .field("readback", &self.readback.as_ref().map(|(size, _)| size))
+            .field("offscreen_only", &self.offscreen_only)
+            .finish()
+    }
+}
+
+impl WarpGridRender {
+    /// Also draw offscreen at `size` and publish every frame to `frames` so it can be
+    /// sent to the LEDs
+    pub fn with_readback(mut self, size: (u32, u32), frames: SharedFrame) -> Self {
+        self.readback = Some((size, frames));
+        self
+    }
+
+    /// Don't draw into the window, which isn't there
+    pub fn offscreen_only(mut self) -> Self {
+        self.offscreen_only = true;
+        self
+    }
 
-impl Default for WarpGridRender {
-    fn default() -> Self {
-        Self {}
+    fn dimensions(resources: &Resources) -> Option<(usize, usize)> {
+        resources
+            .get::<AudioFeatures>()
+            .map(|features| features.get_size())
     }
 }
 
 impl<B: Backend> RenderPlugin<B> for WarpGridRender {
-    fn on_build<'a, 'b>(
-        &mut self,
-        _world: &mut World,
-        resources: &mut Resources,
-        _builder: &mut DispatcherBuilder,
-    ) -> Result<(), Error> {
-        resources.insert(UniformData::new(16, 120));
-        Ok(())
+    fn should_rebuild(&mut self, _world: &World, resources: &Resources) -> bool {
+        Self::dimensions(resources) != self.dimensions
     }
 
     fn on_plan(
@@ -253,12 +332,173 @@ impl<B: Backend> RenderPlugin<B> for WarpGridRender {
         plan: &mut RenderPlan<B>,
         _factory: &mut Factory<B>,
         _world: &World,
-        _resources: &Resources,
+        resources: &Resources,
     ) -> Result<(), Error> {
-        plan.extend_target(Target::Main, |ctx| {
-            ctx.add(RenderOrder::Transparent, WarpGridDesc::new().builder())?;
-            Ok(())
-        });
+        self.dimensions = Self::dimensions(resources);
+        // nothing to draw until the audio system has inserted its features
+        if let Some((bins, length)) = self.dimensions {
+            let mut targets = Vec::new();
+            if let Some((size, frames)) = &self.readback {
+                readback::plan(plan, *size, frames)?;
+                targets.push(readback::TARGET);
+            }
+            if !self.offscreen_only {
+                targets.push(Target::Main);
+            }
+            warp::plan(plan, &targets, bins, length)?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::shaders::update::{UniformData, FRAGMENT_SPIRV, VERTEX_SPIRV};
+    use super::texture::{AUDIO_BINDINGS, PALETTE_BINDINGS};
+    use super::{SET_AUDIO, SET_PALETTE, SET_PARAMS};
+    use glsl_layout::Uniform;
+    use spirv_reflect::types::{ReflectDescriptorSet, ReflectDescriptorType};
+    use spirv_reflect::ShaderModule;
+
+    fn descriptor_sets(spirv: &[u8]) -> Vec<ReflectDescriptorSet> {
+        let module = ShaderModule::load_u8_data(spirv).unwrap();
+        let mut sets = module.enumerate_descriptor_sets(None).unwrap();
+        sets.sort_by_key(|s| s.set);
+        sets
+    }
+
+    fn samplers(set: &ReflectDescriptorSet) -> Vec<u32> {
+        set.bindings
+            .iter()
+            .filter(|b| b.descriptor_type == ReflectDescriptorType::CombinedImageSampler)
+            .map(|b| b.binding)
+            .collect()
+    }
+
+    #[test]
+    pub fn vertex_has_no_descriptors() {
+        assert!(descriptor_sets(VERTEX_SPIRV).is_empty());
+    }
+
+    #[test]
+    pub fn readback_descriptor_set() {
+        let sets = descriptor_sets(super::shaders::readback::FRAGMENT_SPIRV);
+        assert_eq!(sets.len(), 1);
+        let mut bindings: Vec<_> = sets[0]
+            .bindings
+            .iter()
+            .map(|b| (b.binding, b.descriptor_type))
+            .collect();
+        bindings.sort_by_key(|b| b.0);
+        assert_eq!(
+            bindings,
+            vec![
+                (0, ReflectDescriptorType::CombinedImageSampler),
+                (1, ReflectDescriptorType::StorageBuffer),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn warp_descriptor_sets() {
+        use super::shaders::warp;
+        assert!(descriptor_sets(warp::FRAGMENT_SPIRV).is_empty());
+
+        let sets = descriptor_sets(warp::VERTEX_SPIRV);
+        let ids: Vec<u32> = sets.iter().map(|s| s.set).collect();
+        assert_eq!(ids, vec![0, 1]);
+        assert_eq!(samplers(&sets[0]), vec![0, 1]);
+
+        let params = &sets[1].bindings;
+        assert_eq!(params.len(), 1);
+        assert_eq!(
+            params[0].descriptor_type,
+            ReflectDescriptorType::UniformBuffer
+        );
+        let names: Vec<&str> = params[0]
+            .block
+            .members
+            .iter()
+            .map(|m| m.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["gridSize", "mode", "folds", "rotation"]);
+    }
+
+    #[test]
+    pub fn fragment_descriptor_sets() {
+        let sets = descriptor_sets(FRAGMENT_SPIRV);
+        let ids: Vec<u32> = sets.iter().map(|s| s.set).collect();
+        assert_eq!(ids, vec![SET_PALETTE, SET_PARAMS, SET_AUDIO]);
+
+        let palette: Vec<u32> = (0..PALETTE_BINDINGS).collect();
+        let audio: Vec<u32> = (0..AUDIO_BINDINGS).collect();
+        assert_eq!(samplers(&sets[0]), palette);
+        assert_eq!(samplers(&sets[2]), audio);
+
+        let params = &sets[1].bindings;
+        assert_eq!(params.len(), 1);
+        assert_eq!(params[0].binding, 0);
+        assert_eq!(
+            params[0].descriptor_type,
+            ReflectDescriptorType::UniformBuffer
+        );
+    }
+
+    /// Fill every member of `UniformData` with a distinct value and check that it shows
+    /// up at the offset the shader reads it from
+    #[test]
+    pub fn uniform_data_matches_color_params() {
+        let data = UniformData {
+            value_scale: [1., 2.].into(),
+            lightness_scale: [3., 4.].into(),
+            alpha_scale: [5., 6.].into(),
+            max_alpha: 7.,
+            period: 8.,
+            cycle: 9.,
+            state_size: [10., 11.].into(),
+            column_index: 12,
+        };
+        let expected: &[(&str, &[f32])] = &[
+            ("valueScale", &[1., 2.]),
+            ("lightnessScale", &[3., 4.]),
+            ("alphaScale", &[5., 6.]),
+            ("maxAlpha", &[7.]),
+            ("period", &[8.]),
+            ("cycle", &[9.]),
+            ("stateSize", &[10., 11.]),
+        ];
+
+        let std140 = data.std140();
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                &std140 as *const _ as *const u8,
+                std::mem::size_of_val(&std140),
+            )
+        };
+        let word = |offset: u32| {
+            let o = offset as usize;
+            [bytes[o], bytes[o + 1], bytes[o + 2], bytes[o + 3]]
+        };
+
+        let sets = descriptor_sets(FRAGMENT_SPIRV);
+        let block = &sets[SET_PARAMS as usize].bindings[0].block;
+        assert!(block.size as usize <= bytes.len());
+        assert_eq!(block.members.len(), expected.len() + 1);
+
+        for member in block.members.iter() {
+            if member.name == "columnIndex" {
+                assert_eq!(i32::from_ne_bytes(word(member.offset)), 12);
+                continue;
+            }
+            let values = expected
+                .iter()
+                .find(|(name, _)| *name == member.name)
+                .unwrap_or_else(|| panic!("unexpected member {}", member.name))
+                .1;
+            for (i, &v) in values.iter().enumerate() {
+                let offset = member.offset + 4 * i as u32;
+                assert_eq!(f32::from_ne_bytes(word(offset)), v, "{}", member.name);
+            }
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use amethyst::{
+    error::Error,
+    renderer::{
+        bundle::{
+            ImageOptions, OutputColor, RenderOrder, RenderPlan, Target, TargetImage,
+            TargetPlanOutputs,
+        },
+        rendy::{
+            command::{QueueId, RenderPassEncoder},
+            factory::Factory,
+            graph::{
+                render::{PrepareResult, RenderGroup, RenderGroupDesc},
+                GraphContext, ImageAccess, NodeBuffer, NodeImage,
+            },
+            hal::{self, command::ClearColor, device::Device, format::Format, pso},
+            memory::Download,
+            resource::{
+                Buffer, BufferInfo, DescriptorSet, DescriptorSetLayout, Escape, Handle, ImageView,
+                ImageViewInfo, Sampler,
+            },
+        },
+        submodules::DynamicVertexBuffer,
+        system::GraphAuxData,
+        types::Backend,
+        util, ChangeDetection,
+    },
+};
+
+use super::{build_pipeline, quad, shaders, texture::graph_image, PipelineShaders, VertexArgs};
+use crate::visualizer::frame::SharedFrame;
+
+/// Offscreen target the warp grid draws into when its pixels are read back
+pub const TARGET: Target = Target::Custom("warpgrid");
+/// Target of the pass copying `TARGET` into host visible memory
+const READBACK: Target = Target::Custom("warpgrid_readback");
+
+/// Add the offscreen targets to `plan`, publishing every frame of `TARGET` to `frames`
+pub fn plan<B: Backend>(
+    plan: &mut RenderPlan<B>,
+    size: (u32, u32),
+    frames: &SharedFrame,
+) -> Result<(), Error> {
+    let (w, h) = size;
+    let outputs = || TargetPlanOutputs {
+        colors: vec![OutputColor::Image(ImageOptions {
+            kind: hal::image::Kind::D2(w, h, 1, 1),
+            levels: 1,
+            format: Format::Rgba8Unorm,
+            clear: Some(hal::command::ClearValue {
+                color: ClearColor {
+                    float32: [0., 0., 0., 1.],
+                },
+            }),
+        })],
+        depth: None,
+    };
+    plan.define_pass(TARGET, outputs())?;
+    plan.define_pass(READBACK, outputs())?;
+    // nothing samples the readback target, so it has to be a root to get built
+    plan.add_root(READBACK);
+
+    let frames = frames.clone();
+    plan.extend_target(READBACK, move |ctx| {
+        let image = ctx.get_image(TargetImage::Color(TARGET, 0))?;
+        ctx.add(
+            RenderOrder::Display,
+            ReadbackDesc { size, frames }.builder().with_image(image),
+        )?;
+        Ok(())
+    });
+    Ok(())
+}
+
+/// Copies the offscreen warp grid into a storage buffer per frame in flight
+struct ReadbackDesc {
+    size: (u32, u32),
+    frames: SharedFrame,
+}
+
+impl std::fmt::Debug for ReadbackDesc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReadbackDesc")
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl<B: Backend> RenderGroupDesc<B, GraphAuxData> for ReadbackDesc {
+    fn images(&self) -> Vec<ImageAccess> {
+        vec![ImageAccess {
+            access: hal::image::Access::SHADER_READ,
+            usage: hal::image::Usage::SAMPLED,
+            layout: hal::image::Layout::ShaderReadOnlyOptimal,
+            stages: pso::PipelineStage::FRAGMENT_SHADER,
+        }]
+    }
+
+    fn build(
+        self,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        _queue: QueueId,
+        _aux: &GraphAuxData,
+        framebuffer_width: u32,
+        framebuffer_height: u32,
+        subpass: hal::pass::Subpass<'_, B>,
+        _buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn RenderGroup<B, GraphAuxData>>, pso::CreationError> {
+        use pso::*;
+
+        let (view, sampler) = graph_image(ctx, factory, &images[0])?;
+
+        let layout: Handle<DescriptorSetLayout<B>> = factory
+            .create_descriptor_set_layout(util::set_layout_bindings(vec![
+                (
+                    1,
+                    DescriptorType::Image {
+                        ty: ImageDescriptorType::Sampled { with_sampler: true },
+                    },
+                    ShaderStageFlags::FRAGMENT,
+                ),
+                (
+                    1,
+                    DescriptorType::Buffer {
+                        ty: BufferDescriptorType::Storage { read_only: false },
+                        format: BufferDescriptorFormat::Structured {
+                            dynamic_offset: false,
+                        },
+                    },
+                    ShaderStageFlags::FRAGMENT,
+                ),
+            ]))?
+            .into();
+
+        let (pipeline, pipeline_layout) = build_pipeline(
+            factory,
+            subpass,
+            framebuffer_width,
+            framebuffer_height,
+            vec![layout.raw()],
+            PipelineShaders::quad(&shaders::readback::FRAGMENT),
+            None,
+        )?;
+
+        Ok(Box::new(Readback {
+            pipeline,
+            pipeline_layout,
+            layout,
+            view,
+            sampler,
+            vertex: quad(factory),
+            frames: Vec::new(),
+            size: self.size,
+            shared: self.frames,
+            change: Default::default(),
+        }))
+    }
+}
+
+/// Storage buffer written by the frame with the same index
+struct Slot<B: Backend> {
+    buffer: Escape<Buffer<B>>,
+    set: Escape<DescriptorSet<B>>,
+    /// whether the buffer holds a frame which hasn't been published yet
+    written: bool,
+}
+
+struct Readback<B: Backend> {
+    pipeline: B::GraphicsPipeline,
+    pipeline_layout: B::PipelineLayout,
+    layout: Handle<DescriptorSetLayout<B>>,
+    view: Escape<ImageView<B>>,
+    sampler: Handle<Sampler<B>>,
+    vertex: DynamicVertexBuffer<B, VertexArgs>,
+    frames: Vec<Slot<B>>,
+    size: (u32, u32),
+    shared: SharedFrame,
+    change: ChangeDetection,
+}
+
+impl<B: Backend> std::fmt::Debug for Readback<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Readback")
+            .field("size", &self.size)
+            .field("frames", &self.frames.len())
+            .finish()
+    }
+}
+
+impl<B: Backend> Readback<B> {
+    fn buffer_size(&self) -> u64 {
+        let (w, h) = self.size;
+        4 * w as u64 * h as u64
+    }
+
+    fn slot(&self, factory: &Factory<B>) -> Result<Slot<B>, hal::pso::CreationError> {
+        let size = self.buffer_size();
+        let buffer = factory
+            .create_buffer(
+                BufferInfo {
+                    size,
+                    usage: hal::buffer::Usage::STORAGE,
+                },
+                Download,
+            )
+            .map_err(|_| hal::pso::CreationError::Other)?;
+        let set = factory.create_descriptor_set(self.layout.clone())?;
+        unsafe {
+            factory.device().write_descriptor_sets(vec![
+                util::desc_write(
+                    set.raw(),
+                    0,
+                    hal::pso::Descriptor::CombinedImageSampler(
+                        self.view.raw(),
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                        self.sampler.raw(),
+                    ),
+                ),
+                util::desc_write(
+                    set.raw(),
+                    1,
+                    hal::pso::Descriptor::Buffer(buffer.raw(), Some(0)..Some(size)),
+                ),
+            ]);
+        }
+        Ok(Slot {
+            buffer,
+            set,
+            written: false,
+        })
+    }
+
+    /// Publish what the last frame with this index left in its buffer, by now the fence
+    /// of that frame has been waited on
+    fn publish(&mut self, factory: &Factory<B>, index: usize) {
+        let size = self.buffer_size();
+        let (w, h) = self.size;
+        let slot = &mut self.frames[index];
+        if !slot.written {
+            return;
+        }
+        slot.written = false;
+
+        let mut mapped = match slot.buffer.map(factory.device(), 0..size) {
+            Ok(mapped) => mapped,
+            Err(e) => {
+                log::error!("failed to map readback buffer: {:?}", e);
+                return;
+            }
+        };
+        let pixels: &[u32] = match unsafe { mapped.read(factory.device(), 0..size) } {
+            Ok(pixels) => pixels,
+            Err(e) => {
+                log::error!("failed to read back frame: {:?}", e);
+                return;
+            }
+        };
+        self.shared.publish(w, h, |image| {
+            for (px, &packed) in image.pixels_mut().zip(pixels.iter()) {
+                let [r, g, b, _] = packed.to_le_bytes();
+                px.0 = [r, g, b];
+            }
+        });
+    }
+}
+
+impl<B: Backend> RenderGroup<B, GraphAuxData> for Readback<B> {
+    fn prepare(
+        &mut self,
+        factory: &Factory<B>,
+        _queue: QueueId,
+        index: usize,
+        _subpass: hal::pass::Subpass<'_, B>,
+        _aux: &GraphAuxData,
+    ) -> PrepareResult {
+        let mut changed = false;
+        while self.frames.len() <= index {
+            match self.slot(factory) {
+                Ok(slot) => self.frames.push(slot),
+                Err(e) => {
+                    log::error!("failed to create readback buffer: {:?}", e);
+                    return PrepareResult::DrawReuse;
+                }
+            }
+            changed = true;
+        }
+        self.publish(factory, index);
+        // replaying the recorded draw refills the buffer
+        self.frames[index].written = true;
+
+        self.change.prepare_result(index, changed)
+    }
+
+    fn draw_inline(
+        &mut self,
+        mut encoder: RenderPassEncoder<'_, B>,
+        index: usize,
+        _subpass: hal::pass::Subpass<'_, B>,
+        _aux: &GraphAuxData,
+    ) {
+        let slot = match self.frames.get(index) {
+            Some(slot) => slot,
+            None => return,
+        };
+        encoder.bind_graphics_pipeline(&self.pipeline);
+        unsafe {
+            encoder.bind_graphics_descriptor_sets(
+                &self.pipeline_layout,
+                0,
+                Some(slot.set.raw()),
+                std::iter::empty(),
+            );
+        }
+        self.vertex.bind(0, 0, 0, &mut encoder);
+        unsafe {
+            encoder.draw(0..4, 0..1);
+        }
+    }
+
+    fn dispose(self: Box<Self>, factory: &mut Factory<B>, _aux: &GraphAuxData) {
+        unsafe {
+            factory.device().destroy_graphics_pipeline(self.pipeline);
+            factory
+                .device()
+                .destroy_pipeline_layout(self.pipeline_layout);
+        }
+    }
+}
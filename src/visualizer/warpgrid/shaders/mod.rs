@@ -4,16 +4,20 @@ pub mod update {
             self,
             command::RenderPassEncoder,
             factory::Factory,
-            hal::{format::Format, pso::CreationError},
+            hal::{
+                format::Format,
+                pso::{CreationError, ShaderStageFlags},
+            },
             mesh::{AsVertex, VertexFormat},
             resource::{DescriptorSet, DescriptorSetLayout, Escape, Handle as RendyHandle},
-            shader::{PathBufShaderInfo, ShaderKind, SourceLanguage, SpirvShader},
+            shader::SpirvShader,
         },
         types::Backend,
         util,
     };
     use glsl_layout::*;
-    use std::path::PathBuf;
+
+    pub use crate::visualizer::update::UniformData;
 
     #[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Uniform)]
     #[repr(C, align(4))]
@@ -27,36 +31,6 @@ pub mod update {
         }
     }
 
-    #[derive(Clone, Copy, Debug, Uniform)]
-    #[repr(C, align(4))]
-    pub struct UniformData {
-        pub value_scale: vec2,
-        pub lightness_scale: vec2,
-        pub alpha_scale: vec2,
-        pub period: float,
-        pub cycle: float,
-        pub gamma: vec3,
-
-        pub state_size: vec2,
-        pub column_index: int,
-    }
-
-    impl UniformData {
-        pub fn new(x: u32, y: u32) -> Self {
-            Self {
-                value_scale: [2., 0.].into(),
-                lightness_scale: [0.6, 0.].into(),
-                alpha_scale: [4.0, -4.0].into(),
-                period: 0.3 * 120.,
-                cycle: 0.1,
-                gamma: [1.0, 1.5, 1.2].into(),
-
-                state_size: [x as f32, y as f32].into(),
-                column_index: 0,
-            }
-        }
-    }
-
     // #[derive(Clone, Copy, Debug, Uniform)]
     // #[repr(C, align(4))]
     // pub struct UniformState {
@@ -73,20 +47,17 @@ pub mod update {
     //     }
     // }
 
+    /// SPIR-V compiled from `update.vert` and `update.frag` by the build script
+    pub(crate) const VERTEX_SPIRV: &[u8] =
+        include_bytes!(concat!(env!("OUT_DIR"), "/update.vert.spv"));
+    pub(crate) const FRAGMENT_SPIRV: &[u8] =
+        include_bytes!(concat!(env!("OUT_DIR"), "/update.frag.spv"));
+
     lazy_static::lazy_static! {
-        pub static ref VERTEX: SpirvShader = PathBufShaderInfo::new(
-            PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/src/visualizer/warpgrid/shaders/update.vert")),
-            ShaderKind::Vertex,
-            SourceLanguage::GLSL,
-           "main",
-        ).precompile().unwrap();
-
-        pub static ref FRAGMENT: SpirvShader = PathBufShaderInfo::new(
-            PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/src/visualizer/warpgrid/shaders/update.frag")),
-            ShaderKind::Fragment,
-            SourceLanguage::GLSL,
-            "main",
-        ).precompile().unwrap();
+        pub static ref VERTEX: SpirvShader =
+            SpirvShader::from_bytes(VERTEX_SPIRV, ShaderStageFlags::VERTEX, "main").unwrap();
+        pub static ref FRAGMENT: SpirvShader =
+            SpirvShader::from_bytes(FRAGMENT_SPIRV, ShaderStageFlags::FRAGMENT, "main").unwrap();
     }
     /*
     #[derive(Debug)]
@@ -186,3 +157,47 @@ pub mod update {
     }
     */
 }
+
+pub mod readback {
+    use amethyst::renderer::rendy::{hal::pso::ShaderStageFlags, shader::SpirvShader};
+
+    /// SPIR-V compiled from `readback.frag` by the build script, drawn with the
+    /// `update.vert` quad
+    pub(crate) const FRAGMENT_SPIRV: &[u8] =
+        include_bytes!(concat!(env!("OUT_DIR"), "/readback.frag.spv"));
+
+    lazy_static::lazy_static! {
+        pub static ref FRAGMENT: SpirvShader =
+            SpirvShader::from_bytes(FRAGMENT_SPIRV, ShaderStageFlags::FRAGMENT, "main").unwrap();
+    }
+}
+
+pub mod warp {
+    use amethyst::renderer::rendy::{hal::pso::ShaderStageFlags, shader::SpirvShader};
+    use glsl_layout::*;
+
+    /// SPIR-V compiled from `warp.vert` and `warp.frag` by the build script
+    pub(crate) const VERTEX_SPIRV: &[u8] =
+        include_bytes!(concat!(env!("OUT_DIR"), "/warp.vert.spv"));
+    pub(crate) const FRAGMENT_SPIRV: &[u8] =
+        include_bytes!(concat!(env!("OUT_DIR"), "/warp.frag.spv"));
+
+    lazy_static::lazy_static! {
+        pub static ref VERTEX: SpirvShader =
+            SpirvShader::from_bytes(VERTEX_SPIRV, ShaderStageFlags::VERTEX, "main").unwrap();
+        pub static ref FRAGMENT: SpirvShader =
+            SpirvShader::from_bytes(FRAGMENT_SPIRV, ShaderStageFlags::FRAGMENT, "main").unwrap();
+    }
+
+    /// Uniform block `WarpParams` of `warp.vert`
+    #[derive(Clone, Copy, Debug, Uniform)]
+    #[repr(C, align(4))]
+    pub struct WarpData {
+        /// `[length, bins]`
+        pub grid_size: ivec2,
+        pub mode: int,
+        pub folds: int,
+        /// radians
+        pub rotation: float,
+    }
+}
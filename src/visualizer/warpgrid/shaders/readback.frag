@@ -0,0 +1,21 @@
+#version 450
+
+precision mediump float;
+
+layout(set = 0, binding = 0) uniform sampler2D texFrame;
+
+// one packed rgba8 texel per pixel, row major
+layout(std430, set = 0, binding = 1) buffer Pixels {
+  uint pixels[];
+} bPixels;
+
+layout(location = 0) out vec4 fragColor;
+
+void main() {
+  ivec2 index = ivec2(gl_FragCoord.xy);
+  ivec2 size = textureSize(texFrame, 0);
+
+  vec4 color = texelFetch(texFrame, index, 0);
+  bPixels.pixels[index.y * size.x + index.x] = packUnorm4x8(color);
+  fragColor = color;
+}
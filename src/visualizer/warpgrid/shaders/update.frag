@@ -3,16 +3,16 @@
 
 precision mediump float;
 
-layout(set = 0, binding = 0) uniform sampler2D texHSLuv;
+layout(set = 0, binding = 0) uniform sampler2D texPalette;
 
 // .s is scale, .t is offset
 layout(std140, set = 1, binding = 0) uniform ColorParams {
   uniform vec2 valueScale;
   uniform vec2 lightnessScale;
   uniform vec2 alphaScale;
+  uniform float maxAlpha;
   uniform float period;
   uniform float cycle;
-  uniform vec3 gamma;
   uniform vec2 stateSize;
   uniform int columnIndex;
 } uColorParams;
@@ -23,23 +23,23 @@ layout(set = 2, binding = 1) uniform sampler2D texDrivers;
 layout(location = 0) out vec4 fragColor;
 
 float sigmoid(in float x) {
-  return (1. + x / (1. + abs(x))) / 2.;
+  return 1. / (1. + exp(-x));
 }
 
-vec4 getHSLuv(in float amp, in float ph, in float phi) {
+// matches get_hsv in cpurender.rs, the palette already has its gamma applied
+vec4 getColor(in float amp, in float energy, in float phi) {
   vec2 vs = uColorParams.valueScale;
   vec2 ls = uColorParams.lightnessScale;
   vec2 as = uColorParams.alphaScale;
 
-  float hue = (0.5 * (uColorParams.cycle * phi + ph) / PI);
+  float hue = (0.5 * (uColorParams.cycle * energy + phi) / PI);
   // texture can wrap so no mod
   // hue -= 0.5 * (sign(mod(hue, 1.)) - 1.);
 
   float val = ls.s * sigmoid(vs.s * amp + vs.t) + ls.t;
-  float alpha = sigmoid(as.s * amp + as.t);
+  float alpha = uColorParams.maxAlpha * sigmoid(as.s * amp + as.t);
 
-  vec3 color = texture(texHSLuv, vec2(hue, val)).rgb;
-  color = pow(color, uColorParams.gamma);
+  vec3 color = texture(texPalette, vec2(hue, val)).rgb;
   return vec4(color, alpha);
 }
 
@@ -67,6 +67,6 @@ void main () {
   vec2 drivers = getDrivers(ivec2(gl_FragCoord.y, 0));
 
   amp = drivers.s * (amp - 1.);
-  vec4 color = getHSLuv(amp, drivers.t, phi);
+  vec4 color = getColor(amp, drivers.t, phi);
   fragColor = color * vec4(vec3(decay), 1.);
 }
\ No newline at end of file
@@ -0,0 +1,12 @@
+#version 450
+
+precision mediump float;
+
+layout(location = 0) in vec3 vColor;
+
+layout(location = 0) out vec4 fragColor;
+
+// copies are blended additively like the cpu splats
+void main() {
+  fragColor = vec4(vColor, 1.);
+}
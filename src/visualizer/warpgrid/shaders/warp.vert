@@ -0,0 +1,75 @@
+#version 450
+#define PI 3.141592653589793
+
+precision highp float;
+
+// colors from the update pass, x is the age of the column and y the bin
+layout(set = 0, binding = 0) uniform sampler2D texState;
+// row 0 is the warp of every bin, row 1 the warp of every column
+layout(set = 0, binding = 1) uniform sampler2D texWarp;
+
+layout(std140, set = 1, binding = 0) uniform WarpParams {
+  // length, bins
+  uniform ivec2 gridSize;
+  // 0 none, 1 horizontal, 2 vertical, 3 quad, 4 kaleidoscope
+  uniform int mode;
+  uniform int folds;
+  uniform float rotation;
+} uWarp;
+
+layout(location = 0) out vec3 vColor;
+
+const ivec2 corners[6] = ivec2[6](
+  ivec2(0, 0), ivec2(1, 0), ivec2(0, 1),
+  ivec2(0, 1), ivec2(1, 0), ivec2(1, 1)
+);
+
+// apply_warp in cpurender.rs
+vec2 applyWarp(in vec2 p, in float w, in float s) {
+  float x, y;
+  if (p.x <= 0.) {
+    x = pow(p.x + 1., w) - 1.;
+  } else {
+    x = 1. - pow(1. - p.x, w);
+  }
+  if (p.y <= 0.) {
+    y = pow(1. + p.y, (1. + p.y / 2.) * s) - 1.;
+  } else {
+    y = 1. - pow(1. - p.y, (1. - p.y / 2.) * s);
+  }
+  return vec2(x, y);
+}
+
+// Mirror::each in cpurender.rs, in normalized device coordinates
+vec2 mirror(in vec2 p, in int copy) {
+  float r = (copy & 1) == 0 ? -1. : 1.;
+  float q = (copy & 2) == 0 ? -1. : 1.;
+  switch (uWarp.mode) {
+    case 0:
+      return 2. * p - 1.;
+    case 1:
+      return vec2(r * p.x, 2. * p.y - 1.);
+    case 2:
+      return vec2(2. * p.x - 1., r * p.y);
+    case 3:
+      return vec2(r * p.x, q * p.y);
+    default:
+      float folds = float(uWarp.folds);
+      float wedge = uWarp.rotation + 2. * PI * float(copy / 2) / folds;
+      float angle = wedge + r * p.y * PI / folds;
+      return p.x * vec2(cos(angle), sin(angle));
+  }
+}
+
+void main() {
+  int cells = uWarp.gridSize.x - 1;
+  int cell = gl_VertexIndex / 6;
+  ivec2 index = ivec2(cell % cells, cell / cells) + corners[gl_VertexIndex % 6];
+
+  float hz = texelFetch(texWarp, ivec2(index.y, 0), 0).r;
+  float vt = texelFetch(texWarp, ivec2(index.x, 1), 0).r;
+  vec2 p = applyWarp(vec2(index) / vec2(uWarp.gridSize), hz, vt);
+
+  vColor = texelFetch(texState, index, 0).rgb;
+  gl_Position = vec4(mirror(p, gl_InstanceIndex), 0., 1.);
+}
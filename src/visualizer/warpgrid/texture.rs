@@ -1,14 +1,31 @@
 use amethyst::renderer::{
     rendy::{
-        command::RenderPassEncoder,
-        factory::Factory,
-        hal::{self, device::Device},
-        resource::{DescriptorSet, DescriptorSetLayout, Escape, Handle},
+        command::{QueueId, RenderPassEncoder},
+        factory::{Factory, ImageState},
+        graph::{GraphContext, NodeImage},
+        hal::{
+            self,
+            device::Device,
+            image::{Filter, SamplerDesc, WrapMode},
+        },
+        resource::{
+            DescriptorSet, DescriptorSetLayout, Escape, Handle, ImageView, ImageViewInfo, Sampler,
+        },
+        texture::{
+            pixel::{AsPixel, R32Sfloat, Rg32Sfloat, Rgba8Unorm},
+            Texture as RendyTexture, TextureBuilder,
+        },
     },
     types::{Backend, Texture},
     util,
 };
 
+use audio::frequency_sensor::Features as AudioFeatures;
+
+use crate::visualizer::palette::{self, Palette};
+use crate::visualizer::update::AudioHistory;
+
+#[derive(Debug)]
 pub struct Textures<B: Backend> {
     layout: Handle<DescriptorSetLayout<B>>,
     set: Escape<DescriptorSet<B>>,
@@ -19,6 +36,7 @@ impl<B: Backend> Textures<B> {
         factory: &Factory<B>,
         descriptor_set: u32,
         number: u32,
+        stages: hal::pso::ShaderStageFlags,
     ) -> Result<Self, hal::pso::CreationError> {
         use hal::pso::*;
 
@@ -28,7 +46,7 @@ impl<B: Backend> Textures<B> {
                 DescriptorType::Image {
                     ty: ImageDescriptorType::Sampled { with_sampler: true },
                 },
-                ShaderStageFlags::FRAGMENT,
+                stages,
             )]))?
             .into();
 
@@ -41,4 +59,352 @@ impl<B: Backend> Textures<B> {
     pub fn raw_layout(&self) -> &B::DescriptorSetLayout {
         self.layout.raw()
     }
+
+    /// Point `binding` of the set at `texture`
+    pub fn write(&self, factory: &Factory<B>, binding: u32, texture: &RendyTexture<B>) {
+        self.write_view(
+            factory,
+            binding,
+            texture.view().raw(),
+            texture.sampler().raw(),
+        );
+    }
+
+    /// Point `binding` of the set at an image which isn't a `Texture`, like a graph image
+    pub fn write_view(
+        &self,
+        factory: &Factory<B>,
+        binding: u32,
+        view: &B::ImageView,
+        sampler: &B::Sampler,
+    ) {
+        unsafe {
+            factory
+                .device()
+                .write_descriptor_sets(Some(util::desc_write(
+                    self.set.raw(),
+                    binding,
+                    hal::pso::Descriptor::CombinedImageSampler(
+                        view,
+                        hal::image::Layout::ShaderReadOnlyOptimal,
+                        sampler,
+                    ),
+                )));
+        }
+    }
+
+    pub fn bind(
+        &self,
+        pipeline_layout: &B::PipelineLayout,
+        set_id: u32,
+        encoder: &mut RenderPassEncoder<'_, B>,
+    ) {
+        unsafe {
+            encoder.bind_graphics_descriptor_sets(
+                pipeline_layout,
+                set_id,
+                Some(self.set.raw()),
+                std::iter::empty(),
+            );
+        }
+    }
+}
+
+fn shader_read(queue: QueueId) -> ImageState {
+    ImageState {
+        queue,
+        stage: hal::pso::PipelineStage::FRAGMENT_SHADER,
+        access: hal::image::Access::SHADER_READ,
+        layout: hal::image::Layout::ShaderReadOnlyOptimal,
+    }
+}
+
+/// Create a sampled 2d texture of `size` filled with `data`
+pub(super) fn create_texture<B: Backend, P: AsPixel>(
+    factory: &mut Factory<B>,
+    queue: QueueId,
+    size: (u32, u32),
+    data: Vec<P>,
+    sampler: hal::image::SamplerDesc,
+) -> Result<RendyTexture<B>, hal::pso::CreationError> {
+    let (w, h) = size;
+    TextureBuilder::new()
+        .with_kind(hal::image::Kind::D2(w, h, 1, 1))
+        .with_view_kind(hal::image::ViewKind::D2)
+        .with_data_width(w)
+        .with_data_height(h)
+        .with_data(data)
+        .with_sampler_info(sampler)
+        .build(shader_read(queue), factory)
+        .map_err(|e| {
+            log::error!("failed to create texture: {:?}", e);
+            hal::pso::CreationError::Other
+        })
+}
+
+/// Overwrite the region of `texture` at `offset` of `size` with `data`
+pub(super) fn upload<B: Backend, T: 'static + Copy>(
+    factory: &Factory<B>,
+    queue: QueueId,
+    texture: &RendyTexture<B>,
+    offset: (u32, u32),
+    size: (u32, u32),
+    data: &[T],
+) {
+    let (w, h) = size;
+    let result = unsafe {
+        factory.upload_image(
+            texture.image().clone(),
+            w,
+            h,
+            hal::image::SubresourceLayers {
+                aspects: hal::format::Aspects::COLOR,
+                level: 0,
+                layers: 0..1,
+            },
+            hal::image::Offset {
+                x: offset.0 as i32,
+                y: offset.1 as i32,
+                z: 0,
+            },
+            hal::image::Extent {
+                width: w,
+                height: h,
+                depth: 1,
+            },
+            data,
+            hal::image::Layout::ShaderReadOnlyOptimal,
+            shader_read(queue),
+        )
+    };
+    if let Err(e) = result {
+        log::error!("failed to upload texture: {:?}", e);
+    }
+}
+
+/// View and nearest sampler for reading a graph image like the output of another pass
+pub(super) fn graph_image<B: Backend>(
+    ctx: &GraphContext<B>,
+    factory: &mut Factory<B>,
+    input: &NodeImage,
+) -> Result<(Escape<ImageView<B>>, Handle<Sampler<B>>), hal::pso::CreationError> {
+    let image = ctx
+        .get_image(input.id)
+        .ok_or(hal::pso::CreationError::Other)?;
+    let view = factory
+        .create_image_view(
+            image.clone(),
+            ImageViewInfo {
+                view_kind: hal::image::ViewKind::D2,
+                format: image.format(),
+                swizzle: hal::format::Swizzle::NO,
+                range: input.range.clone(),
+            },
+        )
+        .map_err(|_| hal::pso::CreationError::Other)?;
+    let sampler = factory
+        .get_sampler(SamplerDesc::new(Filter::Nearest, WrapMode::Clamp))
+        .map_err(|_| hal::pso::CreationError::Other)?;
+    Ok((view, sampler))
+}
+
+/// `texPalette`
+pub(super) const PALETTE_BINDINGS: u32 = 1;
+/// `texAmplitudes` and `texDrivers`
+pub(super) const AUDIO_BINDINGS: u32 = 2;
+
+fn palette_texels(palette: &Palette) -> Vec<Rgba8Unorm> {
+    palette
+        .texture_data()
+        .into_iter()
+        .map(|repr| Rgba8Unorm { repr })
+        .collect()
+}
+
+/// The active palette as a hue × value texture, re-uploaded whenever the palette changes
+#[derive(Debug)]
+pub struct PaletteTexture<B: Backend> {
+    textures: Textures<B>,
+    texture: RendyTexture<B>,
+    queue: QueueId,
+    generation: u64,
+}
+
+impl<B: Backend> PaletteTexture<B> {
+    const SIZE: (u32, u32) = (Palette::HUES as u32, Palette::VALUES as u32);
+
+    pub fn new(
+        factory: &mut Factory<B>,
+        queue: QueueId,
+        descriptor_set: u32,
+    ) -> Result<Self, hal::pso::CreationError> {
+        let textures = Textures::new(
+            factory,
+            descriptor_set,
+            PALETTE_BINDINGS,
+            hal::pso::ShaderStageFlags::FRAGMENT,
+        )?;
+        let generation = palette::generation();
+
+        // hue wraps around, value is clamped
+        let mut sampler = SamplerDesc::new(Filter::Linear, WrapMode::Tile);
+        sampler.wrap_mode = (WrapMode::Tile, WrapMode::Clamp, WrapMode::Clamp);
+        let texture = create_texture(
+            factory,
+            queue,
+            Self::SIZE,
+            palette_texels(&palette::current()),
+            sampler,
+        )?;
+        textures.write(factory, 0, &texture);
+
+        Ok(Self {
+            textures,
+            texture,
+            queue,
+            generation,
+        })
+    }
+
+    pub fn raw_layout(&self) -> &B::DescriptorSetLayout {
+        self.textures.raw_layout()
+    }
+
+    /// Upload the active palette if it changed since the last call, returns whether it did
+    pub fn maintain(&mut self, factory: &Factory<B>) -> bool {
+        let generation = palette::generation();
+        if generation == self.generation {
+            return false;
+        }
+        self.generation = generation;
+
+        let texels = palette_texels(&palette::current());
+        upload(
+            factory,
+            self.queue,
+            &self.texture,
+            (0, 0),
+            Self::SIZE,
+            &texels,
+        );
+        true
+    }
+
+    pub fn bind(
+        &self,
+        pipeline_layout: &B::PipelineLayout,
+        set_id: u32,
+        encoder: &mut RenderPassEncoder<'_, B>,
+    ) {
+        self.textures.bind(pipeline_layout, set_id, encoder);
+    }
+}
+
+/// `AudioHistory` uploaded to `texAmplitudes` and `texDrivers`
+#[derive(Debug)]
+pub struct AudioTextures<B: Backend> {
+    textures: Textures<B>,
+    amplitudes: RendyTexture<B>,
+    drivers: RendyTexture<B>,
+    queue: QueueId,
+    history: AudioHistory,
+}
+
+impl<B: Backend> AudioTextures<B> {
+    pub fn new(
+        factory: &mut Factory<B>,
+        queue: QueueId,
+        descriptor_set: u32,
+        bins: usize,
+        length: usize,
+    ) -> Result<Self, hal::pso::CreationError> {
+        let textures = Textures::new(
+            factory,
+            descriptor_set,
+            AUDIO_BINDINGS,
+            hal::pso::ShaderStageFlags::FRAGMENT,
+        )?;
+        let history = AudioHistory::new(bins, length);
+
+        let amplitudes = create_texture(
+            factory,
+            queue,
+            (bins as u32, length as u32),
+            (0..length)
+                .flat_map(|i| history.row(i).iter())
+                .map(|&a| R32Sfloat { repr: [a] })
+                .collect(),
+            SamplerDesc::new(Filter::Nearest, WrapMode::Clamp),
+        )?;
+        let drivers = create_texture(
+            factory,
+            queue,
+            (bins as u32, 1),
+            history
+                .drivers()
+                .iter()
+                .map(|&repr| Rg32Sfloat { repr })
+                .collect(),
+            SamplerDesc::new(Filter::Nearest, WrapMode::Clamp),
+        )?;
+        textures.write(factory, 0, &amplitudes);
+        textures.write(factory, 1, &drivers);
+
+        Ok(Self {
+            textures,
+            amplitudes,
+            drivers,
+            queue,
+            history,
+        })
+    }
+
+    pub fn raw_layout(&self) -> &B::DescriptorSetLayout {
+        self.textures.raw_layout()
+    }
+
+    pub fn history(&self) -> &AudioHistory {
+        &self.history
+    }
+
+    /// Upload the frames of `features` which haven't been seen yet, returns whether
+    /// anything was uploaded
+    pub fn maintain(&mut self, factory: &Factory<B>, features: &AudioFeatures) -> bool {
+        let rows = self.history.maintain(features);
+        if rows == 0 {
+            return false;
+        }
+
+        let (bins, length) = self.history.size();
+        let newest = self.history.column_index();
+        for i in 0..rows {
+            let column = (newest + length - i) % length;
+            upload(
+                factory,
+                self.queue,
+                &self.amplitudes,
+                (0, column as u32),
+                (bins as u32, 1),
+                self.history.row(column),
+            );
+        }
+        upload(
+            factory,
+            self.queue,
+            &self.drivers,
+            (0, 0),
+            (bins as u32, 1),
+            self.history.drivers(),
+        );
+        true
+    }
+
+    pub fn bind(
+        &self,
+        pipeline_layout: &B::PipelineLayout,
+        set_id: u32,
+        encoder: &mut RenderPassEncoder<'_, B>,
+    ) {
+        self.textures.bind(pipeline_layout, set_id, encoder);
+    }
 }
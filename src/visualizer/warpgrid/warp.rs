@@ -0,0 +1,301 @@
+use std::time::Instant;
+
+use amethyst::{
+    error::Error,
+    renderer::{
+        bundle::{
+            ImageOptions, OutputColor, RenderOrder, RenderPlan, Target, TargetImage,
+            TargetPlanOutputs,
+        },
+        rendy::{
+            command::{QueueId, RenderPassEncoder},
+            factory::Factory,
+            graph::{
+                render::{PrepareResult, RenderGroup, RenderGroupDesc},
+                GraphContext, ImageAccess, NodeBuffer, NodeImage,
+            },
+            hal::{self, command::ClearColor, device::Device, format::Format, pso},
+            resource::{Escape, Handle, ImageView, Sampler},
+            texture::{pixel::R32Sfloat, Texture as RendyTexture},
+        },
+        submodules::DynamicUniform,
+        system::GraphAuxData,
+        types::Backend,
+        ChangeDetection,
+    },
+    shred::Resources,
+};
+use glsl_layout::Uniform;
+
+use audio::frequency_sensor::Features as AudioFeatures;
+
+use super::{
+    build_pipeline, shaders,
+    shaders::warp::WarpData,
+    texture::{create_texture, graph_image, upload, Textures},
+    PipelineShaders, WarpGridDesc,
+};
+use crate::visualizer::{modulation::ModulatedParams, warp, Symmetry};
+
+/// Output of the update pass, one texel for every bin of every column of the history
+const STATE: Target = Target::Custom("warpgrid_state");
+
+/// Render the update pass into the state target and warp it onto each of `targets`
+pub fn plan<B: Backend>(
+    plan: &mut RenderPlan<B>,
+    targets: &[Target],
+    bins: usize,
+    length: usize,
+) -> Result<(), Error> {
+    plan.define_pass(
+        STATE,
+        TargetPlanOutputs {
+            colors: vec![OutputColor::Image(ImageOptions {
+                kind: hal::image::Kind::D2(length as u32, bins as u32, 1, 1),
+                levels: 1,
+                format: Format::Rgba16Sfloat,
+                clear: Some(hal::command::ClearValue {
+                    color: ClearColor {
+                        float32: [0., 0., 0., 1.],
+                    },
+                }),
+            })],
+            depth: None,
+        },
+    )?;
+    plan.extend_target(STATE, move |ctx| {
+        ctx.add(
+            RenderOrder::Transparent,
+            WarpGridDesc::new(bins, length).builder(),
+        )?;
+        Ok(())
+    });
+    for &target in targets {
+        plan.extend_target(target, move |ctx| {
+            let state = ctx.get_image(TargetImage::Color(STATE, 0))?;
+            ctx.add(
+                RenderOrder::Transparent,
+                WarpDesc { bins, length }.builder().with_image(state),
+            )?;
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+/// Draws the state as a grid warped like `apply_warp` in `cpurender.rs`, with one
+/// instance for every copy made by the symmetry mode
+#[derive(Clone, Debug, PartialEq)]
+struct WarpDesc {
+    bins: usize,
+    length: usize,
+}
+
+impl<B: Backend> RenderGroupDesc<B, GraphAuxData> for WarpDesc {
+    fn images(&self) -> Vec<ImageAccess> {
+        vec![ImageAccess {
+            access: hal::image::Access::SHADER_READ,
+            usage: hal::image::Usage::SAMPLED,
+            layout: hal::image::Layout::ShaderReadOnlyOptimal,
+            stages: pso::PipelineStage::VERTEX_SHADER,
+        }]
+    }
+
+    fn build(
+        self,
+        ctx: &GraphContext<B>,
+        factory: &mut Factory<B>,
+        queue: QueueId,
+        _aux: &GraphAuxData,
+        framebuffer_width: u32,
+        framebuffer_height: u32,
+        subpass: hal::pass::Subpass<'_, B>,
+        _buffers: Vec<NodeBuffer>,
+        images: Vec<NodeImage>,
+    ) -> Result<Box<dyn RenderGroup<B, GraphAuxData>>, pso::CreationError> {
+        let textures = Textures::new(factory, 0, 2, pso::ShaderStageFlags::VERTEX)?;
+        let (view, sampler) = graph_image(ctx, factory, &images[0])?;
+        textures.write_view(factory, 0, view.raw(), sampler.raw());
+
+        let width = self.bins.max(self.length);
+        let warps = create_texture(
+            factory,
+            queue,
+            (width as u32, 2),
+            vec![R32Sfloat { repr: [1.] }; 2 * width],
+            hal::image::SamplerDesc::new(hal::image::Filter::Nearest, hal::image::WrapMode::Clamp),
+        )?;
+        textures.write(factory, 1, &warps);
+
+        let uniform = DynamicUniform::new(factory, pso::ShaderStageFlags::VERTEX)?;
+
+        let (pipeline, pipeline_layout) = build_pipeline(
+            factory,
+            subpass,
+            framebuffer_width,
+            framebuffer_height,
+            vec![textures.raw_layout(), uniform.raw_layout()],
+            PipelineShaders {
+                vertex: &shaders::warp::VERTEX,
+                fragment: &shaders::warp::FRAGMENT,
+                quad: false,
+                primitive: pso::Primitive::TriangleList,
+            },
+            Some(pso::BlendState::ADD),
+        )?;
+
+        Ok(Box::new(Warp {
+            pipeline,
+            pipeline_layout,
+            textures,
+            _view: view,
+            _sampler: sampler,
+            warps,
+            queue,
+            uniform,
+            size: (self.bins, self.length),
+            hz_warp: Vec::new(),
+            vt_warp: Vec::new(),
+            copies: 0,
+            start: Instant::now(),
+            change: Default::default(),
+        }))
+    }
+}
+
+struct Warp<B: Backend> {
+    pipeline: B::GraphicsPipeline,
+    pipeline_layout: B::PipelineLayout,
+    textures: Textures<B>,
+    /// kept alive for the descriptor set
+    _view: Escape<ImageView<B>>,
+    _sampler: Handle<Sampler<B>>,
+    warps: RendyTexture<B>,
+    queue: QueueId,
+    uniform: DynamicUniform<B, WarpData>,
+    /// `(bins, length)`
+    size: (usize, usize),
+    hz_warp: Vec<f32>,
+    vt_warp: Vec<f32>,
+    /// instances drawn, changes with the symmetry mode
+    copies: u32,
+    /// reference for the kaleidoscope rotation
+    start: Instant,
+    change: ChangeDetection,
+}
+
+impl<B: Backend> std::fmt::Debug for Warp<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Warp")
+            .field("size", &self.size)
+            .field("copies", &self.copies)
+            .finish()
+    }
+}
+
+impl<B: Backend> Warp<B> {
+    /// Recompute the warps and upload them, the grid keeps its last warps if the
+    /// features don't match the texture size
+    fn upload_warps(&mut self, factory: &Factory<B>, resources: &Resources) {
+        let params = resources.get::<ModulatedParams>().unwrap();
+        let features = match resources.get::<AudioFeatures>() {
+            Some(features) if features.get_size() == self.size => features,
+            _ => return,
+        };
+        warp::hz_warp(&params.0, &features, &mut self.hz_warp);
+        warp::vt_warp(&params.0, &features, &mut self.vt_warp);
+
+        let (bins, length) = self.size;
+        let rows = [(0, bins, &self.hz_warp), (1, length, &self.vt_warp)];
+        for &(row, len, data) in rows.iter() {
+            upload(
+                factory,
+                self.queue,
+                &self.warps,
+                (0, row),
+                (len as u32, 1),
+                &data[..len],
+            );
+        }
+    }
+}
+
+/// Most wedges drawn by the kaleidoscope, the same as the cpu renderer
+const MAX_FOLDS: u32 = 32;
+
+/// `mode` and `folds` for `warp.vert` and the number of copies it draws
+fn mirror_mode(symmetry: Symmetry) -> (i32, u32, u32) {
+    match symmetry {
+        Symmetry::None => (0, 1, 1),
+        Symmetry::Horizontal => (1, 1, 2),
+        Symmetry::Vertical => (2, 1, 2),
+        Symmetry::Quad => (3, 1, 4),
+        Symmetry::Kaleidoscope { folds, .. } => {
+            let folds = folds.clamp(1, MAX_FOLDS);
+            (4, folds, 2 * folds)
+        }
+    }
+}
+
+impl<B: Backend> RenderGroup<B, GraphAuxData> for Warp<B> {
+    fn prepare(
+        &mut self,
+        factory: &Factory<B>,
+        _queue: QueueId,
+        index: usize,
+        _subpass: hal::pass::Subpass<'_, B>,
+        aux: &GraphAuxData,
+    ) -> PrepareResult {
+        self.upload_warps(factory, aux.resources);
+
+        let symmetry = aux.resources.get::<ModulatedParams>().unwrap().0.symmetry;
+        let (mode, folds, copies) = mirror_mode(symmetry);
+        let rotation = match symmetry {
+            Symmetry::Kaleidoscope { rotation_hz, .. } => {
+                let t = self.start.elapsed().as_secs_f32();
+                2. * std::f32::consts::PI * (rotation_hz * t).fract()
+            }
+            _ => 0.,
+        };
+        let (bins, length) = self.size;
+        let data = WarpData {
+            grid_size: [length as i32, bins as i32].into(),
+            mode,
+            folds: folds as i32,
+            rotation,
+        };
+        self.uniform.write(factory, index, data.std140());
+
+        let changed = copies != self.copies;
+        self.copies = copies;
+        self.change.prepare_result(index, changed)
+    }
+
+    fn draw_inline(
+        &mut self,
+        mut encoder: RenderPassEncoder<'_, B>,
+        index: usize,
+        _subpass: hal::pass::Subpass<'_, B>,
+        _aux: &GraphAuxData,
+    ) {
+        let (bins, length) = self.size;
+        let vertices = 6 * (length.saturating_sub(1) * bins.saturating_sub(1)) as u32;
+
+        encoder.bind_graphics_pipeline(&self.pipeline);
+        self.textures.bind(&self.pipeline_layout, 0, &mut encoder);
+        self.uniform
+            .bind(index, &self.pipeline_layout, 1, &mut encoder);
+        unsafe {
+            encoder.draw(0..vertices, 0..self.copies);
+        }
+    }
+
+    fn dispose(self: Box<Self>, factory: &mut Factory<B>, _aux: &GraphAuxData) {
+        unsafe {
+            factory.device().destroy_graphics_pipeline(self.pipeline);
+            factory
+                .device()
+                .destroy_pipeline_layout(self.pipeline_layout);
+        }
+    }
+}